@@ -32,7 +32,12 @@
       rustToolchain = (import fenix {inherit pkgs;}).stable.withComponents toolchainComponents;
       craneLib = (crane.mkLib pkgs).overrideToolchain rustToolchain;
 
-      src = craneLib.cleanCargoSource ./.;
+      # the tests read the configuration files in src/testdata, which are not all Cargo sources
+      isTestData = path: builtins.match ".*/src/testdata/.*" path != null;
+      src = pkgs.lib.cleanSourceWith {
+        src = craneLib.path ./.;
+        filter = path: type: (isTestData path) || (craneLib.filterCargoSources path type);
+      };
       cargoArtifacts = craneLib.buildDepsOnly {
         inherit src;
       };
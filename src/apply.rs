@@ -0,0 +1,137 @@
+//! Converging the whole system in one go.
+//!
+//! The steps are run in this order, all using the same configuration:
+//! - create copies
+//! - create links
+//! - synchronize packages
+//! - synchronize services, after reloading unit files that the previous steps might have changed
+//!
+//! Other options of the individual subcommands are left at their defaults.
+
+use anyhow::bail;
+
+use crate::{
+    args::{ApplyArgs, CopyArgs, LinkArgs, OutputFormat, RunMode, ServiceArgs, SyncArgs},
+    backend::PackageBackend,
+    config::Config,
+    link,
+    report::RunReport,
+    service, sync,
+};
+
+/// A single step of [`apply`].
+type Step<'a> = Box<dyn FnOnce() -> anyhow::Result<RunReport> + 'a>;
+
+/// Runs all steps and combines their reports.
+///
+/// See module documentation for the details.
+pub fn apply(
+    args: ApplyArgs,
+    cfg: &Config,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<RunReport> {
+    let copy_args = CopyArgs {
+        force: args.force,
+        backup_dir: None,
+        verbose: args.verbose,
+        watch: false,
+        prune: false,
+        prefix: None,
+        format: OutputFormat::Text,
+    };
+    let link_args = LinkArgs {
+        force: args.force,
+        backup_dir: None,
+        stat: false,
+        verbose: args.verbose,
+        prefix: None,
+        audit_orphans: false,
+        remove: false,
+        yes: false,
+        format: OutputFormat::Text,
+    };
+    let sync_args = SyncArgs {
+        cleanup: args.cleanup,
+        no_cleanup: args.no_cleanup,
+        prune_cache: false,
+        explain_removal: false,
+        no_recursive: false,
+        preview: false,
+        yes: false,
+        interactive: false,
+        strict: false,
+        count_upgrades: false,
+        force_refresh: false,
+        expand_globs: false,
+        no_groups: false,
+        ignore: Vec::new(),
+        exclude: Vec::new(),
+        no_upgrade: args.no_upgrade,
+        allow_partial: args.allow_partial,
+        reasons_only: false,
+        reinstall: None,
+        packages: Vec::new(),
+        allow_undeclared: false,
+        parallel_downloads: None,
+        pacman_conf: None,
+        xkb_types: None,
+    };
+    let service_args = ServiceArgs {
+        list_undeclared: false,
+        explain: false,
+        daemon_reload: true,
+        reset: false,
+        start: false,
+        no_start: false,
+        restart_changed: false,
+        retries: args.retries,
+    };
+    let steps: [(&str, Step<'_>); 4] = [
+        (
+            "copy",
+            Box::new(|| link::create_copies(copy_args, cfg, mode, backend, None)),
+        ),
+        (
+            "link",
+            Box::new(|| link::create_links(link_args, cfg, mode, backend)),
+        ),
+        (
+            "sync",
+            Box::new(|| sync::synchronize_packages(sync_args, cfg, mode, backend)),
+        ),
+        (
+            "service",
+            Box::new(|| service::synchronize_services(service_args, cfg, mode, backend)),
+        ),
+    ];
+
+    let mut report = RunReport::default();
+    let mut failed = Vec::new();
+    for (name, step) in steps {
+        bold!("==> {}", name);
+        match step() {
+            Ok(step_report) => report.merge(step_report),
+            Err(err) => {
+                error!("{} failed: {:#}", name, err);
+                failed.push(name);
+                if args.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        if !report.is_empty() {
+            let prefix = if mode.is_dry_run() {
+                "Would make changes"
+            } else {
+                "Changes"
+            };
+            info!("{} in the steps that succeeded: {}", prefix, report);
+        }
+        bail!("Failed steps: {}", failed.join(", "));
+    }
+    Ok(report)
+}
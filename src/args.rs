@@ -1,8 +1,8 @@
 //! Command line arguments.
 
-use std::path::PathBuf;
+use std::{ffi::OsString, num::NonZeroU32, path::PathBuf, time::Duration};
 
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
 
 /// Trying to declaratively configure Arch Linux
 #[derive(Debug, Parser)]
@@ -14,26 +14,283 @@ pub struct Args {
 }
 
 /// Options common to all subcommands.
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
 pub struct ArgsCommon {
     /// Path to the configuration file.
     #[arg(short = 'f', long)]
     pub config: Option<PathBuf>,
+    /// The format of the configuration file.
+    ///
+    /// By default, the format is detected from the extension of the file: '.yaml' and '.yml' files
+    /// are YAML, '.json' files are JSON, and all other files are TOML. Drop-in files are always
+    /// detected from their extension.
+    #[arg(long, value_name = "FORMAT")]
+    pub config_format: Option<ConfigFormat>,
+    /// Resolve relative paths in the configuration file against the directory containing the
+    /// configuration file itself, even if it is a symbolic link.
+    ///
+    /// By default, if the configuration file is a symbolic link, e.g. into a repository with
+    /// dotfiles, relative paths are resolved against the directory containing the file it points
+    /// to. This also selects the directory that drop-in files are read from.
+    #[arg(long)]
+    pub no_follow_config_link: bool,
     /// Path to the user's home directory.
     #[arg(short = 'd', long)]
     pub home: Option<PathBuf>,
+    /// Create copies and links in the home directory of this user and make them owned by them.
+    ///
+    /// Changing the owner of files requires root privileges.
+    #[arg(long, value_name = "USER", conflicts_with = "home")]
+    pub target_user: Option<OsString>,
+    /// Also declare the packages listed for this tag in the configuration file. Can be used
+    /// multiple times.
+    #[arg(long, value_name = "TAG")]
+    pub tag: Vec<String>,
+    /// Print the exact command line of every pacman command that modifies the system before
+    /// running it, or instead of running it in a dry run.
+    #[arg(long)]
+    pub print_pacman_command: bool,
+    /// Only print what would be done, without modifying anything.
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// Fail instead of running commands that require root privileges.
+    ///
+    /// Applies to running pacman and systemctl in ways that modify the system, and to patching the
+    /// xkb types file.
+    #[arg(long)]
+    pub no_sudo: bool,
+    /// When to use colours and other styles in the output.
+    ///
+    /// With 'auto', output is styled if it goes to a terminal, unless the NO_COLOR environment
+    /// variable is set.
+    #[arg(long, value_name = "WHEN", default_value = "auto")]
+    pub color: ColorChoice,
+    /// Kill external commands, like pacman and systemctl, that run for longer than DURATION, and
+    /// fail.
+    ///
+    /// Given in seconds, or with one of the suffixes 's', 'm' or 'h', e.g. '90' or '10m'. By
+    /// default, commands can run for as long as they need to. Pacman commands that modify the
+    /// system are first interrupted, so that pacman can roll back the transaction, and only killed
+    /// if they do not exit within 30 seconds after that.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+}
+
+/// When output should be styled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Style output written to a terminal, unless `NO_COLOR` is set.
+    Auto,
+    /// Always style output.
+    Always,
+    /// Never style output.
+    Never,
+}
+
+/// The format of a configuration file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    /// TOML, always supported.
+    Toml,
+    /// YAML, if archman was built with the 'yaml' feature.
+    Yaml,
+    /// JSON, if archman was built with the 'json' feature.
+    Json,
+}
+
+/// The format of the output of a subcommand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Messages meant to be read by people.
+    Text,
+    /// A JSON object with an array of entries, if archman was built with the 'json' feature.
+    Json,
+}
+
+/// How actions that modify the system should be performed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    /// Perform all actions.
+    Normal,
+    /// Only print the actions that would be performed.
+    DryRun,
+    /// Perform all actions, except for ones that require root privileges.
+    Unprivileged,
+}
+
+impl ArgsCommon {
+    /// Returns the run mode selected by the command line arguments.
+    pub fn run_mode(&self) -> RunMode {
+        if self.dry_run {
+            RunMode::DryRun
+        } else if self.no_sudo {
+            RunMode::Unprivileged
+        } else {
+            RunMode::Normal
+        }
+    }
+}
+
+impl Subcommand {
+    /// Returns `true` if the subcommand only reads the state of the system, given its arguments.
+    ///
+    /// Such subcommands do not take the lock that guards against concurrent changes.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Self::CompletePackages(_) | Self::Show(_) => true,
+            Self::Link(args) => args.stat || (args.audit_orphans && !args.remove),
+            Self::Service(args) => args.explain || args.list_undeclared,
+            Self::Sync(args) => args.preview,
+            _ => false,
+        }
+    }
+}
+
+impl SyncArgs {
+    /// Returns whether unneeded packages should be removed, given the default from the
+    /// configuration file.
+    pub fn cleanup(&self, default: bool) -> bool {
+        resolve_flag(self.cleanup, self.no_cleanup, default)
+    }
+
+    /// Returns whether installed packages are upgraded, which they are not with '--no-upgrade' or
+    /// when only the given packages are synced.
+    pub fn system_upgrade(&self) -> bool {
+        !self.no_upgrade && self.packages.is_empty()
+    }
+
+    /// Returns whether only part of the configuration is applied, because only the given packages
+    /// are synced, some packages are excluded or package groups are not resolved. The applied state
+    /// is not recorded then.
+    pub fn is_partial(&self) -> bool {
+        !self.packages.is_empty() || !self.exclude.is_empty() || self.no_groups
+    }
+}
+
+impl ShowArgs {
+    /// Returns whether unneeded packages count as a difference for `--exit-code`, given the default
+    /// from the configuration file.
+    pub fn cleanup(&self, default: bool) -> bool {
+        resolve_flag(self.cleanup, self.no_cleanup, default)
+    }
+}
+
+impl ServiceArgs {
+    /// Returns whether all services should be started, given the default from the configuration
+    /// file.
+    pub fn start(&self, default: bool) -> bool {
+        resolve_flag(self.start, self.no_start, default)
+    }
+}
+
+/// Parses a positive duration given in seconds, minutes or hours, e.g. `90`, `90s`, `5m` or `1h`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("unknown unit {:?}, expected 's', 'm' or 'h'", unit)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| String::from("expected a number of seconds, minutes or hours"))?;
+    match number.checked_mul(multiplier) {
+        Some(0) => Err(String::from("the duration must not be zero")),
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(String::from("the duration is too long")),
+    }
+}
+
+/// Resolves a flag that can be turned on with `flag` and off with `negated`.
+///
+/// A flag given on the command line takes precedence over the `default` from the configuration
+/// file. If both `flag` and `negated` are given, the one given last wins, so at most one of them is
+/// set here.
+fn resolve_flag(flag: bool, negated: bool, default: bool) -> bool {
+    (default || flag) && !negated
+}
+
+impl RunMode {
+    pub fn is_dry_run(self) -> bool {
+        self == RunMode::DryRun
+    }
+
+    pub fn is_unprivileged(self) -> bool {
+        self == RunMode::Unprivileged
+    }
 }
 
 #[derive(Debug, Parser)]
 pub enum Subcommand {
+    Apply(ApplyArgs),
+    #[command(hide = true)]
+    ColorTest(ColorTestArgs),
+    #[command(name = "__complete-packages", hide = true)]
+    CompletePackages(CompletePackagesArgs),
     Completions(CompletionsArgs),
     Copy(CopyArgs),
+    Doctor(DoctorArgs),
+    Fmt(FmtArgs),
+    Init(InitArgs),
     Link(LinkArgs),
     Service(ServiceArgs),
     Show(ShowArgs),
     Sync(SyncArgs),
 }
 
+/// Create copies and links, synchronize packages and then services, all in one go.
+///
+/// A failure of one step is reported, but does not prevent the following steps, unless
+/// '--fail-fast' is given.
+#[derive(Debug, Parser)]
+pub struct ApplyArgs {
+    /// Stop after the first step that fails.
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Overwrite existing files when creating copies and links.
+    #[arg(short, long)]
+    pub force: bool,
+    /// Print a message for every declared copy and link, including ones that are up to date.
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// Remove all unneeded packages.
+    ///
+    /// Defaults to `defaults.sync.cleanup` from the configuration file, or to off if it is not set.
+    #[arg(short = 'c', long, overrides_with = "no_cleanup")]
+    pub cleanup: bool,
+    /// Do not remove unneeded packages, even if `defaults.sync.cleanup` is set.
+    #[arg(long, overrides_with = "cleanup")]
+    pub no_cleanup: bool,
+    /// Do not upgrade packages.
+    #[arg(long)]
+    pub no_upgrade: bool,
+    /// Install new packages even with '--no-upgrade', accepting the risk of a partial upgrade.
+    ///
+    /// Installing packages without upgrading the installed ones is a partial upgrade, which is not
+    /// supported on Arch Linux: new packages may need newer versions of libraries than the ones
+    /// installed. Without this flag, archman refuses to do it.
+    #[arg(long, requires = "no_upgrade")]
+    pub allow_partial: bool,
+    /// Retry systemctl commands up to N times if they fail to communicate with the system manager,
+    /// e.g. right after boot.
+    ///
+    /// Other failures, like a unit that does not exist, are never retried.
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub retries: u32,
+}
+
+/// Print a line in every style used in the output, to check how they are rendered
+#[derive(Debug, Parser)]
+pub struct ColorTestArgs {}
+
+/// Print the names of declared and installed packages, one per line, for tab-completion
+#[derive(Debug, Parser)]
+pub struct CompletePackagesArgs {}
+
 // TODO support other shells
 /// Output tab-completion script for zsh to stdout
 #[derive(Debug, Parser)]
@@ -45,6 +302,94 @@ pub struct CopyArgs {
     /// Overwrite files if they already exist.
     #[arg(short, long)]
     pub force: bool,
+    /// With '--force', move the existing copies that are replaced into DIR instead of removing
+    /// them.
+    ///
+    /// A file keeps its path under DIR, e.g. `/etc/foo` is moved to `DIR/etc/foo`, replacing an
+    /// older backup of the same file. Missing directories are created. Files replaced
+    /// while watching, see '--watch', are not backed up.
+    #[arg(long, value_name = "DIR", requires = "force")]
+    pub backup_dir: Option<PathBuf>,
+    /// Print a message for every declared copy, including ones that are already up to date.
+    ///
+    /// By default, only the number of copies that are up to date is printed.
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// After creating the copies, keep watching the original files and copy them again whenever
+    /// they change, until interrupted with Ctrl-C.
+    ///
+    /// Copies of changed files are always overwritten. Changes to the configuration file are picked
+    /// up as well, see '--prune'.
+    #[arg(short, long)]
+    pub watch: bool,
+    /// While watching, remove copies that are no longer declared after the configuration file is
+    /// changed.
+    ///
+    /// The configuration file and drop-in files are always watched too: copies declared in them
+    /// later are created as soon as they are saved. A copy is only removed if it still has the
+    /// contents of its original file.
+    #[arg(long, requires = "watch")]
+    pub prune: bool,
+    /// Create copies under DIR instead of the root directory, e.g. `/etc/foo` becomes
+    /// `DIR/etc/foo`.
+    ///
+    /// Only the locations of copies are moved, not the original files they are made from. A
+    /// leading `~` is expanded to the home directory first, so `~/.bashrc` becomes
+    /// `DIR/home/USER/.bashrc`.
+    #[arg(long, value_name = "DIR")]
+    pub prefix: Option<PathBuf>,
+    /// The format of the output.
+    ///
+    /// With 'json', an object is printed to stdout, with the 'schema_version' of the output
+    /// format and an array of 'outcomes' with an object for every declared copy: its 'location',
+    /// 'target' and 'action', one of 'created', 'updated', 'unchanged', 'skipped' or 'error'.
+    /// Messages are still printed to stderr.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        conflicts_with = "watch"
+    )]
+    pub format: OutputFormat,
+}
+
+/// Check that the programs and files archman needs are available.
+///
+/// Exits with an error if anything that is always needed is missing.
+#[derive(Debug, Parser)]
+pub struct DoctorArgs {
+    /// Check that the targets of declared links and the original files of declared copies exist
+    /// using several threads, one per available CPU.
+    ///
+    /// Missing files are reported in the order of their link or copy paths either way.
+    #[arg(long)]
+    pub parallel: bool,
+}
+
+/// Sort the lists of packages, bundles, package groups and services in the configuration file and
+/// remove duplicates from them.
+///
+/// Comments and the layout of the file are preserved. Drop-in files are not changed.
+#[derive(Debug, Parser)]
+pub struct FmtArgs {
+    /// Do not change the file, fail if it is not formatted.
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// Create a skeleton configuration file.
+#[derive(Debug, Parser)]
+pub struct InitArgs {
+    /// Overwrite the configuration file if it already exists.
+    #[arg(short, long)]
+    pub force: bool,
+    /// Declare the explicitly installed packages as common packages, grouped by the pacman groups
+    /// they belong to.
+    ///
+    /// Packages that do not belong to any group are listed first. A package that belongs to more
+    /// than one group is put in the first of them in alphabetical order.
+    #[arg(long)]
+    pub from_installed: bool,
 }
 
 /// Create links to configuration files in declared locations.
@@ -53,30 +398,164 @@ pub struct LinkArgs {
     /// Overwrite link targets if they already exist.
     #[arg(short, long)]
     pub force: bool,
+    /// With '--force', move the existing links that are replaced into DIR instead of removing them.
+    ///
+    /// A file keeps its path under DIR, e.g. `/etc/foo` is moved to `DIR/etc/foo`, replacing an
+    /// older backup of the same file. Missing directories are created.
+    #[arg(long, value_name = "DIR", requires = "force")]
+    pub backup_dir: Option<PathBuf>,
+    /// Only print the state of every declared link, without changing anything.
+    #[arg(long, conflicts_with = "force")]
+    pub stat: bool,
+    /// Print a message for every declared link, including ones that already exist.
+    ///
+    /// By default, only the number of links that already exist is printed.
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// Create links under DIR instead of the root directory, e.g. `/etc/foo` becomes `DIR/etc/foo`.
+    ///
+    /// Only the locations of links are moved, not the targets they point to. A leading `~` is
+    /// expanded to the home directory first, so `~/.bashrc` becomes `DIR/home/USER/.bashrc`.
+    #[arg(long, value_name = "DIR")]
+    pub prefix: Option<PathBuf>,
+    /// List links that are not declared, but point into the configuration directory, without
+    /// creating any links.
+    ///
+    /// Only the directories that contain declared links are searched, e.g. links left behind after
+    /// removing their declarations. The configuration directory is the one that relative paths in
+    /// the configuration file are resolved against.
+    #[arg(long, conflicts_with_all = ["force", "stat"])]
+    pub audit_orphans: bool,
+    /// Remove the links found by '--audit-orphans', after asking for confirmation.
+    #[arg(long, requires = "audit_orphans")]
+    pub remove: bool,
+    /// Do not ask for confirmation before removing links with '--remove'.
+    #[arg(short = 'y', long, requires = "remove")]
+    pub yes: bool,
+    /// The format of the output.
+    ///
+    /// With 'json', an object is printed to stdout, with the 'schema_version' of the output
+    /// format and an array of 'outcomes' with an object for every declared link: its 'location',
+    /// 'target' and 'action', one of 'created', 'updated', 'unchanged', 'skipped' or 'error'.
+    /// Messages are still printed to stderr.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        conflicts_with_all = ["stat", "audit_orphans"]
+    )]
+    pub format: OutputFormat,
 }
 
-/// Enable declared systemd services.
+/// Enable and mask declared systemd services.
 #[derive(Debug, Parser)]
 pub struct ServiceArgs {
+    /// List enabled unit files that are not declared, without changing anything.
+    #[arg(
+        long,
+        conflicts_with_all = ["daemon_reload", "reset", "start", "no_start", "restart_changed"]
+    )]
+    pub list_undeclared: bool,
+    /// Print the status of every declared service and what would be done to it, and why, without
+    /// changing anything.
+    #[arg(
+        long,
+        conflicts_with_all = ["list_undeclared", "daemon_reload", "reset", "restart_changed"]
+    )]
+    pub explain: bool,
+    /// Reload systemd unit files before enabling services.
+    ///
+    /// Needed when unit files were changed, e.g. by the link subcommand. Implied by '--reset' and
+    /// '--start'.
+    #[arg(long)]
+    pub daemon_reload: bool,
     /// Reset the enabled/disabled status of all services to their defaults.
     #[arg(long)]
     pub reset: bool,
-    /// Start the services when enabling them.
+    /// Start all services when enabling them.
     ///
-    /// Only affects declared services, has no effect on services enabled by `--reset`.
-    #[arg(long)]
+    /// Overrides the `start` setting of services in the configuration file. Only affects declared
+    /// services, has no effect on services enabled by `--reset`. Defaults to
+    /// `defaults.service.start` from the configuration file, or to off if it is not set.
+    #[arg(long, overrides_with = "no_start")]
     pub start: bool,
+    /// Only start services that set `start`, even if `defaults.service.start` is set.
+    #[arg(long, overrides_with = "start")]
+    pub no_start: bool,
+    /// Restart declared services that were already running.
+    ///
+    /// Useful for picking up changes to configuration files of running services. Services that are
+    /// started by this invocation are not restarted.
+    #[arg(long)]
+    pub restart_changed: bool,
+    /// Retry systemctl commands up to N times if they fail to communicate with the system manager,
+    /// e.g. right after boot.
+    ///
+    /// Other failures, like a unit that does not exist, are never retried.
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub retries: u32,
 }
 
 /// Display information about declared and currently installed packages.
 #[derive(Debug, Parser)]
 pub struct ShowArgs {
-    /// Equivalent to specifying '-e', '-i', '-r' and '-u'.
+    /// Equivalent to specifying '-e', '-g', '-i', '-r' and '-u'.
     #[arg(short = 'a', long)]
     pub all: bool,
+    /// Only display the summary, as a single line of `key=count` pairs.
+    ///
+    /// No lists of packages are displayed, regardless of other options.
+    #[arg(long)]
+    pub summary_only: bool,
+    /// Fail if the installed packages differ from the declared ones, i.e. if the sync subcommand
+    /// would install, remove or mark any packages.
+    ///
+    /// Unneeded packages only count if cleanup is enabled, see '--cleanup'. The counts of packages
+    /// that differ are included in the error. Exits with code 2 if they differ, other errors exit
+    /// with code 1.
+    #[arg(
+        long,
+        conflicts_with_all = ["why", "list_hosts", "explain_config", "effective"]
+    )]
+    pub exit_code: bool,
+    /// Count unneeded packages as a difference for '--exit-code'.
+    ///
+    /// Defaults to `defaults.sync.cleanup` from the configuration file, or to off if it is not set.
+    #[arg(long, requires = "exit_code", overrides_with = "no_cleanup")]
+    pub cleanup: bool,
+    /// Do not count unneeded packages as a difference, even if `defaults.sync.cleanup` is set.
+    #[arg(long, requires = "exit_code", overrides_with = "cleanup")]
+    pub no_cleanup: bool,
+    /// Display all packages that would be removed by the sync subcommand with '--cleanup'.
+    ///
+    /// Unlike '-r' and '-u', this includes the dependencies that would be removed recursively.
+    #[arg(long)]
+    pub cascade: bool,
+    /// Display all installed packages whose install reason differs from the declared one.
+    ///
+    /// These are the packages whose install reason would be changed by the sync subcommand.
+    #[arg(long)]
+    pub drift: bool,
+    /// Display all packages that are declared more than once, either directly or both directly and
+    /// as a member of a declared package group.
+    #[arg(long)]
+    pub duplicates: bool,
+    /// Display all explicitly installed packages, each marked as declared or undeclared.
+    ///
+    /// Only affects what is displayed.
+    #[arg(long, conflicts_with = "only_deps")]
+    pub only_explicit: bool,
+    /// Display all packages installed as dependencies, each marked as declared or undeclared.
+    ///
+    /// Only affects what is displayed.
+    #[arg(long)]
+    pub only_deps: bool,
     /// Display all packages that are declared and installed as dependencies.
     #[arg(short = 'e', long)]
     pub to_explicit: bool,
+    /// Display all packages that are not acted upon because pacman is configured to ignore them.
+    #[arg(short = 'g', long)]
+    pub ignored: bool,
     /// Display all packages that are declared and not installed.
     #[arg(short = 'i', long)]
     pub to_install: bool,
@@ -86,18 +565,394 @@ pub struct ShowArgs {
     /// Display all packages installed as dependencies that are not required by any package.
     #[arg(short = 'u', long)]
     pub unneeded: bool,
+    /// Display all installed packages that have newer versions available.
+    ///
+    /// Uses the package databases as they were last refreshed. If `aur_helper` is set in the
+    /// configuration file, foreign packages with newer versions in the AUR are included too, and
+    /// every package is labelled with where its new version comes from, 'repo' or 'aur'.
+    #[arg(long)]
+    pub updates: bool,
+    /// Display the packages, package groups and services whose declarations changed since the
+    /// last successful sync.
+    #[arg(long)]
+    pub changed: bool,
+    /// Only display every declaration in the configuration file that applies to this machine,
+    /// along with the section and the named groups of packages it was declared in.
+    #[arg(long)]
+    pub explain_config: bool,
+    /// Only display every hostname that has a section in the configuration file, along with the
+    /// values it has sections in. The hostname of this machine is marked with '*'.
+    #[arg(long, conflicts_with = "explain_config")]
+    pub list_hosts: bool,
+    /// Only display why PACKAGE is installed: whether it is declared and explicitly installed,
+    /// and the installed packages that depend on it, directly or through other packages.
+    #[arg(long, value_name = "PACKAGE", conflicts_with_all = ["explain_config", "list_hosts"])]
+    pub why: Option<String>,
+    /// Only display the names of all declared packages, with references to named groups of
+    /// packages and declared package groups resolved, sorted and one per line.
+    ///
+    /// Meant for comparing with the output of `pacman -Qqe`. Glob patterns are left out, because
+    /// they only match installed packages.
+    #[arg(long, conflicts_with_all = ["explain_config", "list_hosts", "why"])]
+    pub effective: bool,
+    /// Exclude packages matching the given glob pattern from this run. Can be used multiple times.
+    ///
+    /// Matching packages are left out of the lists of packages to install, remove, etc. The
+    /// configuration file is not changed.
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Do not query pacman for the packages that belong to declared package groups.
+    ///
+    /// Packages that belong to a declared package group are then only declared if declared
+    /// directly, which changes the counts of packages. Meant for machines without network
+    /// access or with stale package databases.
+    #[arg(long, alias = "no-group-resolution")]
+    pub no_groups: bool,
+    /// Write the output to PATH instead of stdout, replacing the file if it exists.
+    ///
+    /// Output written to a file is never styled. Warnings are still printed to stderr.
+    #[arg(short = 'o', long, alias = "target", value_name = "PATH")]
+    pub output: Option<PathBuf>,
+    /// Path to the pacman configuration file.
+    #[arg(long)]
+    pub pacman_conf: Option<PathBuf>,
 }
 
 /// Synchronize installed packages with the package list.
 #[derive(Debug, Parser)]
+#[command(group(
+    ArgGroup::new("partial_upgrade")
+        .args(["no_upgrade", "packages"])
+        .multiple(true)
+))]
 pub struct SyncArgs {
     /// Remove all unneeded packages.
-    #[arg(short = 'c', long)]
+    ///
+    /// Defaults to `defaults.sync.cleanup` from the configuration file, or to off if it is not set.
+    #[arg(short = 'c', long, overrides_with = "no_cleanup")]
     pub cleanup: bool,
+    /// Do not remove unneeded packages, even if `defaults.sync.cleanup` is set.
+    #[arg(long, overrides_with = "cleanup")]
+    pub no_cleanup: bool,
+    /// After removing packages, also remove old versions of packages from the package cache.
+    ///
+    /// Uses `paccache -r` if it is installed, otherwise falls back to `pacman -Sc`. Requires
+    /// cleanup, either with '--cleanup' or in the configuration file.
+    #[arg(long, conflicts_with = "no_cleanup")]
+    pub prune_cache: bool,
+    /// List the packages to remove along with the reason for their removal and ask for
+    /// confirmation before removing them.
+    ///
+    /// If the removal is declined, the applied state is not recorded.
+    #[arg(long)]
+    pub explain_removal: bool,
+    /// Remove only the packages that archman selected for removal, not their dependencies that
+    /// become unneeded.
+    ///
+    /// By default, dependencies of removed packages that are no longer needed by any other package
+    /// are removed along with them, as with `pacman -Rs`.
+    #[arg(long)]
+    pub no_recursive: bool,
+    /// Only print the packages that would be installed, upgraded and removed, as resolved by
+    /// pacman.
+    ///
+    /// Unlike '--dry-run', this includes dependencies. The package databases are not refreshed.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "explain_removal",
+            "yes",
+            "strict",
+            "count_upgrades",
+            "force_refresh",
+            "prune_cache",
+        ]
+    )]
+    pub preview: bool,
+    /// Do not ask for confirmation before removing packages with '--explain-removal'.
+    #[arg(short = 'y', long, requires = "explain_removal")]
+    pub yes: bool,
+    /// Ask which of the packages to install, mark, reinstall and remove to act upon, before doing
+    /// anything.
+    ///
+    /// Every group of packages can be accepted or skipped as a whole, or package by package.
+    /// Skipped packages are left alone, and the applied state is not recorded if any package other
+    /// than one to reinstall is skipped. Requires stdin to be a terminal.
+    #[arg(long, conflicts_with = "yes")]
+    pub interactive: bool,
+    /// Stop as soon as pacman fails, instead of continuing with the next step.
+    ///
+    /// Applies to marking packages as explicitly installed or as dependencies, upgrading and
+    /// installing packages, removing packages and pruning the package cache.
+    #[arg(long)]
+    pub strict: bool,
+    /// Count the packages that pacman actually upgraded, including ones not declared directly.
+    ///
+    /// The output of pacman is passed through archman to find them, which disables its progress
+    /// bars.
+    #[arg(long)]
+    pub count_upgrades: bool,
+    /// Force a refresh of all package databases, even if they appear to be up to date.
+    ///
+    /// Meant for recovering from corrupted package databases, not for routine use.
+    #[arg(long)]
+    pub force_refresh: bool,
+    /// Install the packages in the package databases that match declared glob patterns, like
+    /// 'python-*'.
+    ///
+    /// Without this, glob patterns only keep the installed packages that match them.
+    #[arg(long)]
+    pub expand_globs: bool,
+    /// Do not query pacman for the packages that belong to declared package groups.
+    ///
+    /// Packages that belong to a declared package group are then only declared if declared
+    /// directly. To avoid removing them, no packages are marked as dependencies or removed. The
+    /// applied state is not recorded.
+    #[arg(long, alias = "no-group-resolution", conflicts_with = "cleanup")]
+    pub no_groups: bool,
+    /// Do not upgrade, install or remove the given package. Can be used multiple times.
+    ///
+    /// The package is treated like packages ignored through `IgnorePkg` in the pacman configuration
+    /// file: it is not installed even if it is declared, and it is never removed or marked as
+    /// explicitly installed or as a dependency. Glob patterns, like 'linux*', only protect the
+    /// matching packages from removal.
+    #[arg(long, value_name = "PACKAGE")]
+    pub ignore: Vec<String>,
+    /// Exclude packages matching the given glob pattern from this run. Can be used multiple times.
+    ///
+    /// Matching packages are not installed, removed or marked as explicitly installed or as a
+    /// dependency. Unlike '--ignore', this does not prevent upgrading them. The configuration file
+    /// is not changed, and the applied state is not recorded.
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
     /// Do not upgrade packages.
     #[arg(long)]
     pub no_upgrade: bool,
+    /// Install new packages even with '--no-upgrade' or when only the given packages are synced,
+    /// accepting the risk of a partial upgrade.
+    ///
+    /// Installing packages without upgrading the installed ones is a partial upgrade, which is not
+    /// supported on Arch Linux: new packages may need newer versions of libraries than the ones
+    /// installed. Without this flag, archman refuses to do it.
+    #[arg(long, requires = "partial_upgrade")]
+    pub allow_partial: bool,
+    /// Only update the install reasons of installed packages, without installing, upgrading or
+    /// removing anything.
+    ///
+    /// Declared packages installed as dependencies are marked as explicitly installed, and
+    /// undeclared explicitly installed packages are marked as dependencies. The package databases
+    /// are not refreshed and the state file is not updated.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "cleanup",
+            "prune_cache",
+            "explain_removal",
+            "no_recursive",
+            "preview",
+            "yes",
+            "count_upgrades",
+            "force_refresh",
+            "expand_globs",
+            "no_upgrade",
+            "reinstall",
+            "packages",
+            "parallel_downloads",
+            "xkb_types",
+        ]
+    )]
+    pub reasons_only: bool,
+    /// Also reinstall declared packages that are already installed, e.g. to repair their files.
+    ///
+    /// Without arguments, all of them are reinstalled, otherwise only the given ones. Declared
+    /// packages that are not installed are installed either way.
+    #[arg(long, value_name = "PACKAGE", num_args = 0..)]
+    pub reinstall: Option<Vec<String>>,
+    /// Only sync the given packages, leaving all other packages alone.
+    ///
+    /// Given packages that are not installed are installed, installed ones are reinstalled, which
+    /// also upgrades them if a newer version is available. Their install reason is updated as
+    /// usual, but nothing is removed and no other packages are upgraded. Since that is a partial
+    /// upgrade, it requires '--allow-partial'.
+    #[arg(
+        value_name = "PACKAGE",
+        conflicts_with_all = ["reinstall", "cleanup", "explain_removal"]
+    )]
+    pub packages: Vec<String>,
+    /// Allow syncing given packages that are not declared in the configuration file.
+    ///
+    /// These are installed or reinstalled, but their install reason is left alone.
+    #[arg(long, requires = "packages")]
+    pub allow_undeclared: bool,
+    /// Download up to N packages in parallel, overriding `ParallelDownloads` from the pacman
+    /// configuration file.
+    #[arg(long, value_name = "N")]
+    pub parallel_downloads: Option<NonZeroU32>,
+    /// Path to the pacman configuration file.
+    #[arg(long)]
+    pub pacman_conf: Option<PathBuf>,
     /// Path to the xkb types file.
+    ///
+    /// Patched even if `xkb_patch = false` is set in the configuration file.
     #[arg(long)]
     pub xkb_types: Option<PathBuf>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negated_flags_override_defaults() {
+        let cleanup = |args: &[&str], default: bool| {
+            let args = Args::try_parse_from([&["archman", "sync"], args].concat()).unwrap();
+            match args.subcommand {
+                Subcommand::Sync(sync_args) => sync_args.cleanup(default),
+                _ => unreachable!(),
+            }
+        };
+        assert!(!cleanup(&[], false));
+        assert!(cleanup(&[], true));
+        assert!(cleanup(&["--cleanup"], false));
+        assert!(!cleanup(&["--no-cleanup"], true));
+        assert!(cleanup(&["--no-cleanup", "-c"], false));
+        assert!(!cleanup(&["-c", "--no-cleanup"], true));
+    }
+
+    #[test]
+    fn read_only_subcommands() {
+        let read_only = |args: &[&str]| {
+            let args = Args::try_parse_from([&["archman"], args].concat()).unwrap();
+            args.subcommand.is_read_only()
+        };
+        assert!(read_only(&["show"]));
+        assert!(read_only(&["sync", "--preview"]));
+        assert!(read_only(&["service", "--explain"]));
+        assert!(read_only(&["service", "--list-undeclared"]));
+        assert!(read_only(&["link", "--stat"]));
+        assert!(read_only(&["link", "--audit-orphans"]));
+        assert!(!read_only(&["link", "--audit-orphans", "--remove"]));
+        assert!(!read_only(&["sync"]));
+        assert!(!read_only(&["copy", "--watch"]));
+    }
+
+    #[test]
+    fn skip_system_upgrade_for_scoped_sync() {
+        let system_upgrade = |args: &[&str]| {
+            let args = Args::try_parse_from([&["archman", "sync"], args].concat()).unwrap();
+            match args.subcommand {
+                Subcommand::Sync(sync_args) => sync_args.system_upgrade(),
+                _ => unreachable!(),
+            }
+        };
+        assert!(system_upgrade(&[]));
+        assert!(!system_upgrade(&["--no-upgrade"]));
+        assert!(!system_upgrade(&["vim"]));
+    }
+
+    #[test]
+    fn partial_sync() {
+        let is_partial = |args: &[&str]| {
+            let args = Args::try_parse_from([&["archman", "sync"], args].concat()).unwrap();
+            match args.subcommand {
+                Subcommand::Sync(sync_args) => sync_args.is_partial(),
+                _ => unreachable!(),
+            }
+        };
+        assert!(!is_partial(&[]));
+        assert!(!is_partial(&["--no-upgrade"]));
+        assert!(is_partial(&["vim"]));
+        assert!(is_partial(&["--exclude", "linux*"]));
+        assert!(is_partial(&["--no-groups"]));
+    }
+
+    #[test]
+    fn require_partial_upgrade_for_allow_partial() {
+        let err = Args::try_parse_from(["archman", "sync", "--allow-partial"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        for args in [&["--no-upgrade"][..], &["linux"][..]] {
+            let args = [&["archman", "sync", "--allow-partial"], args].concat();
+            assert!(Args::try_parse_from(&args).is_ok(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn require_explain_removal_for_yes() {
+        let err = Args::try_parse_from(["archman", "sync", "--yes"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        assert!(Args::try_parse_from(["archman", "sync", "--explain-removal", "--yes"]).is_ok());
+    }
+
+    #[test]
+    fn reject_conflicting_flags() {
+        let conflicts = [
+            (&["sync", "--preview", "--yes"][..], "--yes"),
+            (&["sync", "--interactive", "--yes"][..], "--yes"),
+            (&["sync", "--preview", "--strict"][..], "--strict"),
+            (
+                &["sync", "--preview", "--force-refresh"][..],
+                "--force-refresh",
+            ),
+            (&["sync", "--preview", "--prune-cache"][..], "--prune-cache"),
+            (&["sync", "--cleanup", "linux"][..], "--cleanup"),
+            (
+                &["sync", "--explain-removal", "linux"][..],
+                "--explain-removal",
+            ),
+            (
+                &["sync", "--reinstall", "vim", "--", "linux"][..],
+                "--reinstall",
+            ),
+            (
+                &["sync", "--prune-cache", "--no-cleanup"][..],
+                "--no-cleanup",
+            ),
+            (&["sync", "--reasons-only", "--cleanup"][..], "--cleanup"),
+            (&["sync", "--reasons-only", "linux"][..], "--reasons-only"),
+            (&["service", "--list-undeclared", "--reset"][..], "--reset"),
+            (&["service", "--list-undeclared", "--start"][..], "--start"),
+            (
+                &["service", "--explain", "--restart-changed"][..],
+                "--restart-changed",
+            ),
+            (&["show", "--exit-code", "--why", "vim"][..], "--why"),
+            (&["show", "--exit-code", "--list-hosts"][..], "--list-hosts"),
+            (
+                &["show", "--exit-code", "--explain-config"][..],
+                "--explain-config",
+            ),
+            (&["show", "--exit-code", "--effective"][..], "--effective"),
+        ];
+        for (args, flag) in conflicts {
+            let err = Args::try_parse_from([&["archman"], args].concat()).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                clap::error::ErrorKind::ArgumentConflict,
+                "{:?}",
+                args
+            );
+            assert!(err.to_string().contains(flag), "{:?}: {}", args, err);
+        }
+
+        for args in [
+            &["sync", "--preview", "--no-upgrade"][..],
+            &["sync", "--no-cleanup", "linux"][..],
+            &["service", "--explain", "--start"][..],
+            &["show", "--exit-code", "--summary-only"][..],
+        ] {
+            let parsed = Args::try_parse_from([&["archman"], args].concat());
+            assert!(parsed.is_ok(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        for invalid in ["", "0", "0m", "m", "1.5h", "5 m", "5d", "-5"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
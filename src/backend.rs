@@ -0,0 +1,161 @@
+//! Abstraction over the package manager.
+//!
+//! The logic of synchronizing and displaying packages only talks to a [`PackageBackend`]. The only
+//! implementation right now is [`Pacman`](crate::pacman::Pacman).
+
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+    path::Path,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::args::RunMode;
+
+/// The return type of all backend calls.
+pub type Result<T, E = BackendError> = std::result::Result<T, E>;
+
+/// Errors that can occur when using a package backend.
+#[derive(Debug, Error)]
+pub enum BackendError {
+    /// The package manager was run, but did not exit successfully.
+    #[error("{0} did not exit successfully")]
+    ExitFailure(&'static str),
+    /// Any other error.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Filter for packages returned from a query.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryFilter {
+    /// Constrain the install reason.
+    pub install_reason: Option<InstallReason>,
+    /// Only packages not (optionally) required by any other package.
+    // TODO: ignore optional dependencies?
+    pub unrequired: bool,
+    /// Only outdated packages.
+    pub outdated: bool,
+    /// Only packages not found in any of the package databases, e.g. built manually or from AUR.
+    pub foreign: bool,
+}
+
+/// Options for [`PackageBackend::sync`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncOptions<'a> {
+    /// Refresh package databases even if they appear up to date.
+    pub force_refresh: bool,
+    /// Update outdated packages.
+    pub system_upgrade: bool,
+    /// Packages that should not be upgraded.
+    pub ignore: &'a [String],
+    /// The number of packages to download in parallel, if it should be overridden.
+    pub parallel_downloads: Option<NonZeroU32>,
+    /// The configuration file of the package manager that overrides are applied on top of, if not
+    /// the default one.
+    pub config: Option<&'a Path>,
+    /// Capture the output of the package manager to find out which packages were in the
+    /// transaction, while still passing it through.
+    pub capture_transaction: bool,
+}
+
+/// A pending upgrade of an installed package.
+#[derive(Clone, Debug)]
+pub struct Upgrade {
+    /// The name of the package.
+    pub name: String,
+    /// The currently installed version.
+    pub installed_version: String,
+    /// The version available in the package databases, or in the AUR for foreign packages.
+    pub new_version: String,
+    /// Whether the package is foreign, with the new version found by an AUR helper.
+    pub foreign: bool,
+}
+
+/// Install reason of a package.
+#[derive(Clone, Copy, Debug)]
+pub enum InstallReason {
+    /// Explicitly installed.
+    Explicit,
+    /// Installed as a dependency of another package.
+    Dependency,
+}
+
+/// Operations on packages provided by a package manager.
+///
+/// Operations that modify the system take a [`RunMode`] and must not modify anything in a dry run.
+pub trait PackageBackend: Sync {
+    /// Returns the names of installed packages that match the `filter`.
+    fn query(&self, filter: QueryFilter) -> Result<HashSet<String>>;
+
+    /// Returns the names of all packages in the package databases, installed or not.
+    fn available(&self) -> Result<HashSet<String>>;
+
+    /// Returns the installed packages that have newer versions available.
+    ///
+    /// The result is only as fresh as the local copies of the package databases.
+    fn query_upgrades(&self) -> Result<Vec<Upgrade>>;
+
+    /// Returns the foreign packages that have newer versions in the AUR, as reported by the AUR
+    /// `helper`.
+    fn query_aur_upgrades(&self, helper: &str) -> Result<Vec<Upgrade>>;
+
+    /// Returns the names of the installed packages among `packages`.
+    fn installed(&self, packages: &[&str]) -> Result<HashSet<String>>;
+
+    /// Returns the installed version of `package`, or `None` if it is not installed.
+    fn installed_version(&self, package: &str) -> Result<Option<String>>;
+
+    /// Returns the installed packages that depend on the installed `package`, directly or through
+    /// other packages, as the lines of an indented tree rooted at `package`.
+    fn why(&self, package: &str) -> Result<Vec<String>>;
+
+    /// Returns the time since the package databases were last refreshed, if known.
+    fn database_age(&self) -> Result<Option<Duration>>;
+
+    /// Returns the packages that belong to the given `groups`, mapped to the group they belong to.
+    fn groups<'a>(&self, groups: &[&'a str]) -> Result<HashMap<String, &'a str>>;
+
+    /// Returns the installed packages that belong to any group, mapped to all groups they belong
+    /// to.
+    fn installed_groups(&self) -> Result<HashMap<String, Vec<String>>>;
+
+    /// Sets the install reason of the given installed `packages`.
+    fn set_install_reason(
+        &self,
+        install_reason: InstallReason,
+        packages: &[&str],
+        mode: RunMode,
+    ) -> Result<()>;
+
+    /// Refreshes package databases, optionally upgrades installed packages and installs `packages`.
+    ///
+    /// If `options.capture_transaction` is set, returns the names of all packages that were part of
+    /// the transaction, as reported by the package manager. Otherwise returns nothing.
+    fn sync(
+        &self,
+        options: SyncOptions<'_>,
+        packages: &[&str],
+        mode: RunMode,
+    ) -> Result<Vec<String>>;
+
+    /// Removes `packages`, along with their dependencies that are no longer needed if `recursive`
+    /// is set.
+    fn remove(&self, packages: &[&str], recursive: bool, mode: RunMode) -> Result<()>;
+
+    /// Removes old versions of packages from the package cache.
+    ///
+    /// Returns the number of bytes freed, if it could be determined.
+    fn prune_cache(&self, mode: RunMode) -> Result<Option<u64>>;
+
+    /// Returns all packages that would be removed by [`remove`](PackageBackend::remove).
+    fn removal_preview(&self, packages: &[&str], recursive: bool) -> Result<Vec<String>>;
+
+    /// Returns the names and versions of all packages that would be installed or upgraded by
+    /// [`sync`](PackageBackend::sync), as resolved by the package manager itself.
+    ///
+    /// The package databases are not refreshed.
+    fn sync_preview(&self, options: SyncOptions<'_>, packages: &[&str]) -> Result<Vec<String>>;
+}
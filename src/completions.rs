@@ -1,20 +1,121 @@
 //! Generating tab-completion scripts.
+//!
+//! Package names are completed dynamically: the script calls the hidden `__complete-packages`
+//! subcommand, which prints the names of declared and installed packages.
 
-use std::io;
+use std::{
+    collections::BTreeSet,
+    io::{self, BufWriter, Write},
+};
 
+use anyhow::Context;
 use clap::{crate_name, CommandFactory};
-use clap_complete::shells::Zsh;
+use clap_complete::{shells::Zsh, Generator};
 
-use crate::args::{Args, CompletionsArgs};
+use crate::{
+    args::{Args, CompletePackagesArgs, CompletionsArgs},
+    backend::PackageBackend,
+    config::Config,
+    packages,
+};
 
-/// Generates the tab-completion script for this application.
-///
-/// # Panics
-///
-/// Will panic on any kind of IO error related to writing to the provided directory. It would be
-/// nicer to return an error instead, but [`clap`] doesn't let us do that.
+/// The zsh function that completes package names.
+const ZSH_PACKAGES_FUNCTION: &str = r#"(( $+functions[_archman_packages] )) ||
+_archman_packages() {
+    local -a packages
+    packages=(${(f)"$(_call_program packages archman __complete-packages 2>/dev/null)"})
+    _wanted packages expl 'package' compadd -a packages
+}
+
+"#;
+
+/// Generates the tab-completion script for this application and writes it to `stdout`.
 pub fn generate_completions(args: CompletionsArgs) -> anyhow::Result<()> {
     let CompletionsArgs {} = args;
-    clap_complete::generate(Zsh, &mut Args::command(), crate_name!(), &mut io::stdout());
-    Ok(())
+    io::stdout()
+        .write_all(zsh_script().as_bytes())
+        .context("Failed to write the completion script to stdout")
+}
+
+/// Prints the names of all declared and installed packages, sorted and one per line.
+pub fn print_package_names(
+    args: CompletePackagesArgs,
+    cfg: &Config,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    let CompletePackagesArgs {} = args;
+    let declared_packages = cfg.packages()?;
+    let (installed, group_packages) =
+        packages::query_packages_and_groups(backend, &cfg.package_groups().elements)?;
+    let declared = packages::merge_declared_packages(&declared_packages.elements, &group_packages);
+
+    let mut names: BTreeSet<&str> = declared.packages.iter().copied().collect();
+    names.extend(installed.names());
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for name in names {
+        writeln!(stdout, "{}", name).context("Failed to write package names to stdout")?;
+    }
+    stdout
+        .flush()
+        .context("Failed to write package names to stdout")
+}
+
+/// Returns the zsh completion script, with package names completed by `_archman_packages`.
+fn zsh_script() -> String {
+    let script = String::from_utf8(completions_script(Zsh))
+        .expect("generated completion script should be valid UTF-8");
+    let mut output = String::with_capacity(script.len() + ZSH_PACKAGES_FUNCTION.len());
+    for line in script.split_inclusive('\n') {
+        if line.starts_with("if [ \"$funcstack[1]\" = \"_") {
+            output.push_str(ZSH_PACKAGES_FUNCTION);
+        }
+        output.push_str(&complete_packages(line));
+    }
+    output
+}
+
+/// Makes a line of the zsh script complete package names, if it describes a package argument.
+fn complete_packages(line: &str) -> String {
+    if line.contains(":PACKAGE: ' \\") {
+        line.replacen(":PACKAGE: ' \\", ":PACKAGE:_archman_packages' \\", 1)
+    } else if line.trim_start().starts_with("'*::packages -- ") && line.contains(":' \\") {
+        line.replacen(":' \\", ":_archman_packages' \\", 1)
+    } else {
+        line.to_owned()
+    }
+}
+
+/// Returns the tab-completion script for the given shell.
+fn completions_script<G: Generator>(shell: G) -> Vec<u8> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Args::command(), crate_name!(), &mut script);
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zsh_script_contains_all_subcommands() {
+        let script = String::from_utf8(completions_script(Zsh)).unwrap();
+        assert!(script.contains(crate_name!()));
+        for subcommand in Args::command().get_subcommands() {
+            let name = subcommand.get_name();
+            assert!(script.contains(name), "missing subcommand {:?}", name);
+        }
+    }
+
+    #[test]
+    fn zsh_script_completes_package_names() {
+        let script = zsh_script();
+        assert!(script.contains("_archman_packages() {"));
+        assert!(script
+            .lines()
+            .any(|line| line.contains("--why") && line.ends_with(":_archman_packages' \\")));
+        assert!(script
+            .lines()
+            .any(|line| line.contains("'*::packages -- ")
+                && line.ends_with(":_archman_packages' \\")));
+    }
 }
@@ -2,35 +2,131 @@
 
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
     env,
     ffi::{OsStr, OsString},
-    fs,
+    fmt, fs,
     hash::Hash,
+    io::{self, ErrorKind},
+    mem,
     os::unix::ffi::OsStrExt,
     path::{Component, Path, PathBuf},
 };
 
-use anyhow::{anyhow, bail, Context};
-use serde::Deserialize;
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
 
-use crate::args::ArgsCommon;
+use crate::args::{ArgsCommon, ConfigFormat};
+
+use self::format::FormatError;
+
+mod format;
+mod lint;
+
+/// The return type of all fallible config functions.
+type Result<T, E = ConfigError> = std::result::Result<T, E>;
+
+/// Errors that can occur when reading the configuration file.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The configuration file does not exist.
+    #[error("The configuration file {0:?} does not exist")]
+    NotFound(PathBuf),
+    /// The drop-in directory could not be read.
+    #[error("Failed to read the drop-in configuration directory {path:?}")]
+    DropInDir {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The configuration file could not be read.
+    #[error("Failed to read the contents of file {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The configuration file is not valid UTF-8.
+    #[error(
+        "Failed to parse the configuration file {path:?}: line {line} is not valid UTF-8, paths \
+         in the configuration file must be valid UTF-8"
+    )]
+    InvalidUtf8 { path: PathBuf, line: usize },
+    /// The configuration file is not a valid document in its format or does not match the expected
+    /// schema.
+    #[error("Failed to parse the configuration file {path:?}")]
+    ParseError {
+        path: PathBuf,
+        #[source]
+        source: FormatError,
+    },
+    /// The directory containing the configuration file could not be determined.
+    #[error("Failed to determine the canonical path to the configuration file {path:?}")]
+    Canonicalize {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The home directory of the invoking user could not be determined.
+    #[error("Unable to locate the home directory: the environment variable HOME is not set")]
+    HomeNotFound,
+    /// The passwd file could not be read.
+    #[error("Failed to read the contents of the {PASSWD_PATH:?} file")]
+    PasswdRead(#[source] io::Error),
+    /// The passwd file contains an invalid line.
+    #[error("Invalid line in the passwd file: {0}")]
+    PasswdParse(&'static str),
+    /// The user does not have an entry in the passwd file.
+    #[error("Could not find the user {0:?} in the passwd file")]
+    UserNotFound(OsString),
+    /// A declared package refers to a group of packages that is not declared.
+    #[error("Reference to undeclared package group {0:?}")]
+    UndeclaredGroup(String),
+    /// References to groups of packages form a cycle.
+    #[error("Package group {name:?} references itself: {cycle}")]
+    GroupCycle { name: String, cycle: String },
+    /// Tags are given for something other than packages.
+    #[error(
+        "Failed to parse the configuration file {path:?}: `{key}.tags` is not supported, only \
+         packages can be declared for tags"
+    )]
+    UnsupportedTags { path: PathBuf, key: &'static str },
+}
 
 /// The configuration specified in the config file.
 #[derive(Debug)]
 pub struct Config {
-    /// The directory where the file is located.
+    /// The directory that relative paths are resolved against, see [`config_dir`].
     dir: PathBuf,
     /// The path to the user's home directory.
     home: PathBuf,
     /// The hostname of the machine.
     hostname: OsString,
+    /// The user that should own created copies and links, if not the invoking user.
+    target_user: Option<OsString>,
+    /// The tags given with `--tag`, selecting additional sets of packages.
+    tags: Vec<String>,
+    /// The path to the file that records the state applied by the last successful sync.
+    state_file: PathBuf,
     /// The parsed contents of the file.
     data: ConfigData<OsString>,
+    /// The paths that the configuration was read from, see [`Config::source_paths`].
+    source_paths: Vec<PathBuf>,
+    /// The arguments that the configuration was read with, kept for [`Config::reload`].
+    args: ArgsCommon,
 }
 
 /// All values that can be specified in the configuration file.
 ///
+/// The file must be valid UTF-8, so paths that are not valid UTF-8 cannot be specified. It can be
+/// written in TOML, or in YAML or JSON if support for them is enabled, see [`ConfigFormat`].
+///
+/// Every `*.toml` file in the `conf.d` directory next to the configuration file, as well as every
+/// `*.yaml`, `*.yml` and `*.json` file if the format is supported, is read after it, in
+/// alphabetical order, and merged into it, see [`ConfigData::merge`]. Relative paths in these files are resolved
+/// relative to the directory of the main configuration file.
+///
 /// `H` is the type of a hostname: we must deserialize it as a [`String`], but it is more convenient
 /// to store it as an [`OsString`].
 #[derive(Debug, Deserialize)]
@@ -41,14 +137,14 @@ struct ConfigData<H> {
     /// path to the original file specified in the section for a specific host overrides the path
     /// specified in the `common` section.
     #[serde(default, bound = "H: Deserialize<'de> + Eq + Hash")]
-    copies: PerHostname<H, HashMap<String, String>>,
+    copies: PerHostname<H, PathMap>,
     /// The files that should be linked from somewhere on the filesystem.
     ///
     /// The maps map locations of the links to the link targets. For a single path to a link, the
     /// path to the target specified in the section for a specific host overrides the path specified
     /// in the `common` section.
     #[serde(default, bound = "H: Deserialize<'de> + Eq + Hash")]
-    links: PerHostname<H, HashMap<String, String>>,
+    links: PerHostname<H, PathMap>,
     /// The groups of packages that should be installed on our system.
     ///
     /// The effective set of groups is a set union of groups specified in the `common` section and
@@ -59,19 +155,96 @@ struct ConfigData<H> {
     ///
     /// The effective set of packages is a set union of packages specified in the `common` section
     /// and those specified for a specific host.
+    ///
+    /// The packages declared for every tag given with `--tag` are added after those, in the order
+    /// in which the tags were given.
+    ///
+    /// An entry of the form `@name` refers to every named group of packages called `name`, in
+    /// any of these sections. References are expanded transitively, and must not form a cycle.
+    ///
+    /// An entry that contains `*`, `?` or `[` is a glob pattern, like `python-*`. Installed
+    /// packages that match it are treated as declared, so they are kept and marked as explicitly
+    /// installed. A pattern never installs anything, unless the sync subcommand is run with
+    /// `--expand-globs`.
     #[serde(default, bound = "H: Deserialize<'de> + Eq + Hash")]
     packages: PerHostname<H, NestedSet<String>>,
+    /// Named groups of packages that are only installed where they are referenced.
+    ///
+    /// A bundle called `name` is referenced with `@name` from any section of `packages` or from
+    /// another bundle, together with every named group called `name` declared in `packages`.
+    /// Unlike those groups, a bundle that is never referenced declares no packages. References
+    /// must not form a cycle, reading the declared packages fails if they do.
+    #[serde(default)]
+    bundles: IndexMap<String, NestedSet<String>>,
     /// The systemd services that should be enabled on our system.
     ///
     /// The effective set of services is a set union of services specified in the `common` section
     /// and those specified for a specific host.
     #[serde(default, bound = "H: Deserialize<'de> + Eq + Hash")]
-    services: PerHostname<H, Vec<String>>,
+    services: PerHostname<H, Vec<ServiceEntry>>,
+    /// The systemd services that should be masked on our system.
+    ///
+    /// The effective set of services is a set union of services specified in the `common` section
+    /// and those specified for a specific host.
+    #[serde(default, bound = "H: Deserialize<'de> + Eq + Hash")]
+    masked_services: PerHostname<H, Vec<String>>,
+    /// Path to the pacman configuration file.
+    pacman_conf: Option<String>,
     /// Path to the xkb types file.
-    xkb_types: Option<String>,
+    ///
+    /// A single path applies to all hosts, like the `common` section. A path specified for a
+    /// specific host overrides the one specified in the `common` section.
+    #[serde(
+        default,
+        deserialize_with = "common_or_per_hostname",
+        bound = "H: Deserialize<'de> + Eq + Hash"
+    )]
+    xkb_types: PerHostname<H, String>,
+    /// Whether the xkb types file is patched at all, on by default.
+    ///
+    /// When off, `xkb_types` is ignored, as if no path was configured.
+    xkb_patch: Option<bool>,
+    /// Packages that make a reboot advisable when they are upgraded, if not the default ones.
+    reboot_packages: Option<Vec<String>>,
+    /// The AUR helper used to find updates of foreign packages, like `paru` or `yay`.
+    aur_helper: Option<String>,
+    /// Defaults for command line flags.
+    #[serde(default)]
+    defaults: Defaults,
+}
+
+/// Defaults for command line flags, grouped by subcommand.
+///
+/// A flag given on the command line, or its negation like `--no-cleanup`, takes precedence over
+/// these. Flags that are not set here are off by default.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct Defaults {
+    /// Defaults for the sync subcommand.
+    #[serde(default)]
+    sync: SyncDefaults,
+    /// Defaults for the service subcommand.
+    #[serde(default)]
+    service: ServiceDefaults,
+}
+
+/// Defaults for flags of the sync subcommand.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct SyncDefaults {
+    /// The default of `--cleanup`.
+    cleanup: Option<bool>,
+}
+
+/// Defaults for flags of the service subcommand.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct ServiceDefaults {
+    /// The default of `--start`.
+    start: Option<bool>,
 }
 
 /// Value that can have different definitions depending on the hostname of the machine.
+///
+/// Hostnames are matched case-insensitively: both the hostname of the machine and the hostnames of
+/// sections are converted to lowercase, see [`ConfigData::lowercase_hostnames`].
 #[derive(Debug, Deserialize)]
 struct PerHostname<K, T> {
     /// Values common to all hostnames.
@@ -79,113 +252,367 @@ struct PerHostname<K, T> {
     /// Values specific to some hostnames.
     #[serde(default, bound = "K: Deserialize<'de> + Eq + Hash")]
     hosts: HashMap<K, T>,
+    /// Values that only apply when the tag is given with `--tag`, regardless of the hostname.
+    ///
+    /// Only supported for packages, reading the configuration fails if any other values are
+    /// declared for tags.
+    #[serde(default)]
+    tags: HashMap<String, T>,
+}
+
+/// Deserializes a [`PerHostname`] value that can also be given directly, as the value common to
+/// all hostnames.
+fn common_or_per_hostname<'de, D, K, T>(deserializer: D) -> Result<PerHostname<K, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+    PerHostname<K, T>: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(
+        untagged,
+        bound = "T: Deserialize<'de>, PerHostname<K, T>: Deserialize<'de>"
+    )]
+    enum CommonOrPerHostname<K, T> {
+        Common(T),
+        PerHostname(PerHostname<K, T>),
+    }
+
+    Ok(match CommonOrPerHostname::deserialize(deserializer)? {
+        CommonOrPerHostname::Common(common) => PerHostname {
+            common: Some(common),
+            ..PerHostname::default()
+        },
+        CommonOrPerHostname::PerHostname(per_hostname) => per_hostname,
+    })
+}
+
+/// Merges values that can only be declared once, the value read later wins.
+fn replace<T>(value: &mut T, other: T) {
+    *value = other;
+}
+
+/// A mapping of locations of links or copies to their targets or original files.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum PathMap {
+    /// A map, the entries are processed in an unspecified order.
+    Unordered(HashMap<String, String>),
+    /// An array of entries, the entries are processed in the order of declaration.
+    Ordered(Vec<PathMapEntry>),
+}
+
+/// A single entry of an ordered [`PathMap`].
+#[derive(Debug, PartialEq, Deserialize)]
+struct PathMapEntry {
+    /// The location of the link or copy.
+    location: String,
+    /// The target of the link or the original file.
+    #[serde(alias = "original")]
+    target: String,
+    /// Skip the link or copy if the target or the original file does not exist.
+    #[serde(default)]
+    optional: bool,
+    /// Skip the link or copy unless this package is installed.
+    requires: Option<String>,
+}
+
+/// The target of a link or the original file of a copy.
+#[derive(Debug)]
+pub struct Source {
+    /// The path to the target or the original file.
+    pub path: PathBuf,
+    /// Whether the link or copy should be skipped if `path` does not exist.
+    pub optional: bool,
+    /// The package that must be installed for the link or copy to be created, if any.
+    pub requires: Option<String>,
+}
+
+/// A declaration of a systemd service.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum ServiceEntry {
+    /// Just the name of the service.
+    Name(String),
+    /// The name of the service along with additional options.
+    Detailed {
+        /// The name of the service.
+        name: String,
+        /// Whether the service should also be started when it is enabled.
+        #[serde(default)]
+        start: bool,
+        /// The package that must be installed for the service to be enabled, if any.
+        requires: Option<String>,
+    },
 }
 
 /// A nested set of _things_.
 ///
 /// The _things_ in the set can be grouped into named or unnamed groups, with arbitrary nesting.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 #[serde(untagged)]
 enum NestedSet<T> {
     /// A single _thing_.
     Singleton(T),
     /// A group of sets of _things_, where each set has a name.
-    Map(HashMap<String, NestedSet<T>>),
+    Map(IndexMap<String, NestedSet<T>>),
     /// A group of sets of _things_, where sets don't have names.
     Array(Vec<NestedSet<T>>),
 }
 
+/// A single declaration in the configuration file, along with where it was declared.
+#[derive(Debug)]
+pub struct Declaration<'a> {
+    /// What is declared, e.g. `package` or `link`.
+    pub kind: &'static str,
+    /// The declared value. For links and copies, the location along with the target or the
+    /// original file.
+    pub value: String,
+    /// The section of the configuration file that contains the declaration.
+    pub section: Section<'a>,
+    /// The named groups that a package is declared in or reached through, outermost first.
+    pub groups: Vec<&'a str>,
+}
+
+/// A section of a value that can have different definitions depending on the hostname.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section<'a> {
+    /// The `common` section.
+    Common,
+    /// The section for the given hostname.
+    Host(&'a OsStr),
+    /// The section for the given tag.
+    Tag(&'a str),
+}
+
+/// Converts [`NestedSet`]s into a [`FlattenedSet`], expanding references to named groups.
+struct SetFlattener<'a, T> {
+    /// The named groups that can be referenced, there may be more than one group with a name.
+    groups: HashMap<&'a str, Vec<&'a NestedSet<T>>>,
+    /// The elements that were declared directly, not through a reference.
+    declared: HashSet<&'a str>,
+    /// The names of the groups that are currently being flattened, outermost first.
+    path: Vec<&'a str>,
+    /// The result of flattening.
+    flattened: FlattenedSet<&'a str>,
+    /// Every element added to the flattened set, along with the value of `path` at that point.
+    origins: Vec<(&'a str, Vec<&'a str>)>,
+}
+
 /// A flattened [`NestedSet`].
+///
+/// Both sets preserve the order in which the elements were declared.
 #[derive(Debug)]
 pub struct FlattenedSet<T> {
     /// The elements of the set.
-    pub elements: HashSet<T>,
+    pub elements: IndexSet<T>,
     /// The elements that occured more than once.
-    pub duplicates: HashSet<T>,
+    pub duplicates: IndexSet<T>,
 }
 
 impl Config {
     /// Reads the configuration file from the given path or the default path.
-    pub fn read_from_file(args: ArgsCommon) -> anyhow::Result<Self> {
-        let home = match args.home {
-            Some(home) => home,
-            None => get_home_directory().context("Unable to locate the home directory")?,
+    pub fn read_from_file(args: ArgsCommon) -> Result<Self> {
+        let original_args = args.clone();
+        let target_user = args.target_user.clone();
+        let tags = args.tag.clone();
+        let no_follow_link = args.no_follow_config_link;
+        let explicit_format = args.config_format;
+        let (home, effective_path) = Self::locate(args)?;
+        let state_file = Self::default_state_path(&home);
+        let home = match &target_user {
+            Some(user) => get_home_of_user(user)?,
+            None => home,
         };
-        let effective_path = args.config.unwrap_or_else(|| Self::default_path(&home));
 
-        let contents = fs::read_to_string(&effective_path)
-            .with_context(|| format!("Failed to read the contents of file {:?}", effective_path))?;
-        let raw_data: ConfigData<String> = toml::from_str(&contents).with_context(|| {
-            format!(
-                "Failed to parse the configuration file {:?}",
-                effective_path
-            )
-        })?;
+        let format = file_format(explicit_format, &effective_path);
+        let mut raw_data = read_data(&effective_path, format)?;
+
+        let dir = config_dir(&effective_path, !no_follow_link)?;
+
+        let drop_in_dir = dir.join(DROP_IN_DIR);
+        let mut source_paths = vec![effective_path, drop_in_dir.clone()];
+        for (drop_in, format) in drop_in_files(&drop_in_dir)? {
+            raw_data.merge(read_data(&drop_in, format)?);
+            source_paths.push(drop_in);
+        }
+        for tag in &tags {
+            if !raw_data.packages.tags.contains_key(tag) {
+                warn!("no packages are declared for tag {:?}", tag);
+            }
+        }
+
         let data = ConfigData {
             copies: raw_data.copies.map_keys(OsString::from),
             links: raw_data.links.map_keys(OsString::from),
             package_groups: raw_data.package_groups.map_keys(OsString::from),
             packages: raw_data.packages.map_keys(OsString::from),
+            bundles: raw_data.bundles,
             services: raw_data.services.map_keys(OsString::from),
-            xkb_types: raw_data.xkb_types,
-        };
-
-        let absolute_path = effective_path
-            .canonicalize()
-            .context("Failed to determine the canonical path to the configuration file.")?;
-        let dir = match absolute_path.parent() {
-            Some(_) => {
-                let mut parent = absolute_path;
-                parent.pop();
-                parent
-            }
-            None => bail!(
-                "Path to the configuration file ({:?}) has no parent directory",
-                absolute_path
-            ),
+            masked_services: raw_data.masked_services.map_keys(OsString::from),
+            pacman_conf: raw_data.pacman_conf,
+            xkb_types: raw_data.xkb_types.map_keys(OsString::from),
+            xkb_patch: raw_data.xkb_patch,
+            reboot_packages: raw_data.reboot_packages,
+            aur_helper: raw_data.aur_helper,
+            defaults: raw_data.defaults,
         };
 
         Ok(Self {
             dir,
             home,
-            hostname: gethostname::gethostname(),
+            hostname: get_hostname(gethostname::gethostname(), Path::new(HOSTNAME_PATH))
+                .to_ascii_lowercase(),
+            target_user,
+            tags,
+            state_file,
             data,
+            source_paths,
+            args: original_args,
         })
     }
 
+    /// Reads the configuration again, with the same arguments it was first read with.
+    pub fn reload(&self) -> Result<Self> {
+        Self::read_from_file(self.args.clone())
+    }
+
+    /// Returns the paths that the configuration was read from: the configuration file, the drop-in
+    /// directory, which might not exist, and every drop-in file in it.
+    ///
+    /// A change to any of them can change the configuration.
+    pub fn source_paths(&self) -> &[PathBuf] {
+        &self.source_paths
+    }
+
+    /// Returns the path to the user's home directory and the path to the configuration file.
+    pub fn locate(args: ArgsCommon) -> Result<(PathBuf, PathBuf)> {
+        let home = match args.home {
+            Some(home) => home,
+            None => get_home_directory()?,
+        };
+        let effective_path = args.config.unwrap_or_else(|| Self::default_path(&home));
+        Ok((home, effective_path))
+    }
+
+    /// Returns the directory that relative paths are resolved against, usually the directory that
+    /// contains the configuration file and the files it refers to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the user that should own created copies and links, if given with `--target-user`.
+    pub fn target_user(&self) -> Option<&OsStr> {
+        self.target_user.as_deref()
+    }
+
     fn default_path(home: &Path) -> PathBuf {
         let mut path = PathBuf::from(home);
         path.push(".config/archman/archman.toml");
         path
     }
 
-    pub fn xkb_types(&self) -> Option<PathBuf> {
+    /// Returns the path to the file that records the state applied by the last successful sync.
+    pub fn state_file(&self) -> &Path {
+        &self.state_file
+    }
+
+    /// Returns the path to the file locked by subcommands that change the system.
+    pub fn lock_file(&self) -> PathBuf {
+        self.state_file.with_file_name("lock")
+    }
+
+    /// Returns the path to the file that records which copies have the contents of their original
+    /// files, see [`crate::copy_cache`].
+    pub fn copy_cache_file(&self) -> PathBuf {
+        self.state_file.with_file_name("copies.toml")
+    }
+
+    fn default_state_path(home: &Path) -> PathBuf {
+        let mut path = PathBuf::from(home);
+        path.push(".local/state/archman/last-run.toml");
+        path
+    }
+
+    pub fn pacman_conf(&self) -> Option<PathBuf> {
         self.data
-            .xkb_types
+            .pacman_conf
             .as_ref()
             .map(|p| self.resolve_path(p.as_ref()))
     }
 
-    pub fn copies(&self) -> HashMap<PathBuf, PathBuf> {
+    /// Returns the path to the xkb types file, the one for this host if there is one.
+    ///
+    /// Returns `None` if patching the xkb types file is turned off with `xkb_patch`.
+    pub fn xkb_types(&self) -> Option<PathBuf> {
+        if self.data.xkb_patch == Some(false) {
+            return None;
+        }
+        self.sections(&self.data.xkb_types)
+            .last()
+            .map(|(_, p)| self.resolve_path(p.as_ref()))
+    }
+
+    /// Returns the default of `--cleanup` for the sync subcommand.
+    pub fn default_cleanup(&self) -> bool {
+        self.data.defaults.sync.cleanup.unwrap_or(false)
+    }
+
+    /// Returns the default of `--start` for the service subcommand.
+    pub fn default_start(&self) -> bool {
+        self.data.defaults.service.start.unwrap_or(false)
+    }
+
+    /// Returns the packages that make a reboot advisable when they are upgraded.
+    pub fn reboot_packages(&self) -> Vec<&str> {
+        match &self.data.reboot_packages {
+            Some(packages) => packages.iter().map(String::as_str).collect(),
+            None => DEFAULT_REBOOT_PACKAGES.to_vec(),
+        }
+    }
+
+    /// Returns the AUR helper used to find updates of foreign packages, if one is configured.
+    pub fn aur_helper(&self) -> Option<&str> {
+        self.data.aur_helper.as_deref()
+    }
+
+    pub fn copies(&self) -> IndexMap<PathBuf, Source> {
         self.merge_links_or_copies(&self.data.copies)
     }
 
-    pub fn links(&self) -> HashMap<PathBuf, PathBuf> {
+    pub fn links(&self) -> IndexMap<PathBuf, Source> {
         self.merge_links_or_copies(&self.data.links)
     }
 
+    /// Merges the common and host-specific paths.
+    ///
+    /// Entries declared in the ordered form keep their order, a host-specific entry that overrides
+    /// a common one takes the place of the common entry.
     fn merge_links_or_copies(
         &self,
-        paths: &PerHostname<OsString, HashMap<String, String>>,
-    ) -> HashMap<PathBuf, PathBuf> {
-        let mut ret = HashMap::new();
-        let mut extend = |map: &HashMap<String, String>| {
-            ret.extend(map.iter().map(|(location, target)| {
-                (
-                    self.resolve_path(location.as_ref()),
-                    self.resolve_path(target.as_ref()),
-                )
-            }));
+        paths: &PerHostname<OsString, PathMap>,
+    ) -> IndexMap<PathBuf, Source> {
+        let mut ret = IndexMap::new();
+        let mut extend = |map: &PathMap| {
+            let resolve =
+                |location: &String, target: &String, optional, requires: Option<&String>| {
+                    let source = Source {
+                        path: self.resolve_path(target.as_ref()),
+                        optional,
+                        requires: requires.cloned(),
+                    };
+                    (self.resolve_path(location.as_ref()), source)
+                };
+            match map {
+                PathMap::Unordered(map) => ret.extend(
+                    map.iter()
+                        .map(|(location, target)| resolve(location, target, false, None)),
+                ),
+                PathMap::Ordered(entries) => ret.extend(entries.iter().map(|entry| {
+                    let requires = entry.requires.as_ref();
+                    resolve(&entry.location, &entry.target, entry.optional, requires)
+                })),
+            }
         };
         paths.common.as_ref().map(&mut extend);
         // Extending a map overrides old values, so host must go after common
@@ -204,28 +631,195 @@ impl Config {
         flattened
     }
 
-    pub fn packages(&self) -> FlattenedSet<&str> {
+    /// Returns the declared packages, with references to named groups of packages expanded.
+    ///
+    /// Fails if a reference names a group that is not declared or if references form a cycle.
+    pub fn packages(&self) -> Result<FlattenedSet<&str>> {
+        let sets: Vec<_> = self.package_sections().map(|(_, set)| set).collect();
+        let mut flattener = SetFlattener::new(&sets);
+        flattener.add_bundles(&self.data.bundles);
+        for set in sets {
+            flattener.flatten(set)?;
+        }
+        Ok(flattener.flattened)
+    }
+
+    /// Returns the sections of declared packages that apply to this machine and the given tags.
+    fn package_sections(&self) -> impl Iterator<Item = (Section<'_>, &NestedSet<String>)> {
+        let tags = self.tags.iter().filter_map(|tag| {
+            let set = self.data.packages.tags.get(tag)?;
+            Some((Section::Tag(tag), set))
+        });
+        self.sections(&self.data.packages).chain(tags)
+    }
+
+    pub fn services(&self) -> FlattenedSet<&str> {
         let mut flattened = FlattenedSet::new();
-        if let Some(ref common) = self.data.packages.common {
-            common.flatten_into(&mut flattened);
+        if let Some(ref common) = self.data.services.common {
+            flattened.extend(common.iter().map(ServiceEntry::name));
         }
-        if let Some(host) = self.data.packages.hosts.get(&self.hostname) {
-            host.flatten_into(&mut flattened);
+        if let Some(host) = self.data.services.hosts.get(&self.hostname) {
+            flattened.extend(host.iter().map(ServiceEntry::name));
         }
         flattened
     }
 
-    pub fn services(&self) -> FlattenedSet<&str> {
+    /// Returns the declared services that should be started when they are enabled.
+    ///
+    /// A service is included if any of its declarations has `start` set to `true`.
+    pub fn services_to_start(&self) -> HashSet<&str> {
+        let common = self.data.services.common.iter().flatten();
+        let host = self.data.services.hosts.get(&self.hostname);
+        common
+            .chain(host.into_iter().flatten())
+            .filter(|entry| entry.start())
+            .map(ServiceEntry::name)
+            .collect()
+    }
+
+    /// Returns the packages that must be installed for each declared service to be enabled.
+    ///
+    /// A service requires every package that any of its declarations requires.
+    pub fn service_requirements(&self) -> HashMap<&str, Vec<&str>> {
+        let common = self.data.services.common.iter().flatten();
+        let host = self.data.services.hosts.get(&self.hostname);
+        let mut requirements: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in common.chain(host.into_iter().flatten()) {
+            if let Some(package) = entry.requires() {
+                requirements.entry(entry.name()).or_default().push(package);
+            }
+        }
+        requirements
+    }
+
+    pub fn masked_services(&self) -> FlattenedSet<&str> {
         let mut flattened = FlattenedSet::new();
-        if let Some(ref common) = self.data.services.common {
+        if let Some(ref common) = self.data.masked_services.common {
             flattened.extend(common.iter().map(AsRef::as_ref));
         }
-        if let Some(host) = self.data.services.hosts.get(&self.hostname) {
+        if let Some(host) = self.data.masked_services.hosts.get(&self.hostname) {
             flattened.extend(host.iter().map(AsRef::as_ref));
         }
         flattened
     }
 
+    /// Returns the hostname of this machine, as used to select host-specific sections.
+    pub fn hostname(&self) -> &OsStr {
+        &self.hostname
+    }
+
+    /// Returns every hostname that has a section in the configuration file, sorted, along with the
+    /// names of the values it has sections in.
+    pub fn hosts(&self) -> Vec<(&OsStr, Vec<&'static str>)> {
+        fn hostnames<T>(value: &PerHostname<OsString, T>) -> HashSet<&OsStr> {
+            value.hosts.keys().map(OsString::as_os_str).collect()
+        }
+        let data = &self.data;
+        let values = [
+            ("copies", hostnames(&data.copies)),
+            ("links", hostnames(&data.links)),
+            ("package_groups", hostnames(&data.package_groups)),
+            ("packages", hostnames(&data.packages)),
+            ("services", hostnames(&data.services)),
+            ("masked_services", hostnames(&data.masked_services)),
+            ("xkb_types", hostnames(&data.xkb_types)),
+        ];
+        let all: BTreeSet<&OsStr> = values
+            .iter()
+            .flat_map(|(_, hosts)| hosts)
+            .copied()
+            .collect();
+        all.into_iter()
+            .map(|host| {
+                let names = values
+                    .iter()
+                    .filter(|(_, hosts)| hosts.contains(host))
+                    .map(|&(name, _)| name)
+                    .collect();
+                (host, names)
+            })
+            .collect()
+    }
+
+    /// Returns every declaration that applies to this machine, along with where it was declared.
+    ///
+    /// Declarations are not merged, so values declared more than once are returned more than
+    /// once, and links and copies overridden by the host section are returned as well.
+    pub fn declarations(&self) -> Result<Vec<Declaration<'_>>> {
+        let mut declarations = Vec::new();
+        let mut push = |kind, value, section, groups| {
+            declarations.push(Declaration {
+                kind,
+                value,
+                section,
+                groups,
+            })
+        };
+
+        let sets: Vec<_> = self.package_sections().map(|(_, set)| set).collect();
+        let mut flattener = SetFlattener::new(&sets);
+        flattener.add_bundles(&self.data.bundles);
+        for (section, set) in self.package_sections() {
+            flattener.flatten(set)?;
+            for (package, groups) in flattener.origins.drain(..) {
+                push("package", package.to_owned(), section, groups);
+            }
+        }
+
+        for (section, groups) in self.sections(&self.data.package_groups) {
+            for group in groups {
+                push("package group", group.clone(), section, Vec::new());
+            }
+        }
+        for (section, services) in self.sections(&self.data.services) {
+            for service in services {
+                push("service", service.name().to_owned(), section, Vec::new());
+            }
+        }
+        for (section, services) in self.sections(&self.data.masked_services) {
+            for service in services {
+                push("masked service", service.clone(), section, Vec::new());
+            }
+        }
+        for (kind, paths) in [("link", &self.data.links), ("copy", &self.data.copies)] {
+            for (section, map) in self.sections(paths) {
+                let entries: Vec<(&String, &String)> = match map {
+                    PathMap::Unordered(map) => map.iter().collect(),
+                    PathMap::Ordered(entries) => entries
+                        .iter()
+                        .map(|entry| (&entry.location, &entry.target))
+                        .collect(),
+                };
+                for (location, target) in entries {
+                    let value = format!(
+                        "{} -> {}",
+                        self.resolve_path(location.as_ref()).display(),
+                        self.resolve_path(target.as_ref()).display(),
+                    );
+                    push(kind, value, section, Vec::new());
+                }
+            }
+        }
+
+        Ok(declarations)
+    }
+
+    /// Returns the sections of `value` that apply to this machine, common first.
+    fn sections<'a, T>(
+        &'a self,
+        value: &'a PerHostname<OsString, T>,
+    ) -> impl Iterator<Item = (Section<'a>, &'a T)> {
+        let common = value
+            .common
+            .as_ref()
+            .map(|common| (Section::Common, common));
+        let host = value
+            .hosts
+            .get(&self.hostname)
+            .map(|host| (Section::Host(&self.hostname), host));
+        common.into_iter().chain(host)
+    }
+
     fn resolve_path(&self, path: &Path) -> PathBuf {
         let mut components = path.components();
         let substituted_tilde = match components.next() {
@@ -246,29 +840,231 @@ impl Config {
     }
 }
 
+/// Returns the canonical path to the directory containing the configuration file at `path`.
+///
+/// If `follow_link` is set and `path` is a symbolic link, this is the directory containing the
+/// file the link points to, otherwise it is the directory containing the link itself. Relative
+/// paths in the configuration file are resolved against this directory, and drop-in files are
+/// read from it.
+fn config_dir(path: &Path, follow_link: bool) -> Result<PathBuf> {
+    let canonicalize = |path: &Path| {
+        path.canonicalize()
+            .map_err(|source| ConfigError::Canonicalize {
+                path: path.to_owned(),
+                source,
+            })
+    };
+    if follow_link {
+        let mut dir = canonicalize(path)?;
+        // a canonical path to a file always has a parent directory
+        dir.pop();
+        Ok(dir)
+    } else {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") => canonicalize(parent),
+            _ => canonicalize(Path::new(".")),
+        }
+    }
+}
+
+/// Returns the format of the configuration file at `path`, unless given with `--config-format`.
+pub fn file_format(explicit: Option<ConfigFormat>, path: &Path) -> ConfigFormat {
+    explicit.unwrap_or_else(|| ConfigFormat::of_path(path))
+}
+
+/// Reads and parses a single configuration file.
+fn read_data(path: &Path, format: ConfigFormat) -> Result<ConfigData<String>> {
+    let contents = read_contents(path)?;
+    let mut data = parse_data(&contents, path, format)?;
+    data.lowercase_hostnames();
+    Ok(data)
+}
+
+/// Reads a single configuration file and checks that it can be parsed, returning its contents.
+pub fn read_valid_file(path: &Path, format: ConfigFormat) -> Result<String> {
+    let contents = read_contents(path)?;
+    parse_data(&contents, path, format)?;
+    Ok(contents)
+}
+
+/// Reads the contents of a single configuration file.
+fn read_contents(path: &Path) -> Result<String> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(ConfigError::NotFound(path.to_owned()))
+        }
+        Err(source) => {
+            return Err(ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    contents_to_string(contents, path)
+}
+
+/// Parses the `contents` of the configuration file at `path`, warning about unknown keys.
+fn parse_data(contents: &str, path: &Path, format: ConfigFormat) -> Result<ConfigData<String>> {
+    let data: ConfigData<String> =
+        format
+            .parse(contents)
+            .map_err(|source| ConfigError::ParseError {
+                path: path.to_owned(),
+                source,
+            })?;
+    if let Some(key) = data.key_with_tags() {
+        return Err(ConfigError::UnsupportedTags {
+            path: path.to_owned(),
+            key,
+        });
+    }
+    warn_about_unknown_keys(contents, path, format);
+    Ok(data)
+}
+
+/// Returns the paths to all `*.toml` files and files in other known formats in the drop-in
+/// directory `dir`, along with their formats, sorted by name.
+///
+/// Returns no paths if the directory does not exist.
+fn drop_in_files(dir: &Path) -> Result<Vec<(PathBuf, ConfigFormat)>> {
+    let map_err = |source| ConfigError::DropInDir {
+        path: dir.to_owned(),
+        source,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(map_err(err)),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(map_err)?.path();
+        if let Some(format) = ConfigFormat::of_drop_in(&path) {
+            if path.is_file() {
+                files.push((path, format));
+            }
+        }
+    }
+    files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+/// Warns about keys in the configuration file that are not part of the schema, and are therefore
+/// ignored.
+fn warn_about_unknown_keys(contents: &str, path: &Path, format: ConfigFormat) {
+    // the contents were already successfully parsed, so this should only fail for values that TOML
+    // cannot represent, like `null`
+    let table: toml::Table = match format.parse(contents) {
+        Ok(table) => table,
+        Err(_) => return,
+    };
+    for key in lint::unknown_keys(&table) {
+        match key.suggestion {
+            Some(suggestion) => warn!(
+                "ignoring unknown key {} in the configuration file {:?}, did you mean {:?}?",
+                key.path, path, suggestion,
+            ),
+            None => warn!(
+                "ignoring unknown key {} in the configuration file {:?}",
+                key.path, path,
+            ),
+        }
+    }
+}
+
+/// Converts the contents of the configuration file to a string.
+///
+/// TOML documents, and therefore all paths declared in them, must be valid UTF-8. The error names
+/// the first line that is not.
+fn contents_to_string(contents: Vec<u8>, path: &Path) -> Result<String> {
+    String::from_utf8(contents).map_err(|err| {
+        let valid = &err.as_bytes()[..err.utf8_error().valid_up_to()];
+        let line = valid.iter().filter(|b| **b == b'\n').count() + 1;
+        ConfigError::InvalidUtf8 {
+            path: path.to_owned(),
+            line,
+        }
+    })
+}
+
 /// Returns the path to the user's home directory.
 ///
 /// If the program was invoked with `sudo`, returns the home directory of the user running the
 /// `sudo` command.
-fn get_home_directory() -> anyhow::Result<PathBuf> {
-    match get_sudo_user() {
-        None => env::var_os("HOME")
-            .map(From::from)
-            .ok_or_else(|| anyhow!("The environment variable HOME is not set")),
-        Some(sudo_user) => {
-            let passwd_path = "/etc/passwd";
-            let passwd_contents = fs::read(passwd_path).with_context(|| {
-                format!("Failed to read the contents of the {:?} file", passwd_path)
-            })?;
-            find_home_in_passwd_file(&sudo_user, &passwd_contents)
-                .map(From::from)
-                .with_context(|| {
-                    format!(
-                        "Failed to determine the home directory of user {:?}",
-                        sudo_user
-                    )
-                })
+fn get_home_directory() -> Result<PathBuf> {
+    resolve_home_directory(
+        get_sudo_user().as_deref(),
+        env::var_os("HOME").as_deref(),
+        get_home_of_user,
+    )
+}
+
+/// Returns the path to the user's home directory, given the values of `SUDO_USER` and `HOME`.
+///
+/// Some configurations of `sudo` preserve `HOME`, so when invoked with `sudo`, `home` is used if
+/// it is an existing directory other than the home directory of root. Otherwise the home directory
+/// of `sudo_user` is found with `lookup`.
+fn resolve_home_directory<F>(
+    sudo_user: Option<&OsStr>,
+    home: Option<&OsStr>,
+    lookup: F,
+) -> Result<PathBuf>
+where
+    F: FnOnce(&OsStr) -> Result<PathBuf>,
+{
+    let home = home.map(PathBuf::from);
+    let sudo_user = match sudo_user {
+        Some(sudo_user) => sudo_user,
+        None => return home.ok_or(ConfigError::HomeNotFound),
+    };
+    match home {
+        Some(home) if home.is_absolute() && home != Path::new(ROOT_HOME) && home.is_dir() => {
+            Ok(home)
         }
+        _ => lookup(sudo_user),
+    }
+}
+
+/// Returns the path to the home directory of the user with the given login name.
+fn get_home_of_user(user: &OsStr) -> Result<PathBuf> {
+    let passwd_contents = fs::read(PASSWD_PATH).map_err(ConfigError::PasswdRead)?;
+    find_home_in_passwd_file(user, &passwd_contents).map(From::from)
+}
+
+/// The directory next to the configuration file that contains additional configuration files.
+const DROP_IN_DIR: &str = "conf.d";
+
+/// The packages that make a reboot advisable when they are upgraded, unless configured otherwise.
+const DEFAULT_REBOOT_PACKAGES: &[&str] = &["linux", "systemd", "glibc"];
+
+/// The file that lists the home directories of users.
+const PASSWD_PATH: &str = "/etc/passwd";
+
+/// The home directory of root, which is never the home directory of the user running `sudo`.
+const ROOT_HOME: &str = "/root";
+
+/// The file read when the hostname reported by the system is not useful.
+const HOSTNAME_PATH: &str = "/etc/hostname";
+
+/// Returns the hostname of the machine.
+///
+/// `hostname` is the value reported by the system. If it is empty or `localhost`, the trimmed
+/// contents of the file at `fallback_path` are used instead, if the file exists and is not empty.
+fn get_hostname(hostname: OsString, fallback_path: &Path) -> OsString {
+    if !hostname.is_empty() && hostname != "localhost" {
+        return hostname;
+    }
+    let contents = match fs::read(fallback_path) {
+        Ok(contents) => contents,
+        Err(_) => return hostname,
+    };
+    let is_space = |b: &u8| b.is_ascii_whitespace();
+    let start = contents.iter().position(|b| !is_space(b));
+    let end = contents.iter().rposition(|b| !is_space(b));
+    match (start, end) {
+        (Some(start), Some(end)) => OsStr::from_bytes(&contents[start..=end]).to_owned(),
+        _ => hostname,
     }
 }
 
@@ -280,22 +1076,182 @@ fn get_sudo_user() -> Option<OsString> {
 
 /// Parses the contents of the passwd file and returns the path to the home directory of the user
 /// with the given login name.
-fn find_home_in_passwd_file<'a>(user: &OsStr, contents: &'a [u8]) -> anyhow::Result<&'a OsStr> {
+fn find_home_in_passwd_file<'a>(user: &OsStr, contents: &'a [u8]) -> Result<&'a OsStr> {
     for line in contents.split(|b| *b == b'\n') {
         let mut parts = line.split(|b| *b == b':');
         let name = match parts.next() {
             Some(name) => OsStr::from_bytes(name),
-            None => bail!("Invalid line in the passwd file: no login name specified"),
+            None => return Err(ConfigError::PasswdParse("no login name specified")),
         };
         if name == user {
             let home = match parts.nth(4) {
                 Some(home) => OsStr::from_bytes(home),
-                None => bail!("Invalid line in the passwd file: no home directory specified"),
+                None => return Err(ConfigError::PasswdParse("no home directory specified")),
             };
             return Ok(home);
         }
     }
-    bail!("Could not find the user {:?} in the passwd file", user);
+    Err(ConfigError::UserNotFound(user.to_owned()))
+}
+
+impl ConfigData<String> {
+    /// Merges the contents of a configuration file read after this one into this one.
+    ///
+    /// Lists of packages, package groups and services are concatenated. Links and copies declared
+    /// for the same location in both files take the value from `other`, as do paths to files.
+    fn merge(&mut self, other: Self) {
+        self.copies.merge(other.copies, PathMap::merge);
+        self.links.merge(other.links, PathMap::merge);
+        self.package_groups.merge(other.package_groups, Vec::extend);
+        self.packages.merge(other.packages, NestedSet::merge);
+        for (name, bundle) in other.bundles {
+            match self.bundles.entry(name) {
+                indexmap::map::Entry::Occupied(mut entry) => entry.get_mut().merge(bundle),
+                indexmap::map::Entry::Vacant(entry) => {
+                    entry.insert(bundle);
+                }
+            }
+        }
+        self.services.merge(other.services, Vec::extend);
+        self.masked_services
+            .merge(other.masked_services, Vec::extend);
+        self.pacman_conf = other.pacman_conf.or(self.pacman_conf.take());
+        self.xkb_types.merge(other.xkb_types, replace);
+        self.xkb_patch = other.xkb_patch.or(self.xkb_patch.take());
+        self.reboot_packages = other.reboot_packages.or(self.reboot_packages.take());
+        self.aur_helper = other.aur_helper.or(self.aur_helper.take());
+        self.defaults.merge(other.defaults);
+    }
+
+    /// Returns the first key other than `packages` with values declared for tags, if there is one.
+    fn key_with_tags(&self) -> Option<&'static str> {
+        [
+            ("copies", self.copies.tags.is_empty()),
+            ("links", self.links.tags.is_empty()),
+            ("package_groups", self.package_groups.tags.is_empty()),
+            ("services", self.services.tags.is_empty()),
+            ("masked_services", self.masked_services.tags.is_empty()),
+            ("xkb_types", self.xkb_types.tags.is_empty()),
+        ]
+        .into_iter()
+        .find(|(_, empty)| !empty)
+        .map(|(key, _)| key)
+    }
+
+    /// Converts the hostnames of all host-specific sections to lowercase.
+    ///
+    /// Sections whose hostnames only differ in case are merged like sections from different
+    /// files, in the order of their original hostnames.
+    fn lowercase_hostnames(&mut self) {
+        self.copies.lowercase_hostnames(PathMap::merge);
+        self.links.lowercase_hostnames(PathMap::merge);
+        self.package_groups.lowercase_hostnames(Vec::extend);
+        self.packages.lowercase_hostnames(NestedSet::merge);
+        self.services.lowercase_hostnames(Vec::extend);
+        self.masked_services.lowercase_hostnames(Vec::extend);
+        self.xkb_types.lowercase_hostnames(replace);
+    }
+}
+
+impl NestedSet<String> {
+    /// Merges `other` into this set, keeping both as elements of an array.
+    fn merge(&mut self, other: Self) {
+        *self = NestedSet::Array(vec![mem::take(self), other]);
+    }
+}
+
+impl Defaults {
+    /// Merges defaults from a configuration file read after this one into this one, values set in
+    /// `other` win.
+    fn merge(&mut self, other: Self) {
+        self.sync.cleanup = other.sync.cleanup.or(self.sync.cleanup);
+        self.service.start = other.service.start.or(self.service.start);
+    }
+}
+
+impl<K: Eq + Hash, T> PerHostname<K, T> {
+    /// Merges `other` into this value, merging values of the same section with `merge_values`.
+    fn merge<F>(&mut self, other: Self, mut merge_values: F)
+    where
+        F: FnMut(&mut T, T),
+    {
+        let mut merge_section = |section: Option<&mut T>, value: T| match section {
+            Some(section) => {
+                merge_values(section, value);
+                None
+            }
+            None => Some(value),
+        };
+        if let Some(common) = other.common {
+            if let Some(common) = merge_section(self.common.as_mut(), common) {
+                self.common = Some(common);
+            }
+        }
+        for (hostname, host) in other.hosts {
+            if let Some(host) = merge_section(self.hosts.get_mut(&hostname), host) {
+                self.hosts.insert(hostname, host);
+            }
+        }
+        for (tag, tagged) in other.tags {
+            if let Some(tagged) = merge_section(self.tags.get_mut(&tag), tagged) {
+                self.tags.insert(tag, tagged);
+            }
+        }
+    }
+}
+
+impl<T> PerHostname<String, T> {
+    /// Converts the hostnames of all sections to lowercase, merging values of sections that end up
+    /// with the same hostname with `merge_values`.
+    fn lowercase_hostnames<F>(&mut self, mut merge_values: F)
+    where
+        F: FnMut(&mut T, T),
+    {
+        let mut hosts: Vec<_> = mem::take(&mut self.hosts).into_iter().collect();
+        hosts.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        for (hostname, host) in hosts {
+            match self.hosts.entry(hostname.to_ascii_lowercase()) {
+                Entry::Occupied(mut entry) => merge_values(entry.get_mut(), host),
+                Entry::Vacant(entry) => {
+                    entry.insert(host);
+                }
+            }
+        }
+    }
+}
+
+impl PathMap {
+    /// Merges `other` into this map, entries of `other` override entries for the same location.
+    ///
+    /// The result is ordered if either of the maps is ordered.
+    fn merge(&mut self, other: Self) {
+        match (&mut *self, other) {
+            (PathMap::Unordered(map), PathMap::Unordered(other)) => map.extend(other),
+            (_, other) => {
+                let mut entries = mem::take(self).into_entries();
+                let other = other.into_entries();
+                entries.retain(|entry| !other.iter().any(|o| o.location == entry.location));
+                entries.extend(other);
+                *self = PathMap::Ordered(entries);
+            }
+        }
+    }
+
+    /// Converts the map into a list of entries, in an unspecified order if it is unordered.
+    fn into_entries(self) -> Vec<PathMapEntry> {
+        match self {
+            PathMap::Unordered(map) => map
+                .into_iter()
+                .map(|(location, target)| PathMapEntry {
+                    location,
+                    target,
+                    optional: false,
+                    requires: None,
+                })
+                .collect(),
+            PathMap::Ordered(entries) => entries,
+        }
+    }
 }
 
 impl<K1, T> PerHostname<K1, T> {
@@ -307,43 +1263,146 @@ impl<K1, T> PerHostname<K1, T> {
         PerHostname {
             common: self.common,
             hosts: self.hosts.into_iter().map(|(k, v)| (f(k), v)).collect(),
+            tags: self.tags,
         }
     }
 }
 
-impl<T> NestedSet<T> {
-    /// Converts a `NestedSet` into a [`FlattenedSet`].
-    fn flatten_into<'a, E>(&'a self, flattened: &mut FlattenedSet<&'a E>)
-    where
-        E: Eq + Hash + ?Sized,
-        T: AsRef<E>,
-    {
-        self.for_each(|element| {
-            if let Some(duplicate) = flattened.elements.replace(element.as_ref()) {
-                flattened.duplicates.insert(duplicate);
-            }
-        });
+impl fmt::Display for Section<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Common => f.write_str("common"),
+            Section::Host(hostname) => write!(f, "hosts.{:?}", hostname.to_string_lossy()),
+            Section::Tag(tag) => write!(f, "tags.{:?}", tag),
+        }
+    }
+}
+
+impl ServiceEntry {
+    fn name(&self) -> &str {
+        match self {
+            ServiceEntry::Name(name) => name,
+            ServiceEntry::Detailed { name, .. } => name,
+        }
+    }
+
+    fn start(&self) -> bool {
+        match self {
+            ServiceEntry::Name(_) => false,
+            ServiceEntry::Detailed { start, .. } => *start,
+        }
+    }
+
+    fn requires(&self) -> Option<&str> {
+        match self {
+            ServiceEntry::Name(_) => None,
+            ServiceEntry::Detailed { requires, .. } => requires.as_deref(),
+        }
     }
+}
 
-    /// Applies a function to each element in the set, passing each element by value.
-    fn for_each<'a, F: FnMut(&'a T)>(&'a self, mut f: F) {
-        fn do_for_each<'a, T, F: FnMut(&'a T)>(this: &'a NestedSet<T>, f: &mut F) {
-            match this {
-                NestedSet::Singleton(x) => f(x),
-                NestedSet::Map(map) => {
-                    for subset in map.values() {
-                        do_for_each(subset, f);
+impl<'a, T: AsRef<str>> SetFlattener<'a, T> {
+    /// Creates a flattener that resolves references to the named groups in `sets`.
+    fn new(sets: &[&'a NestedSet<T>]) -> Self {
+        let mut groups = HashMap::new();
+        for set in sets {
+            set.collect_groups(&mut groups);
+        }
+        Self {
+            groups,
+            declared: HashSet::new(),
+            path: Vec::new(),
+            flattened: FlattenedSet::new(),
+            origins: Vec::new(),
+        }
+    }
+
+    /// Makes the named `bundles` available to references, without adding their elements to the
+    /// flattened set.
+    fn add_bundles(&mut self, bundles: &'a IndexMap<String, NestedSet<T>>) {
+        for (name, bundle) in bundles {
+            self.groups.entry(name.as_str()).or_default().push(bundle);
+            bundle.collect_groups(&mut self.groups);
+        }
+    }
+
+    /// Adds the elements of `set` to the flattened set.
+    ///
+    /// Elements of the form `@name` are references to the groups called `name`, they are replaced
+    /// with the elements of these groups, expanded transitively. Elements reached only through a
+    /// reference are not reported as duplicates.
+    fn flatten(&mut self, set: &'a NestedSet<T>) -> Result<()> {
+        self.do_flatten(set, false)
+    }
+
+    fn do_flatten(&mut self, set: &'a NestedSet<T>, referenced: bool) -> Result<()> {
+        match set {
+            NestedSet::Singleton(element) => match element.as_ref().strip_prefix('@') {
+                Some(name) => self.expand_reference(name)?,
+                None => {
+                    let element = element.as_ref();
+                    self.flattened.elements.insert(element);
+                    self.origins.push((element, self.path.clone()));
+                    if !referenced && !self.declared.insert(element) {
+                        self.flattened.duplicates.insert(element);
                     }
                 }
-                NestedSet::Array(array) => {
-                    for subset in array {
-                        do_for_each(subset, f);
-                    }
+            },
+            NestedSet::Map(map) => {
+                for (name, subset) in map {
+                    self.path.push(name);
+                    self.do_flatten(subset, referenced)?;
+                    self.path.pop();
                 }
             }
+            NestedSet::Array(array) => {
+                for subset in array {
+                    self.do_flatten(subset, referenced)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_reference(&mut self, name: &'a str) -> Result<()> {
+        if self.path.contains(&name) {
+            self.path.push(name);
+            return Err(ConfigError::GroupCycle {
+                name: name.to_owned(),
+                cycle: self.path.join(" -> "),
+            });
+        }
+        let subsets = self
+            .groups
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ConfigError::UndeclaredGroup(name.to_owned()))?;
+        self.path.push(name);
+        for subset in subsets {
+            self.do_flatten(subset, true)?;
         }
+        self.path.pop();
+        Ok(())
+    }
+}
 
-        do_for_each(self, &mut f)
+impl<T> NestedSet<T> {
+    /// Collects all named groups in the set, including nested ones, into `groups`.
+    fn collect_groups<'a>(&'a self, groups: &mut HashMap<&'a str, Vec<&'a NestedSet<T>>>) {
+        match self {
+            NestedSet::Singleton(_) => {}
+            NestedSet::Map(map) => {
+                for (name, subset) in map {
+                    groups.entry(name.as_str()).or_default().push(subset);
+                    subset.collect_groups(groups);
+                }
+            }
+            NestedSet::Array(array) => {
+                for subset in array {
+                    subset.collect_groups(groups);
+                }
+            }
+        }
     }
 }
 
@@ -366,15 +1425,64 @@ impl<T: Eq + Hash> Extend<T> for FlattenedSet<T> {
     }
 }
 
+// `PartialEq` can't be derived for these, the derived impls would not require hostnames to be
+// `Eq + Hash`, as is necessary to compare `HashMap`s
+impl<H: Eq + Hash> PartialEq for ConfigData<H> {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            copies,
+            links,
+            package_groups,
+            packages,
+            bundles,
+            services,
+            masked_services,
+            pacman_conf,
+            xkb_types,
+            xkb_patch,
+            reboot_packages,
+            aur_helper,
+            defaults,
+        } = self;
+        *copies == other.copies
+            && *links == other.links
+            && *package_groups == other.package_groups
+            && *packages == other.packages
+            && *bundles == other.bundles
+            && *services == other.services
+            && *masked_services == other.masked_services
+            && *pacman_conf == other.pacman_conf
+            && *xkb_types == other.xkb_types
+            && *xkb_patch == other.xkb_patch
+            && *reboot_packages == other.reboot_packages
+            && *aur_helper == other.aur_helper
+            && *defaults == other.defaults
+    }
+}
+
+impl<K: Eq + Hash, T: PartialEq> PartialEq for PerHostname<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.common == other.common && self.hosts == other.hosts && self.tags == other.tags
+    }
+}
+
 impl<K, T> Default for PerHostname<K, T> {
     fn default() -> Self {
         Self {
             common: Default::default(),
             hosts: Default::default(),
+            tags: Default::default(),
         }
     }
 }
 
+impl Default for PathMap {
+    /// Returns an empty `Unordered` map.
+    fn default() -> Self {
+        Self::Unordered(Default::default())
+    }
+}
+
 impl<T> Default for NestedSet<T> {
     /// Returns an empty `Array` of _things_.
     fn default() -> Self {
@@ -410,6 +1518,410 @@ mod tests {
             assert_eq!(home, find_home_in_passwd_file(name, contents).unwrap());
         }
 
-        find_home_in_passwd_file("user0".as_ref(), contents).unwrap_err();
+        let err = find_home_in_passwd_file("user0".as_ref(), contents).unwrap_err();
+        assert!(matches!(err, ConfigError::UserNotFound(user) if user == "user0"));
+    }
+
+    #[test]
+    fn home_directory_with_and_without_sudo() {
+        let existing = env::temp_dir();
+        let existing = existing.as_os_str();
+        let lookup = |user: &OsStr| -> Result<PathBuf> {
+            match user.to_str() {
+                Some("user1") => Ok(PathBuf::from("/home/user1")),
+                _ => Err(ConfigError::UserNotFound(user.to_owned())),
+            }
+        };
+        let resolve = |sudo_user: Option<&str>, home: Option<&OsStr>| {
+            resolve_home_directory(sudo_user.map(OsStr::new), home, lookup)
+        };
+
+        // Without sudo, HOME is used as is
+        assert_eq!(
+            resolve(None, Some("/nonexistent".as_ref())).unwrap(),
+            Path::new("/nonexistent")
+        );
+        assert!(matches!(
+            resolve(None, None),
+            Err(ConfigError::HomeNotFound)
+        ));
+        // With sudo, a preserved HOME is preferred over passwd
+        assert_eq!(
+            resolve(Some("user0"), Some(existing)).unwrap(),
+            Path::new(existing)
+        );
+        // With sudo, HOME of root, a missing directory or no HOME at all fall back to passwd
+        for home in [Some("/root"), Some("/nonexistent"), Some("relative"), None] {
+            let home = home.map(OsStr::new);
+            assert_eq!(
+                resolve(Some("user1"), home).unwrap(),
+                Path::new("/home/user1")
+            );
+        }
+        let err = resolve(Some("user0"), Some("/root".as_ref())).unwrap_err();
+        assert!(matches!(err, ConfigError::UserNotFound(user) if user == "user0"));
+    }
+
+    #[test]
+    fn mixed_case_hostnames() {
+        let contents = concat!(
+            "[packages.hosts]\n",
+            "MyLaptop = [\"vim\"]\n",
+            "mylaptop = [\"zsh\"]\n",
+            "Desktop = [\"steam\"]\n",
+            "[services.hosts]\n",
+            "MYLAPTOP = [\"sshd\"]\n",
+        );
+        let mut data: ConfigData<String> = toml::from_str(contents).unwrap();
+        data.lowercase_hostnames();
+
+        let mut hostnames: Vec<_> = data.packages.hosts.keys().map(String::as_str).collect();
+        hostnames.sort_unstable();
+        assert_eq!(hostnames, ["desktop", "mylaptop"]);
+        let mut flattener = SetFlattener::new(&[]);
+        flattener.flatten(&data.packages.hosts["mylaptop"]).unwrap();
+        let packages: Vec<_> = flattener.flattened.elements.into_iter().collect();
+        assert_eq!(packages, ["vim", "zsh"]);
+        let services: Vec<_> = data.services.hosts["mylaptop"]
+            .iter()
+            .map(ServiceEntry::name)
+            .collect();
+        assert_eq!(services, ["sshd"]);
+    }
+
+    #[test]
+    fn per_hostname_xkb_types() {
+        let main = "xkb_types = \"types/common\"\n";
+        let drop_in = concat!(
+            "[xkb_types.hosts]\n",
+            "Laptop = \"types/laptop\"\n",
+            "desktop = \"types/desktop\"\n",
+        );
+        let mut data: ConfigData<String> = toml::from_str(main).unwrap();
+        assert_eq!(data.xkb_types.common.as_deref(), Some("types/common"));
+        assert!(data.xkb_types.hosts.is_empty());
+
+        data.merge(toml::from_str(drop_in).unwrap());
+        data.merge(toml::from_str("xkb_types.hosts.laptop = \"types/other\"\n").unwrap());
+        data.lowercase_hostnames();
+        assert_eq!(data.xkb_types.common.as_deref(), Some("types/common"));
+        assert_eq!(data.xkb_types.hosts["desktop"], "types/desktop");
+        assert_eq!(data.xkb_types.hosts["laptop"], "types/other");
+        assert_eq!(data.xkb_patch, None);
+
+        data.merge(toml::from_str("xkb_patch = false\n").unwrap());
+        assert_eq!(data.xkb_patch, Some(false));
+        assert_eq!(data.xkb_types.common.as_deref(), Some("types/common"));
+
+        assert!(toml::from_str::<ConfigData<String>>("xkb_types = 1\n").is_err());
+    }
+
+    #[test]
+    fn same_config_in_every_format() {
+        let testdata = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata"));
+        let expected = read_data(&testdata.join("config.toml"), ConfigFormat::Toml).unwrap();
+        assert_eq!(expected.packages.tags.len(), 1);
+
+        for name in ["config.yaml", "config.json"] {
+            let path = testdata.join(name);
+            let format = ConfigFormat::of_path(&path);
+            match read_data(&path, format) {
+                Ok(data) => assert_eq!(data, expected, "{}", name),
+                Err(ConfigError::ParseError {
+                    source: FormatError::Unsupported(_),
+                    ..
+                }) => assert!(!format.is_supported(), "{}", name),
+                Err(err) => panic!("failed to read {}: {:?}", name, err),
+            }
+        }
+    }
+
+    #[test]
+    fn config_dir_of_linked_file() {
+        let scratch = env::temp_dir().join("archman-test-config-dir");
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(scratch.join("dotfiles")).unwrap();
+        fs::create_dir_all(scratch.join("config")).unwrap();
+        let scratch = scratch.canonicalize().unwrap();
+        fs::write(scratch.join("dotfiles/archman.toml"), "").unwrap();
+        let link = scratch.join("config/archman.toml");
+        std::os::unix::fs::symlink("../dotfiles/archman.toml", &link).unwrap();
+
+        assert_eq!(config_dir(&link, true).unwrap(), scratch.join("dotfiles"));
+        assert_eq!(config_dir(&link, false).unwrap(), scratch.join("config"));
+        assert!(config_dir(&scratch.join("missing/archman.toml"), false).is_err());
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn hostname_fallback() {
+        let scratch = env::temp_dir().join("archman-test-hostname");
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(&scratch).unwrap();
+        let fixture = scratch.join("hostname");
+        fs::write(&fixture, "  archbox\n\n").unwrap();
+        let missing = scratch.join("missing");
+
+        assert_eq!(get_hostname("myhost".into(), &fixture), "myhost");
+        assert_eq!(get_hostname("localhost".into(), &fixture), "archbox");
+        assert_eq!(get_hostname("".into(), &fixture), "archbox");
+        assert_eq!(get_hostname("localhost".into(), &missing), "localhost");
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn tags_only_for_packages() {
+        let path = Path::new("archman.toml");
+        let contents = "[packages.tags]\nwork = [\"slack\"]\n";
+        parse_data(contents, path, ConfigFormat::Toml).unwrap();
+
+        let contents = "[services.tags]\nwork = [\"vpn.service\"]\n";
+        let err = parse_data(contents, path, ConfigFormat::Toml).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::UnsupportedTags {
+                key: "services",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn non_utf8_contents() {
+        let path = Path::new("archman.toml");
+        let contents = b"[links.common]\n\"~/a\" = \"a\"\n\"~/\xff\" = \"b\"\n".to_vec();
+        let err = contents_to_string(contents, path).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidUtf8 { line: 3, .. }));
+
+        let contents = b"[links.common]\n\"~/a\" = \"a\"\n".to_vec();
+        contents_to_string(contents, path).unwrap();
+    }
+
+    #[test]
+    fn optional_path_map_entries() {
+        let contents = concat!(
+            "[[common]]\nlocation = \"~/a\"\ntarget = \"a\"\n",
+            "[[common]]\nlocation = \"~/b\"\noriginal = \"b\"\noptional = true\n",
+        );
+        let paths: PerHostname<String, PathMap> = toml::from_str(contents).unwrap();
+        let entries = match paths.common {
+            Some(PathMap::Ordered(entries)) => entries,
+            other => panic!("expected ordered entries, got {:?}", other),
+        };
+        assert!(!entries[0].optional);
+        assert!(entries[1].optional);
+    }
+
+    #[test]
+    fn flatten_in_declaration_order() {
+        let contents = concat!(
+            "zsh = \"zsh\"\n",
+            "base = [\"linux\", \"base\", { firmware = \"linux-firmware\" }]\n",
+            "editors = [\"vim\", [\"emacs\", \"vim\"]]\n",
+            "shells = \"zsh\"\n",
+        );
+        let set: NestedSet<String> = toml::from_str(contents).unwrap();
+
+        let mut flattener = SetFlattener::new(&[]);
+        flattener.flatten(&set).unwrap();
+        let flattened = flattener.flattened;
+
+        let elements: Vec<_> = flattened.elements.into_iter().collect();
+        let duplicates: Vec<_> = flattened.duplicates.into_iter().collect();
+        assert_eq!(
+            elements,
+            ["zsh", "linux", "base", "linux-firmware", "vim", "emacs"]
+        );
+        assert_eq!(duplicates, ["vim", "zsh"]);
+    }
+
+    #[test]
+    fn flatten_group_references() {
+        let contents = concat!(
+            "desktop = [\"@fonts\", \"@audio\", \"sway\"]\n",
+            "fonts = [\"noto-fonts\", { emoji = \"noto-fonts-emoji\" }]\n",
+            "audio = [\"pipewire\", \"@emoji\"]\n",
+        );
+        let set: NestedSet<String> = toml::from_str(contents).unwrap();
+        let mut flattener = SetFlattener::new(&[&set]);
+        flattener.flatten(&set).unwrap();
+        let flattened = flattener.flattened;
+
+        let elements: Vec<_> = flattened.elements.into_iter().collect();
+        assert_eq!(
+            elements,
+            ["noto-fonts", "noto-fonts-emoji", "pipewire", "sway"]
+        );
+        assert!(flattened.duplicates.is_empty());
+    }
+
+    #[test]
+    fn flatten_invalid_group_references() {
+        for contents in [
+            "a = [\"@b\"]\nb = [\"@a\"]\n",
+            "a = [\"x\", \"@a\"]\n",
+            "a = [\"@missing\"]\n",
+        ] {
+            let set: NestedSet<String> = toml::from_str(contents).unwrap();
+            SetFlattener::new(&[&set]).flatten(&set).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn entries_with_requirements() {
+        let contents = concat!(
+            "[[links.common]]\n",
+            "location = \"~/.config/alacritty/alacritty.toml\"\n",
+            "target = \"alacritty.toml\"\n",
+            "requires = \"alacritty\"\n",
+            "[services]\n",
+            "common = [\"sshd\", { name = \"docker\", requires = \"docker\" }]\n",
+        );
+        let data: ConfigData<String> = toml::from_str(contents).unwrap();
+        let entries = match data.links.common {
+            Some(PathMap::Ordered(entries)) => entries,
+            other => panic!("expected ordered entries, got {:?}", other),
+        };
+        assert_eq!(entries[0].requires.as_deref(), Some("alacritty"));
+        let requirements: Vec<_> = data
+            .services
+            .common
+            .iter()
+            .flatten()
+            .map(|entry| (entry.name(), entry.requires()))
+            .collect();
+        assert_eq!(requirements, [("sshd", None), ("docker", Some("docker"))]);
+    }
+
+    #[test]
+    fn flatten_bundle_references() {
+        let contents = concat!(
+            "[bundles]\n",
+            "desktop = [\"sway\", \"@fonts\"]\n",
+            "fonts = [\"noto-fonts\"]\n",
+            "unused = [\"steam\"]\n",
+            "[packages]\n",
+            "common = [\"base\", { fonts = [\"ttf-dejavu\"] }]\n",
+            "hosts = { laptop = [\"@desktop\"] }\n",
+        );
+        let data: ConfigData<String> = toml::from_str(contents).unwrap();
+        let common = data.packages.common.as_ref().unwrap();
+        let host = &data.packages.hosts["laptop"];
+        let mut flattener = SetFlattener::new(&[common, host]);
+        flattener.add_bundles(&data.bundles);
+        flattener.flatten(common).unwrap();
+        flattener.flatten(host).unwrap();
+        let packages: Vec<_> = flattener.flattened.elements.into_iter().collect();
+        assert_eq!(packages, ["base", "ttf-dejavu", "sway", "noto-fonts"]);
+
+        let cycle = "[bundles]\na = [\"@b\"]\nb = [\"@a\"]\n[packages]\ncommon = [\"@a\"]\n";
+        let data: ConfigData<String> = toml::from_str(cycle).unwrap();
+        let common = data.packages.common.as_ref().unwrap();
+        let mut flattener = SetFlattener::new(&[common]);
+        flattener.add_bundles(&data.bundles);
+        let err = flattener.flatten(common).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Package group \"a\" references itself: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn flatten_records_origins() {
+        let contents = concat!(
+            "desktop = [\"@fonts\", \"sway\"]\n",
+            "fonts = [\"noto-fonts\"]\n",
+            "base = \"base\"\n",
+        );
+        let set: NestedSet<String> = toml::from_str(contents).unwrap();
+        let mut flattener = SetFlattener::new(&[&set]);
+        flattener.flatten(&set).unwrap();
+
+        let origins: Vec<_> = flattener
+            .origins
+            .iter()
+            .map(|(element, groups)| (*element, groups.join(".")))
+            .collect();
+        assert_eq!(
+            origins,
+            [
+                ("noto-fonts", "desktop.fonts".to_owned()),
+                ("sway", "desktop".to_owned()),
+                ("noto-fonts", "fonts".to_owned()),
+                ("base", "base".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_drop_ins() {
+        let main = concat!(
+            "pacman_conf = \"/etc/pacman.conf\"\n",
+            "[defaults.sync]\n",
+            "cleanup = true\n",
+            "[packages]\n",
+            "common = [\"base\"]\n",
+            "[links.common]\n",
+            "\"~/a\" = \"a\"\n",
+            "\"~/b\" = \"b\"\n",
+            "[services]\n",
+            "common = [\"sshd\"]\n",
+        );
+        let drop_in = concat!(
+            "xkb_types = \"/usr/share/X11/xkb/types/complete\"\n",
+            "reboot_packages = [\"linux-lts\"]\n",
+            "aur_helper = \"paru\"\n",
+            "[defaults.service]\n",
+            "start = true\n",
+            "[packages]\n",
+            "common = { editors = [\"vim\"] }\n",
+            "hosts = { archbox = [\"@editors\"] }\n",
+            "[[links.common]]\n",
+            "location = \"~/b\"\n",
+            "target = \"c\"\n",
+            "[services]\n",
+            "common = [\"docker\"]\n",
+        );
+        let mut data: ConfigData<String> = toml::from_str(main).unwrap();
+        data.merge(toml::from_str(drop_in).unwrap());
+
+        assert_eq!(data.pacman_conf.as_deref(), Some("/etc/pacman.conf"));
+        assert_eq!(
+            data.xkb_types.common.as_deref(),
+            Some("/usr/share/X11/xkb/types/complete")
+        );
+        assert_eq!(data.reboot_packages, Some(vec!["linux-lts".to_owned()]));
+        assert_eq!(data.aur_helper.as_deref(), Some("paru"));
+        assert_eq!(data.defaults.sync.cleanup, Some(true));
+        assert_eq!(data.defaults.service.start, Some(true));
+        let services: Vec<_> = data
+            .services
+            .common
+            .iter()
+            .flatten()
+            .map(ServiceEntry::name)
+            .collect();
+        assert_eq!(services, ["sshd", "docker"]);
+
+        let common = data.packages.common.as_ref().unwrap();
+        let host = &data.packages.hosts["archbox"];
+        let mut flattener = SetFlattener::new(&[common, host]);
+        flattener.flatten(common).unwrap();
+        flattener.flatten(host).unwrap();
+        let packages: Vec<_> = flattener.flattened.elements.into_iter().collect();
+        assert_eq!(packages, ["base", "vim"]);
+
+        let links = match data.links.common {
+            Some(PathMap::Ordered(entries)) => entries,
+            other => panic!("expected ordered entries, got {:?}", other),
+        };
+        let mut links: Vec<_> = links
+            .iter()
+            .map(|entry| (entry.location.as_str(), entry.target.as_str()))
+            .collect();
+        links.sort_unstable();
+        assert_eq!(links, [("~/a", "a"), ("~/b", "c")]);
     }
 }
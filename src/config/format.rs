@@ -0,0 +1,115 @@
+//! Parsing configuration files written in different formats.
+//!
+//! The configuration is deserialized with serde, so every format that has a serde deserializer
+//! produces the same values. TOML is always supported, other formats are enabled with the cargo
+//! features of the same name.
+
+use std::{fmt, path::Path};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::args::ConfigFormat;
+
+/// An error from parsing a configuration file in one of the supported formats.
+#[derive(Debug, Error)]
+pub enum FormatError {
+    /// The file is not a valid TOML document or does not match the expected schema.
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    /// The file is not a valid YAML document or does not match the expected schema.
+    #[cfg(feature = "yaml")]
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    /// The file is not a valid JSON document or does not match the expected schema.
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Support for the format was not enabled at build time.
+    #[error("support for {0} configuration files was not enabled when archman was built")]
+    Unsupported(ConfigFormat),
+}
+
+impl ConfigFormat {
+    /// Returns the format of the file at `path`, based on its extension.
+    ///
+    /// Files with extensions other than `.yaml`, `.yml` and `.json` are assumed to be TOML.
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    /// Returns the format of a drop-in file at `path`, if it has the extension of a known format.
+    ///
+    /// Unlike [`ConfigFormat::of_path`], this does not fall back to TOML, so that unrelated files
+    /// in the drop-in directory are skipped. Formats not enabled at build time are still returned,
+    /// so that reading such a file fails like reading the main configuration file does, instead of
+    /// its declarations silently disappearing.
+    pub fn of_drop_in(path: &Path) -> Option<Self> {
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    /// Returns `true` if support for this format was enabled at build time.
+    pub fn is_supported(self) -> bool {
+        match self {
+            ConfigFormat::Toml => true,
+            ConfigFormat::Yaml => cfg!(feature = "yaml"),
+            ConfigFormat::Json => cfg!(feature = "json"),
+        }
+    }
+
+    /// Deserializes a value of type `T` from `contents`, written in this format.
+    pub fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T, FormatError> {
+        match self {
+            ConfigFormat::Toml => Ok(toml::from_str(contents)?),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => Ok(serde_yaml::from_str(contents)?),
+            #[cfg(feature = "json")]
+            ConfigFormat::Json => Ok(serde_json::from_str(contents)?),
+            #[allow(unreachable_patterns)]
+            format => Err(FormatError::Unsupported(format)),
+        }
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Json => "JSON",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format_from_extension() {
+        let of_path = |path: &str| ConfigFormat::of_path(Path::new(path));
+        assert_eq!(of_path("archman.toml"), ConfigFormat::Toml);
+        assert_eq!(of_path("archman.yaml"), ConfigFormat::Yaml);
+        assert_eq!(of_path("archman.yml"), ConfigFormat::Yaml);
+        assert_eq!(of_path("archman.json"), ConfigFormat::Json);
+        assert_eq!(of_path("archman.conf"), ConfigFormat::Toml);
+        assert_eq!(of_path("archman"), ConfigFormat::Toml);
+
+        let of_drop_in = |path: &str| ConfigFormat::of_drop_in(Path::new(path));
+        assert_eq!(of_drop_in("10-laptop.toml"), Some(ConfigFormat::Toml));
+        assert_eq!(of_drop_in("20-desktop.yaml"), Some(ConfigFormat::Yaml));
+        assert_eq!(of_drop_in("30-server.json"), Some(ConfigFormat::Json));
+        assert_eq!(of_drop_in("README.md"), None);
+        assert_eq!(of_drop_in("10-laptop"), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Finding keys in the configuration file that we do not recognize.
+//!
+//! Deserialization silently ignores unknown keys, so a typo in the name of a section would make the
+//! whole section disappear. Instead of rejecting such files, we look for unknown keys separately
+//! and warn about them.
+
+use toml::{Table, Value};
+
+/// The keys allowed at the top level of the configuration file.
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "copies",
+    "links",
+    "package_groups",
+    "packages",
+    "bundles",
+    "services",
+    "masked_services",
+    "pacman_conf",
+    "xkb_types",
+    "xkb_patch",
+    "reboot_packages",
+    "aur_helper",
+    "defaults",
+];
+
+/// The keys allowed in a value that can have different definitions depending on the hostname.
+const PER_HOSTNAME_KEYS: &[&str] = &["common", "hosts"];
+
+/// The keys allowed in the declared packages, which can also have definitions for tags.
+const PACKAGES_KEYS: &[&str] = &["common", "hosts", "tags"];
+
+/// The subcommands that can have defaults for their flags, and the flags for each of them.
+const DEFAULTS_KEYS: &[(&str, &[&str])] = &[("sync", &["cleanup"]), ("service", &["start"])];
+
+/// The keys allowed in an entry of an ordered map of links or copies.
+const PATH_MAP_ENTRY_KEYS: &[&str] = &["location", "target", "original", "optional", "requires"];
+
+/// The keys allowed in a detailed declaration of a service.
+const SERVICE_ENTRY_KEYS: &[&str] = &["name", "start", "requires"];
+
+/// A key that is not part of the configuration file schema.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownKey {
+    /// The path to the key, e.g. `packages.comon`.
+    pub path: String,
+    /// A known key with a similar name, if there is one.
+    pub suggestion: Option<&'static str>,
+}
+
+/// Returns all keys in `table` that are not part of the configuration file schema.
+pub fn unknown_keys(table: &Table) -> Vec<UnknownKey> {
+    let mut unknown = Vec::new();
+    for (key, value) in table {
+        if !check_key(key, key, TOP_LEVEL_KEYS, &mut unknown) {
+            continue;
+        }
+        let per_hostname = match value.as_table() {
+            Some(per_hostname) => per_hostname,
+            None => continue,
+        };
+        if key == "defaults" {
+            check_defaults(per_hostname, &mut unknown);
+            continue;
+        }
+        if key == "bundles" {
+            // bundles can have any names
+            continue;
+        }
+        let known = if key == "packages" {
+            PACKAGES_KEYS
+        } else {
+            PER_HOSTNAME_KEYS
+        };
+        for (section_key, section) in per_hostname {
+            let path = format!("{}.{}", key, section_key);
+            if !check_key(section_key, &path, known, &mut unknown) {
+                continue;
+            }
+            if section_key == "common" {
+                check_section(key, &path, section, &mut unknown);
+            } else if let Some(hosts) = section.as_table() {
+                for (hostname, section) in hosts {
+                    let path = format!("{}.{:?}", path, hostname);
+                    check_section(key, &path, section, &mut unknown);
+                }
+            }
+        }
+    }
+    unknown
+}
+
+/// Checks the keys of the `defaults` table, which has a table of flags for every subcommand.
+fn check_defaults(defaults: &Table, unknown: &mut Vec<UnknownKey>) {
+    let subcommands: Vec<_> = DEFAULTS_KEYS.iter().map(|&(key, _)| key).collect();
+    for (subcommand, flags) in defaults {
+        let path = format!("defaults.{}", subcommand);
+        if !check_key(subcommand, &path, &subcommands, unknown) {
+            continue;
+        }
+        let known = DEFAULTS_KEYS
+            .iter()
+            .find(|&&(key, _)| key == subcommand)
+            .map_or(&[][..], |&(_, flags)| flags);
+        for flag in flags.as_table().into_iter().flat_map(Table::keys) {
+            check_key(flag, &format!("{}.{}", path, flag), known, unknown);
+        }
+    }
+}
+
+/// Checks the keys of entries in a single section of the top-level value called `kind`.
+fn check_section(kind: &str, path: &str, section: &Value, unknown: &mut Vec<UnknownKey>) {
+    let known = match kind {
+        "copies" | "links" => PATH_MAP_ENTRY_KEYS,
+        "services" => SERVICE_ENTRY_KEYS,
+        _ => return,
+    };
+    let entries = match section.as_array() {
+        Some(entries) => entries,
+        None => return,
+    };
+    for (index, entry) in entries.iter().enumerate() {
+        for key in entry.as_table().into_iter().flat_map(Table::keys) {
+            let path = format!("{}[{}].{}", path, index, key);
+            check_key(key, &path, known, unknown);
+        }
+    }
+}
+
+/// Returns `true` if `key` is one of the `known` keys, otherwise records it as unknown.
+fn check_key(key: &str, path: &str, known: &[&'static str], unknown: &mut Vec<UnknownKey>) -> bool {
+    if known.contains(&key) {
+        return true;
+    }
+    unknown.push(UnknownKey {
+        path: path.to_owned(),
+        suggestion: closest_key(key, known),
+    });
+    false
+}
+
+/// Returns the known key closest to `key`, if it is close enough to be a likely typo.
+fn closest_key(key: &str, known: &[&'static str]) -> Option<&'static str> {
+    known
+        .iter()
+        .map(|&candidate| (edit_distance(key, candidate), candidate))
+        .filter(|&(distance, _)| distance <= 2)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Returns the Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a_char) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_unknown_keys() {
+        let contents = concat!(
+            "pacman_conf = \"/etc/pacman.conf\"\n",
+            "[packges.common]\n",
+            "base = [\"base\"]\n",
+            "[links.comon]\n",
+            "\"~/a\" = \"a\"\n",
+            "[[links.hosts.archbox]]\n",
+            "location = \"~/b\"\n",
+            "target = \"b\"\n",
+            "optinal = true\n",
+            "[services]\n",
+            "common = [\"sshd\", { name = \"docker\", strat = true }]\n",
+            "[packages.common]\n",
+            "unknown-looking-group = [\"vim\"]\n",
+            "[packages.tags]\n",
+            "gaming = [\"steam\"]\n",
+            "[links.tags]\n",
+            "[defaults.sync]\n",
+            "cleanup = true\n",
+            "[defaults.service]\n",
+            "strat = true\n",
+            "[defaults.show]\n",
+        );
+        let table: Table = toml::from_str(contents).unwrap();
+        let paths: Vec<_> = unknown_keys(&table)
+            .into_iter()
+            .map(|key| (key.path, key.suggestion))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                ("defaults.service.strat".to_owned(), Some("start")),
+                ("defaults.show".to_owned(), None),
+                ("links.comon".to_owned(), Some("common")),
+                (
+                    "links.hosts.\"archbox\"[0].optinal".to_owned(),
+                    Some("optional")
+                ),
+                ("links.tags".to_owned(), None),
+                ("packges".to_owned(), Some("packages")),
+                ("services.common[1].strat".to_owned(), Some("start")),
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("links", "links"), 0);
+        assert_eq!(edit_distance("packges", "packages"), 1);
+        assert_eq!(edit_distance("strat", "start"), 2);
+        assert_eq!(edit_distance("hosts", ""), 5);
+    }
+}
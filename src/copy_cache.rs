@@ -0,0 +1,252 @@
+//! Remembering which copies have the contents of their original files.
+//!
+//! Comparing a copy with its original means reading both files. The cache records, for every copy,
+//! the metadata of both files at the time they were last known to have the same contents, along
+//! with a hash of those contents. If neither file changed since, they are not read again. If only
+//! the metadata of the original changed, e.g. because it was touched, only the original is read and
+//! its hash is compared with the recorded one.
+//!
+//! A missing or stale entry only means that the files are compared in full.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, Metadata},
+    io::{self, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The version of the cache file format, bumped on every incompatible change.
+const CACHE_VERSION: u32 = 1;
+
+/// The copies known to have the contents of their original files.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CopyCache {
+    /// The version of the cache file format.
+    version: u32,
+    /// The entries, by the location of the copy.
+    ///
+    /// Copies and original files with paths that are not valid UTF-8 are never recorded.
+    copies: BTreeMap<String, Entry>,
+}
+
+/// A copy that had the contents of its original file.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    /// The path to the original file.
+    original: String,
+    /// The hash of the contents of both files, see [`hash`].
+    hash: String,
+    /// The metadata of the original file.
+    original_stamp: Stamp,
+    /// The metadata of the copy.
+    copy_stamp: Stamp,
+}
+
+/// The metadata of a file that changes whenever its contents change.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Stamp {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    /// Unlike the modification time, this cannot be set to an arbitrary value.
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+impl CopyCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self {
+            version: CACHE_VERSION,
+            copies: BTreeMap::new(),
+        }
+    }
+
+    /// Returns `true` if the file at `copy` is known to have the contents of `original`.
+    ///
+    /// Only the original is read, and only if its metadata changed since it was recorded. If its
+    /// contents did not change, the entry is updated.
+    pub fn is_up_to_date(&mut self, copy: &Path, original: &Path) -> bool {
+        let entry = match copy.to_str().and_then(|copy| self.copies.get_mut(copy)) {
+            Some(entry) if Path::new(&entry.original) == original => entry,
+            Some(_) | None => return false,
+        };
+        match stamp(copy) {
+            Ok(copy_stamp) if copy_stamp == entry.copy_stamp => {}
+            Ok(_) | Err(_) => return false,
+        }
+        let original_stamp = match stamp(original) {
+            Ok(original_stamp) => original_stamp,
+            Err(_) => return false,
+        };
+        if original_stamp == entry.original_stamp {
+            return true;
+        }
+        match fs::read(original) {
+            Ok(contents) if hash(&contents) == entry.hash => {
+                entry.original_stamp = original_stamp;
+                true
+            }
+            Ok(_) | Err(_) => false,
+        }
+    }
+
+    /// Records whether the file at `copy` has the contents of `original`.
+    ///
+    /// Both files are read. The metadata is taken before reading them, so that a file that changes
+    /// in the meantime makes the entry stale instead of wrong.
+    pub fn record(&mut self, copy: &Path, original: &Path) -> io::Result<()> {
+        let (copy_key, original_path) = match (copy.to_str(), original.to_str()) {
+            (Some(copy), Some(original)) => (copy, original),
+            _ => return Ok(()),
+        };
+        self.copies.remove(copy_key);
+        let copy_stamp = stamp(copy)?;
+        let original_stamp = stamp(original)?;
+        let contents = fs::read(original)?;
+        if fs::read(copy)? != contents {
+            return Ok(());
+        }
+        let entry = Entry {
+            original: original_path.to_owned(),
+            hash: hash(&contents),
+            original_stamp,
+            copy_stamp,
+        };
+        self.copies.insert(copy_key.to_owned(), entry);
+        Ok(())
+    }
+
+    /// Removes the entry for `copy`, if there is one.
+    pub fn forget(&mut self, copy: &Path) {
+        if let Some(copy) = copy.to_str() {
+            self.copies.remove(copy);
+        }
+    }
+
+    /// Removes the entries of all copies for which `keep` returns `false`.
+    pub fn retain<F: Fn(&Path) -> bool>(&mut self, keep: F) {
+        self.copies.retain(|copy, _| keep(Path::new(copy)));
+    }
+}
+
+/// Returns the metadata of the regular file at `path`, without following links.
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.file_type().is_file() {
+        return Err(io::Error::new(ErrorKind::Other, "not a regular file"));
+    }
+    Ok(Stamp::from(&metadata))
+}
+
+impl From<&Metadata> for Stamp {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+        }
+    }
+}
+
+/// Returns the 64-bit FNV-1a hash of `contents`, in hexadecimal.
+///
+/// The hash only guards against touched original files, so it does not need to be cryptographic,
+/// but it must not change between versions of Rust, unlike the hashers from the standard library.
+fn hash(contents: &[u8]) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let hash = contents.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Reads the cache file at `path`.
+///
+/// Returns an empty cache if the file does not exist. A file that cannot be parsed or that uses an
+/// older format is treated as missing, with a warning.
+pub fn read(path: &Path) -> anyhow::Result<CopyCache> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(CopyCache::new()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read the contents of file {:?}", path))
+        }
+    };
+    Ok(parse(&contents, path))
+}
+
+fn parse(contents: &str, path: &Path) -> CopyCache {
+    match toml::from_str::<CopyCache>(contents) {
+        Ok(cache) if cache.version == CACHE_VERSION => cache,
+        Ok(_) | Err(_) => {
+            warn!("ignoring the copy cache {:?} in an unknown format", path);
+            CopyCache::new()
+        }
+    }
+}
+
+/// Writes `cache` to the cache file at `path`, creating its parent directories if needed.
+pub fn write(path: &Path, cache: &CopyCache) -> anyhow::Result<()> {
+    let serialized = toml::to_string(cache).context("Failed to serialize the copy cache")?;
+    let contents = format!("# Generated by archman, do not edit.\n{}", serialized);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create the parent directory of {:?}", path))?;
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write to {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn detect_changed_files() {
+        let dir = env::temp_dir().join(format!("archman-copy-cache-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("original");
+        let copy = dir.join("copy");
+        fs::write(&original, "contents").unwrap();
+        fs::write(&copy, "contents").unwrap();
+
+        let mut cache = CopyCache::new();
+        assert!(!cache.is_up_to_date(&copy, &original));
+        cache.record(&copy, &original).unwrap();
+        assert!(cache.is_up_to_date(&copy, &original));
+        assert!(!cache.is_up_to_date(&copy, &dir.join("other")));
+
+        let serialized = toml::to_string(&cache).unwrap();
+        let mut cache = parse(&serialized, Path::new("copies.toml"));
+        assert!(cache.is_up_to_date(&copy, &original));
+
+        // rewriting the original with the same contents only changes its metadata
+        fs::write(&original, "contents").unwrap();
+        assert!(cache.is_up_to_date(&copy, &original));
+
+        fs::write(&copy, "modified").unwrap();
+        assert!(!cache.is_up_to_date(&copy, &original));
+        cache.record(&copy, &original).unwrap();
+        assert!(!cache.is_up_to_date(&copy, &original));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_cache_format() {
+        let path = Path::new("copies.toml");
+        assert!(parse("version = 0\n[copies]\n", path).copies.is_empty());
+        assert!(parse("not a cache file", path).copies.is_empty());
+    }
+}
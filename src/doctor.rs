@@ -0,0 +1,220 @@
+//! Checking that the environment has everything archman needs.
+
+use std::{
+    env,
+    fs::File,
+    num::NonZeroUsize,
+    os::unix::fs::PermissionsExt,
+    panic,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::bail;
+
+use crate::{
+    args::{ArgsCommon, DoctorArgs},
+    config::{Config, Source},
+};
+
+/// How much a failed check matters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Severity {
+    /// archman cannot work properly without it.
+    Required,
+    /// Only some uses of archman need it.
+    Optional,
+}
+
+/// Runs all checks and prints a report, failing if any required check failed.
+pub fn check_environment(args: DoctorArgs, common: ArgsCommon) -> anyhow::Result<()> {
+    let DoctorArgs { parallel } = args;
+    let mut report = Report::default();
+
+    for (program, severity) in [
+        ("pacman", Severity::Required),
+        ("systemctl", Severity::Required),
+        ("sudo", Severity::Optional),
+    ] {
+        let result = match find_in_path(program) {
+            Some(path) => Ok(format!("found {:?}", path)),
+            None => Err(String::from("not found in PATH")),
+        };
+        report.record(program, severity, result);
+    }
+
+    let located = Config::locate(common.clone());
+    let home_result = match &located {
+        Ok((home, _)) => Ok(format!("{:?}", home)),
+        Err(err) => Err(err.to_string()),
+    };
+    report.record("home directory", Severity::Required, home_result);
+    if located.is_err() {
+        return report.finish();
+    }
+
+    let config = match Config::read_from_file(common) {
+        Ok(config) => {
+            let details = String::from("parsed successfully");
+            report.record("configuration file", Severity::Required, Ok(details));
+            config
+        }
+        Err(err) => {
+            let details = format!("{:#}", anyhow::Error::from(err));
+            report.record("configuration file", Severity::Required, Err(details));
+            return report.finish();
+        }
+    };
+
+    if let Some(xkb_types) = config.xkb_types() {
+        let result = match File::open(&xkb_types) {
+            Ok(_) => Ok(format!("{:?} is readable", xkb_types)),
+            Err(err) => Err(format!("failed to open {:?}: {}", xkb_types, err)),
+        };
+        report.record("xkb types file", Severity::Required, result);
+    }
+
+    let links = config.links();
+    let copies = config.copies();
+    let entries: Vec<_> = links
+        .iter()
+        .chain(&copies)
+        .map(|(location, source)| (location.as_path(), source))
+        .collect();
+    let threads = if parallel {
+        thread::available_parallelism().map_or(1, NonZeroUsize::get)
+    } else {
+        1
+    };
+    let missing = find_missing_sources(&entries, threads);
+    let result = if missing.is_empty() {
+        Ok(format!("all {} exist", entries.len()))
+    } else {
+        let missing: Vec<_> = missing
+            .iter()
+            .map(|(location, source)| format!("{:?} (for {:?})", source, location))
+            .collect();
+        Err(format!("missing {}", missing.join(", ")))
+    };
+    report.record("link targets and copied files", Severity::Required, result);
+
+    report.finish()
+}
+
+/// Returns the declared links and copies, given as pairs of a location and a source, whose source
+/// does not exist, sorted by location.
+///
+/// Optional sources are skipped. The checks are split between up to `threads` threads.
+fn find_missing_sources<'a>(
+    entries: &[(&'a Path, &'a Source)],
+    threads: usize,
+) -> Vec<(&'a Path, &'a Path)> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = (entries.len() + threads - 1) / threads;
+    let mut missing: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = entries
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter(|(_, source)| !source.optional && !source.path.exists())
+                        .map(|&(location, source)| (location, source.path.as_path()))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload))
+            })
+            .collect()
+    });
+    missing.sort_unstable();
+    missing
+}
+
+/// The results of checks made so far.
+#[derive(Debug, Default)]
+struct Report {
+    /// The number of failed required checks.
+    failed: usize,
+}
+
+impl Report {
+    /// Prints the result of a single check.
+    fn record(&mut self, name: &str, severity: Severity, result: Result<String, String>) {
+        match (result, severity) {
+            (Ok(details), _) => info!("{}: {}", name, details),
+            (Err(details), Severity::Required) => {
+                self.failed += 1;
+                error!("{}: {}", name, details);
+            }
+            (Err(details), Severity::Optional) => warn!("{}: {}", name, details),
+        }
+    }
+
+    /// Fails if any required check failed.
+    fn finish(self) -> anyhow::Result<()> {
+        match self.failed {
+            0 => Ok(()),
+            1 => bail!("1 required check failed"),
+            n => bail!("{} required checks failed", n),
+        }
+    }
+}
+
+/// Returns the path to the executable called `program` in one of the directories in `PATH`.
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Returns `true` if `path` is a file that can be executed by someone.
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_missing_sources_in_order() {
+        let existing = env::temp_dir();
+        let source = |path: &Path, optional| Source {
+            path: path.to_owned(),
+            optional,
+            requires: None,
+        };
+        let present = source(&existing, false);
+        let missing = source(Path::new("/nonexistent/archman/source"), false);
+        let optional = source(Path::new("/nonexistent/archman/optional"), true);
+        let entries = [
+            (Path::new("/home/user/d"), &missing),
+            (Path::new("/home/user/a"), &present),
+            (Path::new("/home/user/c"), &optional),
+            (Path::new("/home/user/b"), &missing),
+            (Path::new("/etc/e"), &missing),
+        ];
+
+        let expected = [
+            (Path::new("/etc/e"), missing.path.as_path()),
+            (Path::new("/home/user/b"), missing.path.as_path()),
+            (Path::new("/home/user/d"), missing.path.as_path()),
+        ];
+        for threads in [1, 2, 4, 16] {
+            assert_eq!(find_missing_sources(&entries, threads), expected);
+        }
+        assert!(find_missing_sources(&[], 4).is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+//! Sorting the lists in the configuration file.
+//!
+//! Lists of packages, bundles, package groups and services are sets, so their order does not
+//! matter. We sort them alphabetically and remove duplicates, so that changes to them are easy to
+//! review.
+//! Everything else in the file, including comments and the layout of the lists, is left alone.
+//! Links and copies are never reordered, since their order can be significant.
+
+use std::fs;
+
+use anyhow::{bail, Context};
+use toml_edit::{Array, Decor, Document, Item, Value};
+
+use crate::{
+    args::{ArgsCommon, ConfigFormat, FmtArgs},
+    config::{self, Config},
+    util,
+};
+
+/// The top-level keys whose lists are sorted.
+const SORTED_KEYS: &[&str] = &[
+    "packages",
+    "bundles",
+    "package_groups",
+    "services",
+    "masked_services",
+];
+
+/// Sorts the lists in the configuration file in place.
+///
+/// With `--check`, only fails if the file is not sorted.
+pub fn format_config(args: FmtArgs, common: ArgsCommon) -> anyhow::Result<()> {
+    let mode = common.run_mode();
+    let explicit_format = common.config_format;
+    let (_, path) = Config::locate(common)?;
+    let format = config::file_format(explicit_format, &path);
+    if format != ConfigFormat::Toml {
+        bail!(
+            "Only TOML configuration files can be formatted, {:?} is {}",
+            path,
+            format
+        );
+    }
+    let contents = config::read_valid_file(&path, format)?;
+    let formatted = format_contents(&contents)
+        .with_context(|| format!("Failed to parse the configuration file {:?}", path))?;
+
+    if formatted == contents {
+        info!("{:?} is already formatted", path);
+        return Ok(());
+    }
+    if args.check {
+        bail!("{:?} is not formatted, run 'archman fmt' to fix it", path);
+    }
+    if mode.is_dry_run() {
+        info!("Would format {:?}", path);
+        return Ok(());
+    }
+    // the file is often a link into a repository of dotfiles, which must not be replaced
+    let target = fs::canonicalize(&path)
+        .with_context(|| format!("Failed to resolve the path {:?}", path))?;
+    util::write_atomically(&target, formatted.as_bytes())
+        .with_context(|| format!("Failed to write to {:?}", target))?;
+    info!("Formatted {:?}", path);
+    Ok(())
+}
+
+/// Returns `contents` with all lists of packages, bundles, package groups and services sorted.
+fn format_contents(contents: &str) -> Result<String, toml_edit::TomlError> {
+    let mut document: Document = contents.parse()?;
+    for &key in SORTED_KEYS {
+        if let Some(item) = document.get_mut(key) {
+            format_item(item);
+        }
+    }
+    Ok(document.to_string())
+}
+
+/// Sorts all lists in `item`, recursively.
+fn format_item(item: &mut Item) {
+    match item {
+        Item::Value(value) => format_value(value),
+        Item::Table(table) => table.iter_mut().for_each(|(_, item)| format_item(item)),
+        Item::ArrayOfTables(tables) => tables
+            .iter_mut()
+            .flat_map(|table| table.iter_mut())
+            .for_each(|(_, item)| format_item(item)),
+        Item::None => {}
+    }
+}
+
+/// Sorts all lists in `value`, recursively.
+fn format_value(value: &mut Value) {
+    match value {
+        Value::Array(array) => {
+            array.iter_mut().for_each(format_value);
+            sort_array(array);
+        }
+        Value::InlineTable(table) => table.iter_mut().for_each(|(_, value)| format_value(value)),
+        _ => {}
+    }
+}
+
+/// Sorts the elements of `array` and removes duplicate strings.
+///
+/// Elements are sorted by their string value, or by the `name` of detailed service declarations.
+/// Arrays with any other elements, like nested sets of packages, are left alone.
+///
+/// If no element has a comment attached, elements take the whitespace around them from their new
+/// position, so that the layout of the array is preserved. Otherwise comments move along with the
+/// elements they precede, and the comments of a removed duplicate are added to the one kept.
+fn sort_array(array: &mut Array) {
+    if array.iter().any(|value| sort_key(value).is_none()) {
+        return;
+    }
+    let mut values: Vec<Value> = (0..array.len()).map(|_| array.remove(0)).collect();
+    let decors: Vec<Decor> = values.iter().map(|value| value.decor().clone()).collect();
+    let has_comments = decors.iter().any(|decor| {
+        let has_comment = |raw: Option<&toml_edit::RawString>| {
+            raw.and_then(|raw| raw.as_str())
+                .map_or(false, |raw| raw.contains('#'))
+        };
+        has_comment(decor.prefix()) || has_comment(decor.suffix())
+    });
+
+    values.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+    values.dedup_by(|duplicate, kept| {
+        let is_duplicate = duplicate.as_str().is_some() && duplicate.as_str() == kept.as_str();
+        if is_duplicate {
+            merge_comments(duplicate.decor(), kept.decor_mut());
+        }
+        is_duplicate
+    });
+    for (index, mut value) in values.into_iter().enumerate() {
+        if !has_comments {
+            *value.decor_mut() = decors[index].clone();
+        }
+        array.push_formatted(value);
+    }
+}
+
+/// Adds the comment lines that precede an element with the decor `from` to the ones that precede
+/// an element with the decor `into`, indented like that element.
+fn merge_comments(from: &Decor, into: &mut Decor) {
+    let comments: Vec<&str> = from
+        .prefix()
+        .and_then(|raw| raw.as_str())
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('#'))
+        .collect();
+    if comments.is_empty() {
+        return;
+    }
+    let prefix = into
+        .prefix()
+        .and_then(|raw| raw.as_str())
+        .unwrap_or_default();
+    let (head, indent) = prefix.rsplit_once('\n').unwrap_or((prefix.trim_end(), ""));
+    let mut merged = format!("{}\n", head);
+    for comment in comments {
+        merged.push_str(indent);
+        merged.push_str(comment);
+        merged.push('\n');
+    }
+    merged.push_str(indent);
+    into.set_prefix(merged);
+}
+
+/// Returns the key that `value` is sorted by, if it can be sorted.
+fn sort_key(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(string) => Some(string.value()),
+        Value::InlineTable(table) => table.get("name").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_lists() {
+        let contents = concat!(
+            "# comment at the top\n",
+            "[packages]\n",
+            "common = [\"vim\", \"base\", \"@editors\", \"vim\"]\n",
+            "hosts.archbox = [\n",
+            "    \"zsh\",\n",
+            "    # the best browser\n",
+            "    \"firefox\",\n",
+            "]\n",
+            "[packages.tags]\n",
+            "gaming = { games = [\"steam\", \"lutris\"], other = [[\"b\", \"a\"], \"c\"] }\n",
+            "[services]\n",
+            "common = [{ name = \"sshd\", start = true }, \"docker\"]\n",
+            "[links.common]\n",
+            "\"~/b\" = \"b\"\n",
+            "\"~/a\" = \"a\"\n",
+        );
+        let expected = concat!(
+            "# comment at the top\n",
+            "[packages]\n",
+            "common = [\"@editors\", \"base\", \"vim\"]\n",
+            "hosts.archbox = [\n",
+            "    # the best browser\n",
+            "    \"firefox\",\n",
+            "    \"zsh\",\n",
+            "]\n",
+            "[packages.tags]\n",
+            "gaming = { games = [\"lutris\", \"steam\"], other = [[\"a\", \"b\"], \"c\"] }\n",
+            "[services]\n",
+            "common = [\"docker\", { name = \"sshd\", start = true }]\n",
+            "[links.common]\n",
+            "\"~/b\" = \"b\"\n",
+            "\"~/a\" = \"a\"\n",
+        );
+        let formatted = format_contents(contents).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format_contents(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn keep_comments_of_duplicates() {
+        let contents = concat!(
+            "[packages]\n",
+            "common = [\n",
+            "    # editor\n",
+            "    \"vim\",\n",
+            "    \"base\",\n",
+            "    # needed for plugins\n",
+            "    \"vim\",\n",
+            "]\n",
+        );
+        let expected = concat!(
+            "[packages]\n",
+            "common = [\n",
+            "    \"base\",\n",
+            "    # editor\n",
+            "    # needed for plugins\n",
+            "    \"vim\",\n",
+            "]\n",
+        );
+        let formatted = format_contents(contents).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format_contents(&formatted).unwrap(), formatted);
+    }
+}
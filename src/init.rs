@@ -0,0 +1,287 @@
+//! Creating a skeleton configuration file.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    args::{ArgsCommon, InitArgs},
+    backend::{InstallReason, PackageBackend, QueryFilter},
+    config::Config,
+};
+
+/// Explicitly installed packages, grouped by the pacman group they belong to.
+#[derive(Debug, Default)]
+struct InstalledPackages {
+    /// Packages that do not belong to any group, sorted.
+    ungrouped: Vec<String>,
+    /// Packages that belong to a group, sorted, by the group they were put in.
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Writes a commented configuration file skeleton to the configuration file path.
+///
+/// Refuses to overwrite an existing file, unless `--force` was given. With `--from-installed`, the
+/// common packages are the explicitly installed ones, see [`installed_packages`].
+pub fn create_config(
+    args: InitArgs,
+    common: ArgsCommon,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    let mode = common.run_mode();
+    let (_, path) = Config::locate(common)?;
+
+    match path.symlink_metadata() {
+        Ok(_) if !args.force => bail!("{:?} already exists, use --force to overwrite it", path),
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to query for metadata of file {:?}", path))?
+        }
+    }
+
+    let installed = if args.from_installed {
+        let installed =
+            installed_packages(backend).context("Failed to query for installed packages")?;
+        Some(installed)
+    } else {
+        None
+    };
+
+    if mode.is_dry_run() {
+        info!("Would create {:?}", path);
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create the parent directory of {:?}", path))?;
+    }
+
+    let hostname = gethostname::gethostname();
+    let contents = skeleton(&hostname, installed.as_ref());
+    fs::write(&path, contents).with_context(|| format!("Failed to write to {:?}", path))?;
+    info!("Created {:?}", path);
+
+    Ok(())
+}
+
+/// Returns the explicitly installed packages, grouped by the pacman groups they belong to.
+///
+/// A package that belongs to more than one group is put in the first of them in alphabetical order.
+fn installed_packages(backend: &dyn PackageBackend) -> anyhow::Result<InstalledPackages> {
+    let explicit = |foreign| QueryFilter {
+        install_reason: Some(InstallReason::Explicit),
+        foreign,
+        ..QueryFilter::default()
+    };
+    let mut packages = backend.query(explicit(false))?;
+    packages.extend(backend.query(explicit(true))?);
+    let groups = backend.installed_groups()?;
+    Ok(group_packages(packages, &groups))
+}
+
+/// Groups `packages` by the first of their `groups`, see [`installed_packages`].
+fn group_packages(
+    packages: HashSet<String>,
+    groups: &HashMap<String, Vec<String>>,
+) -> InstalledPackages {
+    let mut packages: Vec<_> = packages.into_iter().collect();
+    packages.sort_unstable();
+    let mut installed = InstalledPackages::default();
+    for package in packages {
+        match groups.get(&package).and_then(|groups| groups.iter().min()) {
+            Some(group) => installed
+                .groups
+                .entry(group.clone())
+                .or_default()
+                .push(package),
+            None => installed.ungrouped.push(package),
+        }
+    }
+    installed
+}
+
+/// Returns the contents of the skeleton configuration file.
+///
+/// If `hostname` is valid UTF-8, it is used to create a stub section for host-specific packages.
+/// If `installed` packages are given, they are declared as common packages, otherwise that section
+/// only has an example.
+fn skeleton(hostname: &OsStr, installed: Option<&InstalledPackages>) -> String {
+    let mut contents = String::from(concat!(
+        "# Configuration file for archman.\n",
+        "#\n",
+        "# Every section has a `common` part that applies to all machines, and an optional `hosts`\n",
+        "# part with values that apply only to the machine with the given hostname. Hostnames are\n",
+        "# matched ignoring case.\n",
+        "#\n",
+        "# Every `*.toml` file in the `conf.d` directory next to this file is read after it, in\n",
+        "# alphabetical order. Lists are merged, paths to files are taken from the last file.\n",
+        "\n",
+        "# Path to the xkb types file that should be patched during `archman sync`, either a single\n",
+        "# path or a table with `common` and `hosts` sections.\n",
+        "# xkb_types = \"/usr/share/X11/xkb/types/complete\"\n",
+        "# Set to false to never patch the xkb types file, even if `xkb_types` is set.\n",
+        "# xkb_patch = true\n",
+        "\n",
+        "# Path to the pacman configuration file, used to find packages ignored by pacman.\n",
+        "# pacman_conf = \"/etc/pacman.conf\"\n",
+        "\n",
+        "# Packages that make `archman sync` recommend a reboot when they are upgraded.\n",
+        "# reboot_packages = [\"linux\", \"systemd\", \"glibc\"]\n",
+        "\n",
+        "# AUR helper that `archman show --updates` uses to find updates of foreign packages.\n",
+        "# aur_helper = \"paru\"\n",
+        "\n",
+        "# Defaults for command line flags, overridden by flags like `--cleanup` and `--no-cleanup`.\n",
+        "[defaults.sync]\n",
+        "# cleanup = false\n",
+        "[defaults.service]\n",
+        "# start = false\n",
+        "\n",
+        "# Files that should be copied, mapping locations of the copies to the original files.\n",
+        "[copies.common]\n",
+        "# \"~/.config/foo/foo.conf\" = \"foo/foo.conf\"\n",
+        "\n",
+        "# Links that should be created, mapping locations of the links to the link targets. To\n",
+        "# create links in order, use an array like `common = [{ location = \"...\", target = \"...\" }]`.\n",
+        "# Entries in an array can set `optional = true` to be skipped if the target does not exist,\n",
+        "# and `requires = \"package\"` to be skipped if the package is not installed. Neither is an\n",
+        "# error.\n",
+        "[links.common]\n",
+        "# \"~/.config/bar/bar.conf\" = \"bar/bar.conf\"\n",
+        "\n",
+        "# Package groups that should be installed.\n",
+        "[package_groups]\n",
+        "common = []\n",
+        "\n",
+        "# Packages that should be installed. Packages can be grouped into named or unnamed groups.\n",
+    ));
+    match installed {
+        Some(installed) => contents.push_str(&packages_section(installed)),
+        None => contents.push_str(concat!(
+            "[packages.common]\n",
+            "# base = [\"base\", \"linux\", \"linux-firmware\"]\n",
+        )),
+    }
+    contents.push_str(concat!(
+        "\n",
+        "# Packages that should be installed only when the tag is given with `--tag`, in addition to\n",
+        "# the ones declared in `common` and for this machine.\n",
+        "[packages.tags]\n",
+        "# gaming = [\"steam\"]\n",
+        "\n",
+        "# Bundles of packages that are only installed where they are referenced, like `\"@desktop\"`\n",
+        "# in any section of `packages`. References must not form a cycle.\n",
+        "[bundles]\n",
+        "# desktop = [\"sway\", \"foot\"]\n",
+        "\n",
+        "# Systemd services that should be enabled. Services declared as tables can also be started\n",
+        "# when they are enabled, e.g. `{ name = \"NetworkManager\", start = true }`, and set\n",
+        "# `requires = \"package\"` to be skipped, not failed, if the package is not installed.\n",
+        "[services]\n",
+        "common = []\n",
+        "\n",
+        "# Systemd services that should be masked.\n",
+        "[masked_services]\n",
+        "common = []\n",
+    ));
+
+    if let Some(hostname) = hostname.to_str() {
+        contents.push_str(&format!(
+            "\n# Packages that should be installed only on this machine.\n[packages.hosts.{}]\n",
+            toml_key(hostname),
+        ));
+    }
+
+    contents
+}
+
+/// Returns a section declaring the `installed` packages as common packages.
+///
+/// Ungrouped packages come first, followed by a named group for every pacman group.
+fn packages_section(installed: &InstalledPackages) -> String {
+    let string = |package: &String| toml::Value::String(package.clone()).to_string();
+    let mut section = String::from("[packages]\ncommon = [\n");
+    for package in &installed.ungrouped {
+        section.push_str(&format!("    {},\n", string(package)));
+    }
+    for (group, packages) in &installed.groups {
+        let packages: Vec<_> = packages.iter().map(string).collect();
+        section.push_str(&format!(
+            "    {{ {} = [{}] }},\n",
+            toml_key(group),
+            packages.join(", "),
+        ));
+    }
+    section.push_str("]\n");
+    section
+}
+
+/// Formats `key` as a TOML key, quoting it if it is not a valid bare key.
+fn toml_key(key: &str) -> String {
+    let is_bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if is_bare {
+        key.to_owned()
+    } else {
+        toml::Value::String(key.to_owned()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skeleton_is_valid_toml() {
+        for hostname in ["archlinux", "my.host", "\"quoted\""] {
+            let contents = skeleton(hostname.as_ref(), None);
+            let parsed: toml::Table = toml::from_str(&contents).unwrap();
+            let hosts = parsed["packages"]["hosts"].as_table().unwrap();
+            assert!(hosts.contains_key(hostname));
+        }
+    }
+
+    #[test]
+    fn skeleton_from_installed_packages() {
+        let packages = ["vim", "gcc", "make", "xorg-xrandr", "firefox"];
+        let groups = [
+            ("gcc", &["base-devel"][..]),
+            ("make", &["base-devel"][..]),
+            ("xorg-xrandr", &["xorg-apps", "xorg"][..]),
+        ];
+        let packages = packages.into_iter().map(String::from).collect();
+        let groups = groups
+            .into_iter()
+            .map(|(package, groups)| {
+                let groups = groups.iter().copied().map(String::from).collect();
+                (package.to_owned(), groups)
+            })
+            .collect();
+        let installed = group_packages(packages, &groups);
+        assert_eq!(installed.ungrouped, ["firefox", "vim"]);
+
+        let contents = skeleton("archlinux".as_ref(), Some(&installed));
+        let parsed: toml::Table = toml::from_str(&contents).unwrap();
+        let expected: toml::Value = toml::from_str::<toml::Table>(concat!(
+            "common = [\"firefox\", \"vim\", { base-devel = [\"gcc\", \"make\"] }, ",
+            "{ xorg = [\"xorg-xrandr\"] }]\n",
+        ))
+        .unwrap()
+        .remove("common")
+        .unwrap();
+        assert_eq!(parsed["packages"]["common"], expected);
+        assert!(parsed["packages"]["hosts"]
+            .as_table()
+            .unwrap()
+            .contains_key("archlinux"));
+    }
+}
@@ -7,41 +7,100 @@
 #[macro_use]
 mod util;
 
+mod apply;
 mod args;
+mod backend;
 mod completions;
 mod config;
+mod copy_cache;
+mod doctor;
+mod format;
+mod init;
 mod link;
+mod lock;
 mod packages;
 mod pacman;
+mod report;
 mod service;
 mod show;
+mod state;
 mod sync;
 
 pub use args::Args;
+pub use report::RunReport;
+pub use show::Drift;
+pub use util::use_style;
 
 use args::Subcommand;
 use config::Config;
+use lock::Lock;
+use pacman::Pacman;
 
 /// Runs the program, given the parsed command line arguments.
-pub fn run(args: Args) -> anyhow::Result<()> {
-    if let Subcommand::Completions(completions_args) = args.subcommand {
-        return completions::generate_completions(completions_args);
-    }
+///
+/// Returns a report of the changes made to the system.
+pub fn run(args: Args) -> anyhow::Result<RunReport> {
+    let mode = args.common.run_mode();
+    util::set_color_choice(args.common.color);
+    pacman::set_print_commands(args.common.print_pacman_command);
+    util::set_command_timeout(args.common.timeout);
+    let args = match args.subcommand {
+        Subcommand::ColorTest(color_test_args) => {
+            util::print_color_test(color_test_args);
+            return Ok(RunReport::default());
+        }
+        Subcommand::Completions(completions_args) => {
+            completions::generate_completions(completions_args)?;
+            return Ok(RunReport::default());
+        }
+        Subcommand::Doctor(doctor_args) => {
+            doctor::check_environment(doctor_args, args.common)?;
+            return Ok(RunReport::default());
+        }
+        Subcommand::Fmt(fmt_args) => {
+            format::format_config(fmt_args, args.common)?;
+            return Ok(RunReport::default());
+        }
+        Subcommand::Init(init_args) => {
+            init::create_config(init_args, args.common, &Pacman)?;
+            return Ok(RunReport::default());
+        }
+        _ => args,
+    };
 
     let config = Config::read_from_file(args.common)?;
+    let lock = if args.subcommand.is_read_only() || mode.is_dry_run() {
+        None
+    } else {
+        Some(Lock::acquire(&config.lock_file())?)
+    };
 
-    match args.subcommand {
-        Subcommand::Completions(_) => unreachable!(),
+    let mut report = match args.subcommand {
+        Subcommand::ColorTest(_)
+        | Subcommand::Completions(_)
+        | Subcommand::Doctor(_)
+        | Subcommand::Fmt(_)
+        | Subcommand::Init(_) => unreachable!(),
+        Subcommand::Apply(apply_args) => apply::apply(apply_args, &config, mode, &Pacman)?,
+        Subcommand::CompletePackages(complete_args) => {
+            completions::print_package_names(complete_args, &config, &Pacman)?;
+            RunReport::default()
+        }
         Subcommand::Copy(copy_args) => {
-            link::create_copies(copy_args, config);
-            Ok(())
+            link::create_copies(copy_args, &config, mode, &Pacman, lock)?
+        }
+        Subcommand::Link(link_args) => link::create_links(link_args, &config, mode, &Pacman)?,
+        Subcommand::Service(service_args) => {
+            service::synchronize_services(service_args, &config, mode, &Pacman)?
+        }
+        Subcommand::Show(show_args) => {
+            show::show_packages(show_args, &config, &Pacman)?;
+            RunReport::default()
         }
-        Subcommand::Link(link_args) => {
-            link::create_links(link_args, config);
-            Ok(())
+        Subcommand::Sync(sync_args) => {
+            sync::synchronize_packages(sync_args, &config, mode, &Pacman)?
         }
-        Subcommand::Service(service_args) => service::synchronize_services(service_args, config),
-        Subcommand::Show(show_args) => show::show_packages(show_args, config),
-        Subcommand::Sync(sync_args) => sync::synchronize_packages(sync_args, config),
-    }
+    };
+    report.dry_run = mode.is_dry_run();
+    Ok(report)
 }
@@ -1,88 +1,852 @@
 //! Creating links to and copies of configuration files.
 
-use std::{fs, io::ErrorKind, os::unix, path::Path};
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, ErrorKind, Write},
+    mem,
+    os::unix::{self, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use indexmap::{IndexMap, IndexSet};
+use is_terminal::IsTerminal;
+use serde::Serialize;
 
 use crate::{
-    args::{CopyArgs, LinkArgs},
-    config::Config,
+    args::{CopyArgs, LinkArgs, OutputFormat, RunMode},
+    backend::PackageBackend,
+    config::{Config, Source},
+    copy_cache::{self, CopyCache},
+    lock::Lock,
+    report::RunReport,
+    util::{self, SigintGuard},
 };
 
+/// How often original files are checked for changes when watching them.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
 /// Creates symbolic links to files specified in `cfg`.
-pub fn create_links(args: LinkArgs, cfg: Config) {
-    for (location, target) in cfg.links() {
-        let parent = match location.parent() {
-            Some(parent) => parent,
-            None => {
-                error!("The root directory is not a valid link path");
+pub fn create_links(
+    args: LinkArgs,
+    cfg: &Config,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<RunReport> {
+    check_output_format(args.format)?;
+    let mut report = RunReport::default();
+    let links = reroot(cfg.links(), args.prefix.as_deref());
+    if args.stat {
+        print_link_stats(&links);
+        return Ok(report);
+    }
+    if args.audit_orphans {
+        let skip_prompt = args.yes || mode.is_dry_run();
+        audit_orphans(
+            &links,
+            cfg.dir(),
+            args.remove,
+            skip_prompt,
+            mode,
+            &mut report,
+        )?;
+        return Ok(report);
+    }
+    check_can_change_owner(cfg.target_user(), mode)?;
+    let (links, mut outcomes) = skip_unmet_requirements(backend, links)?;
+    let mut progress = Progress::new("linking", links.len(), args.verbose);
+    for (location, target) in links {
+        progress.clear();
+        let result = create_declared_link(&location, &target, &args, cfg.target_user(), mode);
+        let outcome = Outcome::new(&location, &target, result);
+        if outcome.action.is_change() {
+            report.links.push(location.clone());
+        }
+        outcomes.push(outcome);
+        progress.advance(&location);
+    }
+    progress.clear();
+    print_unchanged_count(&outcomes, "link", "links");
+    print_outcomes(&outcomes, args.format, &mut report)?;
+    Ok(report)
+}
+
+/// Creates copies of files specified in `cfg`.
+///
+/// The `lock` held while creating them is released before watching, so that other runs are not
+/// blocked until the watch is interrupted.
+pub fn create_copies(
+    args: CopyArgs,
+    cfg: &Config,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+    lock: Option<Lock>,
+) -> anyhow::Result<RunReport> {
+    check_output_format(args.format)?;
+    let mut report = RunReport::default();
+    check_can_change_owner(cfg.target_user(), mode)?;
+    let copies = reroot(cfg.copies(), args.prefix.as_deref());
+    let (copies, mut outcomes) = skip_unmet_requirements(backend, copies)?;
+    let cache_file = cfg.copy_cache_file();
+    let mut cache = copy_cache::read(&cache_file)?;
+    let mut progress = Progress::new("copying", copies.len(), args.verbose);
+    for (copy, original) in &copies {
+        progress.clear();
+        let result = create_cached_copy(copy, original, &args, cfg.target_user(), mode, &mut cache);
+        let outcome = Outcome::new(copy, original, result);
+        if outcome.action.is_change() {
+            report.copies.push(copy.clone());
+        }
+        outcomes.push(outcome);
+        progress.advance(copy);
+    }
+    progress.clear();
+    print_unchanged_count(&outcomes, "copy", "copies");
+    print_outcomes(&outcomes, args.format, &mut report)?;
+    if !mode.is_dry_run() {
+        cache.retain(|copy| copies.contains_key(copy));
+        if let Err(err) = copy_cache::write(&cache_file, &cache) {
+            warn!("failed to update the copy cache: {:#}", err);
+        }
+    }
+
+    if args.watch {
+        drop(lock);
+        watch_copies(cfg, copies, &args, mode, backend, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Moves the declared locations under `prefix`, if there is one.
+///
+/// Locations are always absolute, so they are joined with `prefix` as if they were relative to the
+/// root directory. Sources stay where they are.
+fn reroot(paths: IndexMap<PathBuf, Source>, prefix: Option<&Path>) -> IndexMap<PathBuf, Source> {
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => return paths,
+    };
+    paths
+        .into_iter()
+        .map(|(location, source)| {
+            let relative = location.strip_prefix("/").unwrap_or(&location);
+            (prefix.join(relative), source)
+        })
+        .collect()
+}
+
+/// Prints the links that are not declared, but point into `dir`, and removes them if `remove` is
+/// set.
+///
+/// Unless `skip_prompt` is set, asks the user for confirmation before removing them.
+fn audit_orphans(
+    links: &IndexMap<PathBuf, Source>,
+    dir: &Path,
+    remove: bool,
+    skip_prompt: bool,
+    mode: RunMode,
+    report: &mut RunReport,
+) -> anyhow::Result<()> {
+    let orphans = find_orphans(links, dir);
+    if orphans.is_empty() {
+        info!("No undeclared links point into {:?}", dir);
+        return Ok(());
+    }
+    colour!("Undeclared links pointing into {:?}:", dir);
+    for (location, target) in &orphans {
+        println!("  {} -> {}", location.display(), target.display());
+    }
+    if !remove {
+        return Ok(());
+    }
+    if !skip_prompt {
+        let answer = util::ask("Remove these links? [y/N]")
+            .context("Failed to confirm the removal of links")?;
+        if !util::is_yes(&answer) {
+            info!("Not removing any links");
+            return Ok(());
+        }
+    }
+    for (location, target) in orphans {
+        if mode.is_dry_run() {
+            info!("Would remove link {:?} -> {:?}", location, target);
+        } else if let Err(err) = fs::remove_file(&location) {
+            error!("Failed to remove {:?}: {}", location, err);
+            continue;
+        } else {
+            info!("Removed link {:?} -> {:?}", location, target);
+        }
+        report.removed_links.push(location);
+    }
+    Ok(())
+}
+
+/// Returns the undeclared links, along with their targets, that point into `dir` from directories
+/// that contain declared `links`.
+///
+/// Links in each directory are sorted by name. Directories that cannot be read are skipped.
+fn find_orphans(links: &IndexMap<PathBuf, Source>, dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let parents: IndexSet<&Path> = links.keys().filter_map(|link| link.parent()).collect();
+    let mut orphans = Vec::new();
+    for parent in parents {
+        match find_orphans_in(parent, links, dir) {
+            Ok(found) => orphans.extend(found),
+            Err(err) => error!("{:#}", err),
+        }
+    }
+    orphans
+}
+
+/// Returns the undeclared links in `parent` that point into `dir`, sorted by name.
+fn find_orphans_in(
+    parent: &Path,
+    links: &IndexMap<PathBuf, Source>,
+    dir: &Path,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read the directory {:?}", parent))
+        }
+    };
+    let mut orphans = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read the directory {:?}", parent))?;
+        let location = entry.path();
+        let is_symlink = entry
+            .file_type()
+            .with_context(|| format!("Failed to query for the type of file {:?}", location))?
+            .is_symlink();
+        if !is_symlink || links.contains_key(&location) {
+            continue;
+        }
+        let target = location
+            .read_link()
+            .with_context(|| format!("Failed to read the target of link {:?}", location))?;
+        if points_into(parent, &target, dir) {
+            orphans.push((location, target));
+        }
+    }
+    orphans.sort_unstable();
+    Ok(orphans)
+}
+
+/// Returns `true` if `target` of a link in `parent` is inside `dir`.
+///
+/// A relative target is resolved against `parent`. The target is also compared after resolving
+/// links in it, if it exists, since `dir` is canonical.
+fn points_into(parent: &Path, target: &Path, dir: &Path) -> bool {
+    let target = parent.join(target);
+    target.starts_with(dir)
+        || target
+            .canonicalize()
+            .map_or(false, |target| target.starts_with(dir))
+}
+
+/// Copies original files again whenever they change, until interrupted with SIGINT.
+///
+/// Original files are checked every [`WATCH_INTERVAL`]. A change is only applied once the file
+/// stays the same for a whole interval, so that a burst of writes results in a single copy.
+///
+/// The files that the configuration was read from are watched the same way. When they change, the
+/// configuration is read again and the watched copies are updated, see [`update_watched_copies`].
+/// If it cannot be read, the previous configuration is kept.
+fn watch_copies(
+    cfg: &Config,
+    copies: IndexMap<PathBuf, Source>,
+    args: &CopyArgs,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+    report: &mut RunReport,
+) -> anyhow::Result<()> {
+    let guard = SigintGuard::install().context("Failed to install a handler for SIGINT")?;
+    let watch_args = CopyArgs {
+        force: true,
+        backup_dir: None,
+        verbose: args.verbose,
+        watch: true,
+        prune: args.prune,
+        prefix: args.prefix.clone(),
+        format: args.format,
+    };
+    let owner = cfg.target_user();
+    let mut watched = copies
+        .into_iter()
+        .map(|(copy, original)| {
+            let file = WatchedFile::new(&original.path);
+            (copy, (original, file))
+        })
+        .collect();
+    let mut config_files = watch_paths(cfg.source_paths());
+
+    print_watched_count(&watched);
+    while !guard.interrupted() {
+        thread::sleep(WATCH_INTERVAL);
+        for (copy, (original, file)) in &mut watched {
+            if file.poll(&original.path) {
+                let result = create_declared_copy(copy, original, &watch_args, owner, mode);
+                record_copy(result, copy, report);
+            }
+        }
+
+        // every file must be polled, so that all of them are up to date
+        let config_changed = config_files
+            .iter_mut()
+            .fold(false, |changed, (path, file)| file.poll(path) | changed);
+        if !config_changed {
+            continue;
+        }
+        let reloaded = match cfg.reload() {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                warn!(
+                    "failed to reload the configuration, keeping the previous one: {:#}",
+                    anyhow::Error::from(err),
+                );
                 continue;
             }
         };
-        if let Err(err) = create_link(&location, &target, parent, args.force) {
-            error!("{:#}", err);
-        }
+        info!("Reloaded the configuration");
+        config_files = watch_paths(reloaded.source_paths());
+        let declared = reroot(reloaded.copies(), args.prefix.as_deref());
+        let declared = match skip_unmet_requirements(backend, declared) {
+            Ok((declared, _)) => declared,
+            Err(err) => {
+                error!("{:#}", err);
+                continue;
+            }
+        };
+        update_watched_copies(&mut watched, declared, args, owner, mode, report);
+        print_watched_count(&watched);
     }
+    info!("Stopped watching original files");
+    Ok(())
 }
 
-/// Creates copies of files specified in `cfg`.
-pub fn create_copies(args: CopyArgs, cfg: Config) {
-    for (copy, original) in cfg.copies() {
-        let parent = match copy.parent() {
-            Some(parent) => parent,
-            None => {
-                error!("The root directory is not a valid copy path");
+/// Replaces the `watched` copies with the ones `declared` in the reloaded configuration.
+///
+/// Newly declared copies are created, copies whose original file changed are overwritten. Copies
+/// that are no longer declared are removed with `--prune`, see [`remove_copy`], otherwise they are
+/// only no longer watched.
+fn update_watched_copies(
+    watched: &mut IndexMap<PathBuf, (Source, WatchedFile)>,
+    declared: IndexMap<PathBuf, Source>,
+    args: &CopyArgs,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+    report: &mut RunReport,
+) {
+    let mut previous = mem::take(watched);
+    for (copy, original) in declared {
+        let overwrite = match previous.swap_remove(&copy) {
+            Some((old, file)) if old.path == original.path => {
+                watched.insert(copy, (original, file));
                 continue;
             }
+            Some(_) => true,
+            None => args.force,
+        };
+        let create_args = CopyArgs {
+            force: overwrite,
+            backup_dir: None,
+            verbose: args.verbose,
+            watch: true,
+            prune: args.prune,
+            prefix: args.prefix.clone(),
+            format: args.format,
         };
-        if let Err(err) = create_copy(&copy, &original, parent, args.force) {
-            error!("{:#}", err);
+        let result = create_declared_copy(&copy, &original, &create_args, owner, mode);
+        record_copy(result, &copy, report);
+        let file = WatchedFile::new(&original.path);
+        watched.insert(copy, (original, file));
+    }
+
+    for (copy, (original, _)) in previous {
+        if !args.prune {
+            info!("{:?} is no longer declared, not watching it anymore", copy);
+            continue;
+        }
+        match remove_copy(&copy, &original.path, mode) {
+            Ok(true) => report.removed_copies.push(copy),
+            Ok(false) => {}
+            Err(err) => error!("{:#}", err),
         }
     }
 }
 
-fn create_link(
+/// Removes the copy of `original` at `copy`, if it still has the contents of `original`.
+///
+/// Returns `true` if the copy was (or, in a dry run, would be) removed.
+fn remove_copy(copy: &Path, original: &Path, mode: RunMode) -> anyhow::Result<bool> {
+    match classify_copy(copy, original)? {
+        CopyState::Missing => Ok(false),
+        CopyState::Different | CopyState::NotAFile => {
+            warn!(
+                "{:?} is no longer declared, but it differs from {:?}, not removing it",
+                copy, original,
+            );
+            Ok(false)
+        }
+        CopyState::Correct if mode.is_dry_run() => {
+            info!("Would remove copy {:?}", copy);
+            Ok(true)
+        }
+        CopyState::Correct => {
+            fs::remove_file(copy).with_context(|| format!("Failed to remove {:?}", copy))?;
+            info!("Removed copy {:?}", copy);
+            Ok(true)
+        }
+    }
+}
+
+/// Records the `result` of creating a copy while watching in `report`, once for every copy.
+fn record_copy(result: anyhow::Result<Action>, copy: &Path, report: &mut RunReport) {
+    match result {
+        Ok(action)
+            if action.is_change() && !report.copies.iter().any(|recorded| recorded == copy) =>
+        {
+            report.copies.push(copy.to_owned())
+        }
+        Ok(_) => {}
+        Err(err) => error!("{:#}", err),
+    }
+}
+
+/// Prints how many original files are watched.
+fn print_watched_count(watched: &IndexMap<PathBuf, (Source, WatchedFile)>) {
+    info!(
+        "Watching {} original {} for changes, press Ctrl-C to stop",
+        watched.len(),
+        if watched.len() == 1 { "file" } else { "files" },
+    );
+}
+
+/// Starts watching all `paths` for changes.
+fn watch_paths(paths: &[PathBuf]) -> Vec<(PathBuf, WatchedFile)> {
+    paths
+        .iter()
+        .map(|path| (path.clone(), WatchedFile::new(path)))
+        .collect()
+}
+
+/// The state of a file watched for changes.
+#[derive(Debug)]
+struct WatchedFile {
+    /// The last seen modification time and size of the file, see [`stamp`].
+    stamp: Option<(SystemTime, u64)>,
+    /// Whether the file changed since it was last handled.
+    pending: bool,
+}
+
+impl WatchedFile {
+    /// Starts watching the file at `path`.
+    fn new(path: &Path) -> Self {
+        Self {
+            stamp: stamp(path),
+            pending: false,
+        }
+    }
+
+    /// Checks the file at `path` for changes.
+    ///
+    /// Returns `true` once for every completed change, i.e. when the file changed and then stayed
+    /// the same for a whole interval between checks.
+    fn poll(&mut self, path: &Path) -> bool {
+        let current = stamp(path);
+        if current != self.stamp {
+            self.stamp = current;
+            self.pending = true;
+            return false;
+        }
+        mem::take(&mut self.pending)
+    }
+}
+
+/// Returns the modification time and size of the file at `path`, if it can be read.
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Creates a single declared link, see [`create_link`].
+fn create_declared_link(
     location: &Path,
-    target: &Path,
-    parent: &Path,
-    overwrite: bool,
+    target: &Source,
+    args: &LinkArgs,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+) -> anyhow::Result<Action> {
+    if skip_missing_optional(location, target, args.verbose) {
+        return Ok(Action::Skipped);
+    }
+    let parent = match location.parent() {
+        Some(parent) => parent,
+        None => bail!("The root directory is not a valid link path"),
+    };
+    let overwrite = Overwrite::new(args.force, args.backup_dir.as_deref());
+    create_link(
+        location,
+        &target.path,
+        parent,
+        overwrite,
+        owner,
+        mode,
+        args.verbose,
+    )
+}
+
+/// Creates a single declared copy, see [`create_copy`].
+fn create_declared_copy(
+    copy: &Path,
+    original: &Source,
+    args: &CopyArgs,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+) -> anyhow::Result<Action> {
+    if skip_missing_optional(copy, original, args.verbose) {
+        return Ok(Action::Skipped);
+    }
+    let parent = match copy.parent() {
+        Some(parent) => parent,
+        None => bail!("The root directory is not a valid copy path"),
+    };
+    let overwrite = Overwrite::new(args.force, args.backup_dir.as_deref());
+    create_copy(
+        copy,
+        &original.path,
+        parent,
+        overwrite,
+        owner,
+        mode,
+        args.verbose,
+    )
+}
+
+/// Creates a single declared copy, see [`create_copy`], unless `cache` shows it is up to date.
+///
+/// Outside of a dry run, copies that are up to date afterwards are recorded in `cache`.
+fn create_cached_copy(
+    copy: &Path,
+    original: &Source,
+    args: &CopyArgs,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+    cache: &mut CopyCache,
+) -> anyhow::Result<Action> {
+    if cache.is_up_to_date(copy, &original.path) {
+        if args.verbose {
+            info!("{:?} already exists", copy);
+        }
+        return Ok(Action::Unchanged);
+    }
+    let result = create_declared_copy(copy, original, args, owner, mode);
+    match result {
+        Ok(Action::Created | Action::Updated | Action::Unchanged) if !mode.is_dry_run() => {
+            if let Err(err) = cache.record(copy, &original.path) {
+                warn!("failed to record {:?} in the copy cache: {}", copy, err);
+            }
+        }
+        _ => cache.forget(copy),
+    }
+    result
+}
+
+/// A progress indicator for processing declared links or copies.
+///
+/// The indicator is a single line on `stderr` that is redrawn after every processed path, it is
+/// only shown if `stderr` is a terminal and per-path messages are not enabled. It must be cleared
+/// before printing anything else.
+struct Progress {
+    /// What is being done to every path, e.g. `linking`.
+    action: &'static str,
+    /// The number of paths processed so far.
+    done: usize,
+    /// The number of paths to process.
+    total: usize,
+    /// Whether the indicator is shown at all.
+    enabled: bool,
+    /// Whether the indicator is currently drawn.
+    drawn: bool,
+}
+
+impl Progress {
+    fn new(action: &'static str, total: usize, verbose: bool) -> Self {
+        Self {
+            action,
+            done: 0,
+            total,
+            enabled: !verbose && io::stderr().is_terminal(),
+            drawn: false,
+        }
+    }
+
+    /// Redraws the indicator to show that `path` was processed.
+    fn advance(&mut self, path: &Path) {
+        self.done += 1;
+        if self.enabled {
+            let mut stderr = io::stderr().lock();
+            let _ = write!(
+                stderr,
+                "\r\x1b[K[{}/{}] {} {}",
+                self.done,
+                self.total,
+                self.action,
+                path.display(),
+            );
+            let _ = stderr.flush();
+            self.drawn = true;
+        }
+    }
+
+    /// Erases the indicator, if it is drawn.
+    fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[K");
+            self.drawn = false;
+        }
+    }
+}
+
+/// Removes the entries of `paths` whose required package is not installed, returning them as
+/// skipped outcomes.
+///
+/// An unmet requirement is not an error, the entry is only skipped with a message. All required
+/// packages are checked with a single query.
+fn skip_unmet_requirements(
+    backend: &dyn PackageBackend,
+    paths: IndexMap<PathBuf, Source>,
+) -> anyhow::Result<(IndexMap<PathBuf, Source>, Vec<Outcome>)> {
+    let required: IndexSet<&str> = paths
+        .values()
+        .filter_map(|source| source.requires.as_deref())
+        .collect();
+    if required.is_empty() {
+        return Ok((paths, Vec::new()));
+    }
+    let required: Vec<_> = required.into_iter().collect();
+    let installed = backend
+        .installed(&required)
+        .context("Failed to check which required packages are installed")?;
+
+    let mut skipped = Vec::new();
+    let paths = paths
+        .into_iter()
+        .filter(|(location, source)| match &source.requires {
+            Some(package) if !installed.contains(package) => {
+                info!(
+                    "Skipping {:?}, it requires package {:?}, which is not installed",
+                    location, package,
+                );
+                skipped.push(Outcome::new(location, source, Ok(Action::Skipped)));
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    Ok((paths, skipped))
+}
+
+/// Returns `true` if `source` is optional and does not exist, so `location` should be skipped.
+fn skip_missing_optional(location: &Path, source: &Source, verbose: bool) -> bool {
+    let skip = source.optional && !source.path.exists();
+    if skip && verbose {
+        info!("Skipping {:?}, {:?} does not exist", location, source.path);
+    }
+    skip
+}
+
+/// What was done to a declared link or copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// Nothing existed at its location, so it was created.
+    Created,
+    /// Something different existed at its location and was replaced.
+    Updated,
+    /// It already existed.
+    Unchanged,
+    /// Something different existed at its location and was left alone, or the source was optional
+    /// and did not exist.
+    Skipped,
+    /// It could not be processed.
+    Error,
+}
+
+impl Action {
+    /// Returns `true` if the filesystem was (or, in a dry run, would be) changed.
+    fn is_change(self) -> bool {
+        matches!(self, Action::Created | Action::Updated)
+    }
+}
+
+/// The outcome of processing a single declared link or copy, printed with `--format json`.
+#[derive(Debug, Serialize)]
+struct Outcome {
+    /// The location of the link or copy.
+    location: PathBuf,
+    /// The target of the link or the original file of the copy.
+    target: PathBuf,
+    /// What was done.
+    action: Action,
+    /// The error message, if it could not be processed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Outcome {
+    /// Converts the `result` of processing the link or copy at `location`, printing the error if
+    /// there is one.
+    fn new(location: &Path, source: &Source, result: anyhow::Result<Action>) -> Self {
+        let (action, error) = match result {
+            Ok(action) => (action, None),
+            Err(err) => {
+                error!("{:#}", err);
+                (Action::Error, Some(format!("{:#}", err)))
+            }
+        };
+        Self {
+            location: location.to_owned(),
+            target: source.path.clone(),
+            action,
+            error,
+        }
+    }
+}
+
+/// Prints how many of the declared links or copies were already up to date.
+///
+/// They are only listed one by one with `--verbose`, this way they are accounted for without it.
+/// `singular` and `plural` describe a single link or copy and multiple ones.
+fn print_unchanged_count(outcomes: &[Outcome], singular: &str, plural: &str) {
+    let unchanged = outcomes
+        .iter()
+        .filter(|outcome| outcome.action == Action::Unchanged)
+        .count();
+    if unchanged > 0 {
+        let what = if unchanged == 1 { singular } else { plural };
+        info!("({} {} unchanged)", unchanged, what);
+    }
+}
+
+/// Fails if the output `format` is not supported by this build of archman.
+fn check_output_format(format: OutputFormat) -> anyhow::Result<()> {
+    if format == OutputFormat::Json && !cfg!(feature = "json") {
+        bail!("Support for JSON output was not enabled when archman was built");
+    }
+    Ok(())
+}
+
+/// Prints `outcomes` to `stdout` in the given `format`.
+///
+/// Nothing is printed in the text format, every outcome was already reported with a message.
+fn print_outcomes(
+    outcomes: &[Outcome],
+    format: OutputFormat,
+    report: &mut RunReport,
 ) -> anyhow::Result<()> {
+    if format == OutputFormat::Text {
+        return Ok(());
+    }
+    println!("{}", outcomes_to_json(outcomes)?);
+    report.structured_output = true;
+    Ok(())
+}
+
+/// The JSON document printed with `--format json`.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct OutcomesDocument<'a> {
+    /// See [`crate::report::JSON_SCHEMA_VERSION`].
+    schema_version: u32,
+    outcomes: &'a [Outcome],
+}
+
+/// Serializes `outcomes` as a JSON object with the schema version and an array of the outcomes.
+#[cfg(feature = "json")]
+fn outcomes_to_json(outcomes: &[Outcome]) -> anyhow::Result<String> {
+    let document = OutcomesDocument {
+        schema_version: crate::report::JSON_SCHEMA_VERSION,
+        outcomes,
+    };
+    serde_json::to_string_pretty(&document).context("Failed to serialize the outcomes as JSON")
+}
+
+#[cfg(not(feature = "json"))]
+fn outcomes_to_json(_: &[Outcome]) -> anyhow::Result<String> {
+    unreachable!("checked by `check_output_format`")
+}
+
+/// The state of a declared link on the filesystem.
+#[derive(Debug, PartialEq, Eq)]
+enum LinkState {
+    /// Nothing exists at the location of the link.
+    Missing,
+    /// The link exists and points to the expected target.
+    Correct,
+    /// The link exists, but points to a different target.
+    WrongTarget(PathBuf),
+    /// Something other than a link exists at the location of the link.
+    NotASymlink,
+    /// The link points to the expected target, but the target does not exist.
+    Dangling,
+}
+
+impl LinkState {
+    /// Returns a short description of the state.
+    fn label(&self) -> &'static str {
+        match self {
+            LinkState::Missing => "missing link",
+            LinkState::Correct => "ok",
+            LinkState::WrongTarget(_) => "wrong target",
+            LinkState::NotASymlink => "not a link",
+            LinkState::Dangling => "dangling",
+        }
+    }
+}
+
+/// The state of a declared copy on the filesystem.
+#[derive(Debug, PartialEq, Eq)]
+enum CopyState {
+    /// Nothing exists at the location of the copy.
+    Missing,
+    /// The copy exists and has the same contents as the original.
+    Correct,
+    /// The copy exists, but its contents differ from the original.
+    Different,
+    /// Something other than a regular file exists at the location of the copy.
+    NotAFile,
+}
+
+/// Classifies the file at `location` by comparing it to a link to `target`.
+fn classify_link(location: &Path, target: &Path) -> anyhow::Result<LinkState> {
     match location.symlink_metadata() {
         Ok(metadata) if metadata.file_type().is_symlink() => {
             let old_target = location
                 .read_link()
                 .with_context(|| format!("Failed to read the target of link {:?}", location))?;
-            if old_target == target {
-                info!("{:?} already exists", location);
-            } else if overwrite {
-                fs::remove_file(location)
-                    .with_context(|| format!("Failed to remove {:?}", location))?;
-                unix::fs::symlink(target, location)
-                    .with_context(|| format!("Failed to create {:?}", location))?;
-                info!("Created link {:?} -> {:?}", location, target);
+            if old_target != target {
+                Ok(LinkState::WrongTarget(old_target))
+            } else if location.metadata().is_err() {
+                Ok(LinkState::Dangling)
             } else {
-                warn!(
-                    "{:?} already exists, but its target is {:?}, (expected {:?})",
-                    location, old_target, target,
-                );
+                Ok(LinkState::Correct)
             }
         }
-        Ok(_) => warn!("{:?} already exists, but isn't a link", location),
-        Err(err) if err.kind() == ErrorKind::NotFound => {
-            fs::create_dir_all(parent).with_context(|| {
-                format!("Failed to create the parent directory of {:?}", location)
-            })?;
-            unix::fs::symlink(target, location)
-                .with_context(|| format!("Failed to create {:?}", location))?;
-            info!("Created link {:?} -> {:?}", location, target);
+        Ok(_) => Ok(LinkState::NotASymlink),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(LinkState::Missing),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to query for metadata of file {:?}", location))
         }
-        Err(err) => Err(err)
-            .with_context(|| format!("Failed to query for metadata of file {:?}", location))?,
     }
-    Ok(())
 }
 
-fn create_copy(copy: &Path, original: &Path, parent: &Path, overwrite: bool) -> anyhow::Result<()> {
+/// Classifies the file at `copy` by comparing it to a copy of `original`.
+fn classify_copy(copy: &Path, original: &Path) -> anyhow::Result<CopyState> {
     match copy.symlink_metadata() {
         Ok(metadata) if metadata.file_type().is_file() => {
             let original_contents = fs::read(original)
@@ -90,29 +854,629 @@ fn create_copy(copy: &Path, original: &Path, parent: &Path, overwrite: bool) ->
             let dest_contents = fs::read(copy)
                 .with_context(|| format!("Failed to read the contents of {:?}", copy))?;
             if original_contents == dest_contents {
-                info!("{:?} already exists", copy);
-            } else if overwrite {
-                fs::copy(original, copy)
-                    .with_context(|| format!("Failed to copy {:?} to {:?}", original, copy))?;
-                info!("Copied {:?} -> {:?}", original, copy);
+                Ok(CopyState::Correct)
             } else {
-                warn!(
-                    "{:?} already exists, but is different from {:?}",
-                    copy, original,
-                );
+                Ok(CopyState::Different)
             }
         }
-        Ok(_) => warn!("{:?} already exists, but isn't a regular file", copy),
-        Err(err) if err.kind() == ErrorKind::NotFound => {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create the parent directory of {:?}", copy))?;
+        Ok(_) => Ok(CopyState::NotAFile),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(CopyState::Missing),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to query for metadata of file {:?}", copy))
+        }
+    }
+}
+
+/// Prints the state of every declared link, aligned into columns.
+fn print_link_stats(links: &IndexMap<PathBuf, Source>) {
+    let mut rows = Vec::with_capacity(links.len());
+    for (location, Source { path: target, .. }) in links {
+        match classify_link(location, target) {
+            Ok(state) => {
+                let current = match &state {
+                    LinkState::WrongTarget(current) => current.display().to_string(),
+                    LinkState::Correct | LinkState::Dangling => target.display().to_string(),
+                    LinkState::Missing | LinkState::NotASymlink => String::from("-"),
+                };
+                rows.push([
+                    state.label().to_owned(),
+                    location.display().to_string(),
+                    current,
+                    target.display().to_string(),
+                ]);
+            }
+            Err(err) => error!("{:#}", err),
+        }
+    }
+
+    let header = ["STATUS", "LINK", "CURRENT TARGET", "EXPECTED TARGET"].map(String::from);
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+}
+
+/// What to do with an existing link or copy that differs from the declared one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overwrite<'a> {
+    /// Leave it alone and warn about it.
+    Never,
+    /// Replace it.
+    Replace,
+    /// Move it to the same path under the given backup directory, then replace it.
+    Backup(&'a Path),
+}
+
+impl<'a> Overwrite<'a> {
+    fn new(force: bool, backup_dir: Option<&'a Path>) -> Self {
+        match (force, backup_dir) {
+            (false, _) => Self::Never,
+            (true, None) => Self::Replace,
+            (true, Some(backup_dir)) => Self::Backup(backup_dir),
+        }
+    }
+
+    fn is_allowed(self) -> bool {
+        self != Self::Never
+    }
+
+    /// Moves the file or link at `path` to the backup directory, if there is one.
+    ///
+    /// Returns `true` if `path` was (or, in a dry run, would be) moved.
+    fn back_up(self, path: &Path, mode: RunMode) -> anyhow::Result<bool> {
+        let backup_dir = match self {
+            Self::Backup(backup_dir) => backup_dir,
+            Self::Never | Self::Replace => return Ok(false),
+        };
+        if mode.is_dry_run() {
+            info!(
+                "Would move {:?} to {:?}",
+                path,
+                backup_path(path, backup_dir)
+            );
+        } else {
+            let backup = move_to_backup(path, backup_dir)
+                .with_context(|| format!("Failed to back up {:?}", path))?;
+            info!("Moved {:?} to {:?}", path, backup);
+        }
+        Ok(true)
+    }
+}
+
+/// Returns the path that the file at `path` is moved to when it is backed up into `backup_dir`.
+///
+/// This is `path` relative to the root directory, under `backup_dir`, so that backups of files in
+/// different directories do not collide.
+fn backup_path(path: &Path, backup_dir: &Path) -> PathBuf {
+    backup_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Moves the file or link at `path` into `backup_dir`, see [`backup_path`], replacing an older
+/// backup of the same path.
+///
+/// Returns the path of the backup.
+fn move_to_backup(path: &Path, backup_dir: &Path) -> io::Result<PathBuf> {
+    let backup = backup_path(path, backup_dir);
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(path, &backup) {
+        Ok(()) => {}
+        // files cannot be renamed across filesystems, copy them instead
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            let _ = fs::remove_file(&backup);
+            if path.symlink_metadata()?.file_type().is_symlink() {
+                unix::fs::symlink(fs::read_link(path)?, &backup)?;
+            } else {
+                fs::copy(path, &backup)?;
+            }
+            fs::remove_file(path)?;
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(backup)
+}
+
+/// Creates a link at `location` pointing to `target`.
+///
+/// Returns what was (or, in a dry run, would be) done to the link.
+fn create_link(
+    location: &Path,
+    target: &Path,
+    parent: &Path,
+    overwrite: Overwrite<'_>,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+    verbose: bool,
+) -> anyhow::Result<Action> {
+    match classify_link(location, target)? {
+        LinkState::Correct | LinkState::Dangling => {
+            if verbose {
+                info!("{:?} already exists", location);
+            }
+            Ok(Action::Unchanged)
+        }
+        LinkState::WrongTarget(_) if overwrite.is_allowed() && mode.is_dry_run() => {
+            overwrite.back_up(location, mode)?;
+            info!("Would replace link {:?} -> {:?}", location, target);
+            Ok(Action::Updated)
+        }
+        LinkState::WrongTarget(_) if overwrite.is_allowed() => {
+            if !overwrite.back_up(location, mode)? {
+                fs::remove_file(location)
+                    .with_context(|| format!("Failed to remove {:?}", location))?;
+            }
+            unix::fs::symlink(target, location)
+                .with_context(|| format!("Failed to create {:?}", location))?;
+            set_owner(location, owner)?;
+            info!("Created link {:?} -> {:?}", location, target);
+            Ok(Action::Updated)
+        }
+        LinkState::WrongTarget(old_target) => {
+            warn!(
+                "{:?} already exists, but its target is {:?}, (expected {:?})",
+                location, old_target, target,
+            );
+            Ok(Action::Skipped)
+        }
+        LinkState::NotASymlink => {
+            warn!("{:?} already exists, but isn't a link", location);
+            Ok(Action::Skipped)
+        }
+        LinkState::Missing if mode.is_dry_run() => {
+            info!("Would create link {:?} -> {:?}", location, target);
+            Ok(Action::Created)
+        }
+        LinkState::Missing => {
+            create_parent(parent, owner).with_context(|| {
+                format!("Failed to create the parent directory of {:?}", location)
+            })?;
+            unix::fs::symlink(target, location)
+                .with_context(|| format!("Failed to create {:?}", location))?;
+            set_owner(location, owner)?;
+            info!("Created link {:?} -> {:?}", location, target);
+            Ok(Action::Created)
+        }
+    }
+}
+
+/// Creates a copy of `original` at `copy`.
+///
+/// Returns what was (or, in a dry run, would be) done to the copy.
+fn create_copy(
+    copy: &Path,
+    original: &Path,
+    parent: &Path,
+    overwrite: Overwrite<'_>,
+    owner: Option<&OsStr>,
+    mode: RunMode,
+    verbose: bool,
+) -> anyhow::Result<Action> {
+    match classify_copy(copy, original)? {
+        CopyState::Correct => {
+            if verbose {
+                info!("{:?} already exists", copy);
+            }
+            Ok(Action::Unchanged)
+        }
+        CopyState::Different if overwrite.is_allowed() && mode.is_dry_run() => {
+            overwrite.back_up(copy, mode)?;
+            info!("Would copy {:?} -> {:?}", original, copy);
+            Ok(Action::Updated)
+        }
+        CopyState::Different if overwrite.is_allowed() => {
+            overwrite.back_up(copy, mode)?;
             fs::copy(original, copy)
                 .with_context(|| format!("Failed to copy {:?} to {:?}", original, copy))?;
+            set_owner(copy, owner)?;
             info!("Copied {:?} -> {:?}", original, copy);
+            Ok(Action::Updated)
         }
-        Err(err) => {
-            Err(err).with_context(|| format!("Failed to query for metadata of file {:?}", copy))?
+        CopyState::Different => {
+            warn!(
+                "{:?} already exists, but is different from {:?}",
+                copy, original,
+            );
+            Ok(Action::Skipped)
+        }
+        CopyState::NotAFile => {
+            warn!("{:?} already exists, but isn't a regular file", copy);
+            Ok(Action::Skipped)
+        }
+        CopyState::Missing if mode.is_dry_run() => {
+            info!("Would copy {:?} -> {:?}", original, copy);
+            Ok(Action::Created)
+        }
+        CopyState::Missing => {
+            create_parent(parent, owner)
+                .with_context(|| format!("Failed to create the parent directory of {:?}", copy))?;
+            fs::copy(original, copy)
+                .with_context(|| format!("Failed to copy {:?} to {:?}", original, copy))?;
+            set_owner(copy, owner)?;
+            info!("Copied {:?} -> {:?}", original, copy);
+            Ok(Action::Created)
         }
     }
+}
+
+/// Fails if the owner of created files should be changed, but that is not possible.
+fn check_can_change_owner(owner: Option<&OsStr>, mode: RunMode) -> anyhow::Result<()> {
+    if owner.is_none() || mode.is_dry_run() {
+        return Ok(());
+    }
+    if mode.is_unprivileged() {
+        bail!("Refusing to change the owner of files because of --no-sudo");
+    }
+    let uid = fs::metadata("/proc/self")
+        .context("Failed to determine the user running this process")?
+        .uid();
+    if uid != 0 {
+        bail!("Changing the owner of files with --target-user requires root privileges");
+    }
     Ok(())
 }
+
+/// Creates the directory `path` and all of its missing parents, owned by `owner` if given.
+fn create_parent(path: &Path, owner: Option<&OsStr>) -> anyhow::Result<()> {
+    let first_missing = path
+        .ancestors()
+        .take_while(|ancestor| ancestor.symlink_metadata().is_err())
+        .last();
+    fs::create_dir_all(path)?;
+    match first_missing {
+        Some(first_missing) => set_owner(first_missing, owner),
+        None => Ok(()),
+    }
+}
+
+/// Recursively changes the owner of `path` to `owner`, without following symbolic links.
+fn set_owner(path: &Path, owner: Option<&OsStr>) -> anyhow::Result<()> {
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    let mut user_and_group = owner.to_owned();
+    user_and_group.push(":");
+    let status = Command::new("chown")
+        .args(["--no-dereference", "--recursive"])
+        .arg(user_and_group)
+        .arg("--")
+        .arg(path)
+        .status()
+        .context("Failed to execute chown")?;
+    if !status.success() {
+        bail!("Failed to change the owner of {:?} to {:?}", path, owner);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty scratch directory unique to the test `name`.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("archman-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn classify_links() {
+        let dir = scratch_dir("classify-links");
+        let target = dir.join("target");
+        fs::write(&target, "contents").unwrap();
+
+        let location = dir.join("link");
+        assert_eq!(
+            classify_link(&location, &target).unwrap(),
+            LinkState::Missing
+        );
+        unix::fs::symlink(&target, &location).unwrap();
+        assert_eq!(
+            classify_link(&location, &target).unwrap(),
+            LinkState::Correct
+        );
+        let other = dir.join("other");
+        assert_eq!(
+            classify_link(&location, &other).unwrap(),
+            LinkState::WrongTarget(target.clone()),
+        );
+        assert_eq!(
+            classify_link(&target, &other).unwrap(),
+            LinkState::NotASymlink
+        );
+        fs::remove_file(&target).unwrap();
+        assert_eq!(
+            classify_link(&location, &target).unwrap(),
+            LinkState::Dangling
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn classify_copies() {
+        let dir = scratch_dir("classify-copies");
+        let original = dir.join("original");
+        fs::write(&original, "contents").unwrap();
+
+        let copy = dir.join("copy");
+        assert_eq!(classify_copy(&copy, &original).unwrap(), CopyState::Missing);
+        fs::write(&copy, "contents").unwrap();
+        assert_eq!(classify_copy(&copy, &original).unwrap(), CopyState::Correct);
+        fs::write(&copy, "other contents").unwrap();
+        assert_eq!(
+            classify_copy(&copy, &original).unwrap(),
+            CopyState::Different
+        );
+        assert_eq!(classify_copy(&dir, &original).unwrap(), CopyState::NotAFile);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn outcomes_of_creating_links() {
+        let dir = scratch_dir("outcomes-of-creating-links");
+        let target = dir.join("target");
+        let other = dir.join("other");
+        fs::write(&target, "contents").unwrap();
+        let location = dir.join("link");
+        let create = |target: &Path, overwrite: bool| {
+            create_link(
+                &location,
+                target,
+                &dir,
+                Overwrite::new(overwrite, None),
+                None,
+                RunMode::Normal,
+                false,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(create(&target, false), Action::Created);
+        assert_eq!(create(&target, false), Action::Unchanged);
+        assert_eq!(create(&other, false), Action::Skipped);
+        assert_eq!(create(&other, true), Action::Updated);
+        assert_eq!(location.read_link().unwrap(), other);
+
+        let source = Source {
+            path: target.clone(),
+            optional: false,
+            requires: None,
+        };
+        let error = Outcome::new(&location, &source, Err(anyhow::anyhow!("Failed")));
+        assert_eq!(error.action, Action::Error);
+        #[cfg(feature = "json")]
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "location": location,
+                "target": target,
+                "action": "error",
+                "error": "Failed",
+            }),
+        );
+        #[cfg(feature = "json")]
+        {
+            let json = outcomes_to_json(std::slice::from_ref(&error)).unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+                serde_json::json!({
+                    "schema_version": crate::report::JSON_SCHEMA_VERSION,
+                    "outcomes": [serde_json::to_value(&error).unwrap()],
+                }),
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn back_up_replaced_files() {
+        let dir = scratch_dir("back-up-replaced-files");
+        let backup_dir = dir.join("backup");
+        let original = dir.join("original");
+        fs::write(&original, "new").unwrap();
+        let copy = dir.join("config/copy");
+        fs::create_dir_all(copy.parent().unwrap()).unwrap();
+        fs::write(&copy, "old").unwrap();
+        let link = dir.join("link");
+        unix::fs::symlink(&copy, &link).unwrap();
+
+        let overwrite = Overwrite::new(true, Some(&backup_dir));
+        let action = create_copy(
+            &copy,
+            &original,
+            &dir,
+            overwrite,
+            None,
+            RunMode::DryRun,
+            false,
+        );
+        assert_eq!(action.unwrap(), Action::Updated);
+        assert!(!backup_dir.exists());
+
+        let action = create_copy(
+            &copy,
+            &original,
+            &dir,
+            overwrite,
+            None,
+            RunMode::Normal,
+            false,
+        );
+        assert_eq!(action.unwrap(), Action::Updated);
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "new");
+        let copy_backup = backup_path(&copy, &backup_dir);
+        assert!(copy_backup.ends_with("config/copy"));
+        assert_eq!(fs::read_to_string(copy_backup).unwrap(), "old");
+
+        let action = create_link(
+            &link,
+            &original,
+            &dir,
+            overwrite,
+            None,
+            RunMode::Normal,
+            false,
+        );
+        assert_eq!(action.unwrap(), Action::Updated);
+        assert_eq!(link.read_link().unwrap(), original);
+        let link_backup = backup_path(&link, &backup_dir);
+        assert_eq!(link_backup.read_link().unwrap(), copy);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_orphaned_links() {
+        let dir = scratch_dir("find-orphaned-links");
+        let repo = dir.join("repo");
+        let home = dir.join("home");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        for name in ["declared", "removed", "relative"] {
+            fs::write(repo.join(name), "contents").unwrap();
+        }
+        unix::fs::symlink(repo.join("declared"), home.join("declared")).unwrap();
+        unix::fs::symlink(repo.join("removed"), home.join("removed")).unwrap();
+        unix::fs::symlink("../repo/relative", home.join("relative")).unwrap();
+        unix::fs::symlink(&dir, home.join("elsewhere")).unwrap();
+        fs::write(home.join("file"), "contents").unwrap();
+
+        let source = Source {
+            path: repo.join("declared"),
+            optional: false,
+            requires: None,
+        };
+        let links = [(home.join("declared"), source)].into_iter().collect();
+        assert_eq!(
+            find_orphans(&links, &repo),
+            [
+                (home.join("relative"), PathBuf::from("../repo/relative")),
+                (home.join("removed"), repo.join("removed")),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_copies_after_reload() {
+        let dir = scratch_dir("update-copies-after-reload");
+        let source = |name: &str| {
+            let path = dir.join(format!("original-{}", name));
+            fs::write(&path, name).unwrap();
+            Source {
+                path,
+                optional: false,
+                requires: None,
+            }
+        };
+        let kept = dir.join("kept");
+        let added = dir.join("added");
+        let removed = dir.join("removed");
+        let changed = dir.join("changed");
+        fs::write(&kept, "kept").unwrap();
+        fs::write(&removed, "removed").unwrap();
+        fs::write(&changed, "user changes").unwrap();
+
+        let watch = |copies: Vec<(&PathBuf, Source)>| -> IndexMap<_, _> {
+            copies
+                .into_iter()
+                .map(|(copy, original)| {
+                    let file = WatchedFile::new(&original.path);
+                    (copy.clone(), (original, file))
+                })
+                .collect()
+        };
+        let mut watched = watch(vec![
+            (&kept, source("kept")),
+            (&removed, source("removed")),
+            (&changed, source("changed")),
+        ]);
+        let declared = [
+            (kept.clone(), source("kept")),
+            (added.clone(), source("added")),
+        ];
+        let args = CopyArgs {
+            force: false,
+            backup_dir: None,
+            verbose: false,
+            watch: true,
+            prune: true,
+            prefix: None,
+            format: OutputFormat::Text,
+        };
+        let mut report = RunReport::default();
+        update_watched_copies(
+            &mut watched,
+            declared.into_iter().collect(),
+            &args,
+            None,
+            RunMode::Normal,
+            &mut report,
+        );
+
+        let watched: Vec<_> = watched.keys().collect();
+        assert_eq!(watched, [&kept, &added]);
+        assert_eq!(fs::read_to_string(&added).unwrap(), "added");
+        assert_eq!(report.copies, [added]);
+        assert!(!removed.exists());
+        assert_eq!(report.removed_copies, [removed]);
+        assert_eq!(fs::read_to_string(&changed).unwrap(), "user changes");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reroot_locations() {
+        let paths = || -> IndexMap<_, _> {
+            [
+                ("/etc/foo", "/cfg/foo"),
+                ("/home/user/.bashrc", "/cfg/bashrc"),
+            ]
+            .into_iter()
+            .map(|(location, source)| {
+                let source = Source {
+                    path: PathBuf::from(source),
+                    optional: false,
+                    requires: None,
+                };
+                (PathBuf::from(location), source)
+            })
+            .collect()
+        };
+        let pairs = |paths: IndexMap<PathBuf, Source>| -> Vec<_> {
+            paths
+                .into_iter()
+                .map(|(location, source)| (location, source.path))
+                .collect()
+        };
+        assert_eq!(pairs(reroot(paths(), None)), pairs(paths()));
+        assert_eq!(
+            pairs(reroot(paths(), Some(Path::new("/tmp/image")))),
+            [
+                ("/tmp/image/etc/foo".into(), "/cfg/foo".into()),
+                ("/tmp/image/home/user/.bashrc".into(), "/cfg/bashrc".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Preventing concurrent runs of archman that change the system.
+//!
+//! Two runs could race on the pacman database or on the files they write, so subcommands that
+//! change the system first take an exclusive advisory lock on a file, see [`Lock`]. Subcommands
+//! that only read the system never take it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+/// An error from taking the lock.
+#[derive(Debug, Error)]
+pub enum LockError {
+    /// Another process holds the lock.
+    #[error("Another archman instance is running, it holds the lock on {0:?}")]
+    Held(PathBuf),
+    /// The lock file could not be created or locked.
+    #[error("Failed to lock {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// An exclusive advisory lock on a file, held until it is dropped.
+///
+/// The lock is taken with `flock`, so it is also released if the process exits without dropping
+/// it. The lock file itself is left in place.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Takes the lock on the file at `path`, creating the file and its parent directories if they
+    /// do not exist.
+    ///
+    /// Does not wait for the lock, fails with [`LockError::Held`] if another process holds it.
+    pub fn acquire(path: &Path) -> Result<Self, LockError> {
+        let io_error = |source| LockError::Io {
+            path: path.to_owned(),
+            source,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io_error)?;
+        // SAFETY: the file descriptor stays valid for as long as `file` is open.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.kind() {
+                ErrorKind::WouldBlock => LockError::Held(path.to_owned()),
+                _ => io_error(err),
+            });
+        }
+        Ok(Self { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // closing the file releases the lock too, but not if the descriptor was inherited by a
+        // child process that is still running
+        // SAFETY: the file descriptor stays valid for as long as `self.file` is open.
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive() {
+        let dir = std::env::temp_dir().join(format!("archman-lock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("state/lock");
+
+        let lock = Lock::acquire(&path).unwrap();
+        // every `open` creates a separate lock, even in the same process
+        assert!(matches!(Lock::acquire(&path), Err(LockError::Held(held)) if held == path));
+        drop(lock);
+        Lock::acquire(&path).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,26 +1,54 @@
 use anstyle::AnsiColor;
 use clap::Parser;
-use is_terminal::IsTerminal;
 
 fn main() -> ! {
     let args = Parser::parse();
     let exit_code = match archman::run(args) {
-        Ok(()) => 0,
+        Ok(report) => {
+            if !report.is_empty() {
+                let prefix = if report.dry_run {
+                    "Would make changes"
+                } else {
+                    "Changes"
+                };
+                if report.structured_output {
+                    eprintln!("\n{}: {}", prefix, report);
+                } else {
+                    println!("\n{}: {}", prefix, report);
+                }
+            }
+            if !report.reboot_reasons.is_empty() {
+                let style = if archman::use_style(&std::io::stdout()) {
+                    AnsiColor::Yellow.on_default().bold()
+                } else {
+                    Default::default()
+                };
+                println!(
+                    "\n{}Reboot recommended:{} {}",
+                    style.render(),
+                    style.render_reset(),
+                    report.reboot_reasons.join("; "),
+                );
+            }
+            0
+        }
         Err(err) => {
-            let is_tty = std::io::stderr().is_terminal();
-            let style = if is_tty {
+            let style = if archman::use_style(&std::io::stderr()) {
                 AnsiColor::Red.on_default().bold()
             } else {
                 Default::default()
             };
             eprintln!(
-                "\n{}{}{} {:?}",
+                "\n{}error:{} {:?}",
                 style.render(),
-                "error:",
                 style.render_reset(),
                 err,
             );
-            1
+            if err.is::<archman::Drift>() {
+                archman::Drift::EXIT_CODE
+            } else {
+                1
+            }
         }
     };
     std::process::exit(exit_code)
@@ -1,8 +1,21 @@
 //! Gathering information about declared and installed packages.
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    panic,
+    path::Path,
+    thread,
+};
 
-use crate::pacman::{self, InstallReason, QueryFilter};
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indexmap::IndexSet;
+
+use crate::{
+    backend::{BackendError, InstallReason, PackageBackend, QueryFilter},
+    config::FlattenedSet,
+    pacman, util,
+};
 
 /// Packages currently installed on our system.
 #[derive(Debug)]
@@ -10,6 +23,10 @@ pub struct InstalledPackages {
     pub explicit: HashSet<String>,
     pub dependencies: HashSet<String>,
     pub unneeded: HashSet<String>,
+    /// Explicitly installed packages that are not in any package database, e.g. built manually.
+    ///
+    /// These are not included in the other sets.
+    pub foreign: HashSet<String>,
 }
 
 /// Packages organized by what we should do with them.
@@ -19,47 +36,269 @@ pub struct OrganizedPackages<'a> {
     pub to_mark_as_explicit: Vec<&'a str>,
     pub to_remove: Vec<&'a str>,
     pub unneeded: Vec<&'a str>,
+    /// Packages that would belong to one of the other categories, but are ignored by `pacman`.
+    pub ignored: Vec<&'a str>,
+    /// Packages protected from removal by a glob pattern, along with the first matching pattern.
+    ///
+    /// These packages are also included in `ignored`.
+    pub protected: Vec<(&'a str, &'a str)>,
+    /// Foreign packages that are not declared, these are never removed.
+    pub foreign: Vec<&'a str>,
+}
+
+/// Packages that should never be acted upon, given either by name or by a glob pattern.
+///
+/// Patterns are only matched against packages that would be removed.
+#[derive(Debug)]
+pub struct IgnoredPackages {
+    /// Exact names of packages.
+    names: HashSet<String>,
+    /// Glob patterns, in the same order as in `globs`.
+    patterns: Vec<String>,
+    /// Compiled glob patterns.
+    globs: GlobSet,
 }
 
 #[derive(Debug)]
 pub struct MergedPackages<'a> {
     pub packages: HashSet<&'a str>,
+    /// Declared glob patterns, these are not included in `packages`.
+    pub patterns: Vec<&'a str>,
     pub duplicates: HashMap<&'a str, &'a str>,
 }
 
+impl IgnoredPackages {
+    /// Splits `entries` into exact package names and glob patterns.
+    ///
+    /// Entries are treated as glob patterns if [`is_pattern`] says so, like in `IgnorePkg`.
+    pub fn new(entries: HashSet<String>) -> anyhow::Result<Self> {
+        let mut names = HashSet::new();
+        let mut patterns = Vec::new();
+        for entry in entries {
+            if is_pattern(&entry) {
+                patterns.push(entry);
+            } else {
+                names.insert(entry);
+            }
+        }
+        patterns.sort_unstable();
+        let globs = compile_patterns(&patterns)?;
+
+        Ok(Self {
+            names,
+            patterns,
+            globs,
+        })
+    }
+
+    /// Returns `true` if `package` is ignored by name.
+    fn contains(&self, package: &str) -> bool {
+        self.names.contains(package)
+    }
+
+    /// Returns `true` if `package` is ignored by name or matches any of the patterns.
+    fn matches(&self, package: &str) -> bool {
+        self.contains(package) || self.globs.is_match(package)
+    }
+
+    /// Returns the first pattern that matches `package`, if any.
+    fn matching_pattern(&self, package: &str) -> Option<&str> {
+        let index = *self.globs.matches(package).first()?;
+        Some(&self.patterns[index])
+    }
+}
+
+/// Returns `true` if `entry` is a glob pattern rather than a package name, i.e. if it contains `*`,
+/// `?` or `[`.
+pub fn is_pattern(entry: &str) -> bool {
+    entry.contains(['*', '?', '['])
+}
+
+/// Compiles glob patterns that match package names.
+pub fn compile_patterns(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).with_context(|| format!("Invalid package pattern {:?}", pattern))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .context("Failed to compile package patterns")
+}
+
+impl<'a> MergedPackages<'a> {
+    /// Adds every package in `candidates` that matches any of the declared patterns to the
+    /// declared packages.
+    pub fn expand_patterns<I>(&mut self, candidates: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        if self.patterns.is_empty() {
+            return Ok(());
+        }
+        let patterns: Vec<_> = self.patterns.iter().map(|&p| p.to_owned()).collect();
+        let globs = compile_patterns(&patterns)?;
+        self.packages.extend(
+            candidates
+                .into_iter()
+                .filter(|package| globs.is_match(package)),
+        );
+        Ok(())
+    }
+}
+
+impl InstalledPackages {
+    /// Returns the names of all installed packages.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.explicit
+            .iter()
+            .chain(&self.dependencies)
+            .chain(&self.foreign)
+            .map(String::as_str)
+    }
+}
+
+impl OrganizedPackages<'_> {
+    /// Returns `true` if there is nothing to do with any of the packages.
+    ///
+    /// Ignored packages are not taken into account.
+    pub fn is_in_sync(&self) -> bool {
+        self.to_install.is_empty()
+            && self.to_mark_as_explicit.is_empty()
+            && self.to_remove.is_empty()
+            && self.unneeded.is_empty()
+    }
+
+    /// Removes packages matching any of the `excluded` patterns from all categories.
+    pub fn exclude(&mut self, excluded: &GlobSet) {
+        if excluded.is_empty() {
+            return;
+        }
+        self.retain(|package| !excluded.is_match(package));
+    }
+
+    /// Removes all packages other than the `named` ones from all categories.
+    pub fn restrict(&mut self, named: &HashSet<&str>) {
+        self.retain(|package| named.contains(package));
+    }
+
+    /// Keeps only the packages for which `keep` returns `true` in all categories.
+    fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.to_install.retain(|package| keep(package));
+        self.to_mark_as_explicit.retain(|package| keep(package));
+        self.to_remove.retain(|package| keep(package));
+        self.unneeded.retain(|package| keep(package));
+        self.ignored.retain(|package| keep(package));
+        self.protected.retain(|(package, _)| keep(package));
+        self.foreign.retain(|package| keep(package));
+    }
+}
+
 /// Queries for packages currently installed explicitly or as dependencies.
-pub fn query_packages() -> anyhow::Result<InstalledPackages> {
-    let explicit = pacman::query(QueryFilter {
+pub fn query_packages(backend: &dyn PackageBackend) -> anyhow::Result<InstalledPackages> {
+    let explicit = backend.query(QueryFilter {
         install_reason: Some(InstallReason::Explicit),
         ..QueryFilter::default()
     })?;
-    let dependencies = pacman::query(QueryFilter {
+    let dependencies = backend.query(QueryFilter {
         install_reason: Some(InstallReason::Dependency),
         ..QueryFilter::default()
     })?;
-    let unneeded = pacman::query(QueryFilter {
+    let unneeded = backend.query(QueryFilter {
         install_reason: Some(InstallReason::Dependency),
         unrequired: true,
         ..QueryFilter::default()
     })?;
+    let foreign = backend.query(QueryFilter {
+        install_reason: Some(InstallReason::Explicit),
+        foreign: true,
+        ..QueryFilter::default()
+    })?;
 
     Ok(InstalledPackages {
         explicit,
         dependencies,
         unneeded,
+        foreign,
+    })
+}
+
+/// Queries for installed packages and for packages that belong to the given `groups`.
+///
+/// Both queries are run in parallel.
+pub fn query_packages_and_groups<'a>(
+    backend: &dyn PackageBackend,
+    groups: &IndexSet<&'a str>,
+) -> anyhow::Result<(InstalledPackages, HashMap<String, &'a str>)> {
+    thread::scope(|scope| {
+        let installed = scope.spawn(|| query_packages(backend));
+        let group_packages = query_groups(backend, groups)
+            .context("Failed to query for packages that belong to the declared package groups");
+        let installed = installed
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+            .context("Failed to query for installed packages");
+        Ok((installed?, group_packages?))
     })
 }
 
-pub fn query_groups<'a>(groups: &HashSet<&'a str>) -> anyhow::Result<HashMap<String, &'a str>> {
-    pacman::groups(groups.iter().copied()).map_err(Into::into)
+/// Returns the declared package groups whose packages should be queried, none if `skip` is set.
+///
+/// Skipped groups are listed, since their packages are then only declared if declared directly.
+pub fn groups_to_resolve<'a>(groups: &IndexSet<&'a str>, skip: bool) -> IndexSet<&'a str> {
+    if !skip || groups.is_empty() {
+        return groups.clone();
+    }
+    let names: Vec<&str> = groups.iter().copied().collect();
+    info!(
+        "Skipping resolution of declared package groups, packages that belong to them are not \
+         counted as declared: {}",
+        names.join(", "),
+    );
+    IndexSet::new()
+}
+
+pub fn query_groups<'a>(
+    backend: &dyn PackageBackend,
+    groups: &IndexSet<&'a str>,
+) -> anyhow::Result<HashMap<String, &'a str>> {
+    let groups: Vec<_> = groups.iter().copied().collect();
+    backend.groups(&groups).map_err(Into::into)
+}
+
+/// Returns the packages that `pacman` is configured to ignore, either directly or through a group.
+pub fn query_ignored(
+    backend: &dyn PackageBackend,
+    pacman_conf: &Path,
+) -> anyhow::Result<HashSet<String>> {
+    let ignored = pacman::config::ignored(pacman_conf)
+        .context("Failed to read the pacman configuration file")?;
+    let mut packages = ignored.packages;
+    for group in &ignored.groups {
+        match backend.groups(&[group.as_str()]) {
+            Ok(group_packages) => packages.extend(group_packages.into_keys()),
+            Err(BackendError::ExitFailure(_)) => {
+                warn!("failed to query for packages in ignored group {:?}", group)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(packages)
 }
 
 /// Merges declared packages and package groups into a single set of packages.
+///
+/// Declared glob patterns are kept apart, see [`MergedPackages::expand_patterns`].
 pub fn merge_declared_packages<'a>(
-    packages: &HashSet<&'a str>,
+    packages: &IndexSet<&'a str>,
     group_packages: &'a HashMap<String, &'a str>,
 ) -> MergedPackages<'a> {
-    let mut merged_packages: HashSet<_> = packages.iter().copied().collect();
+    let (patterns, names): (Vec<&str>, Vec<&str>) = packages
+        .iter()
+        .copied()
+        .partition(|entry| is_pattern(entry));
+    let mut merged_packages: HashSet<_> = names.into_iter().collect();
     let mut duplicates = HashMap::new();
 
     for (package, group) in group_packages {
@@ -73,20 +312,52 @@ pub fn merge_declared_packages<'a>(
 
     MergedPackages {
         packages: merged_packages,
+        patterns,
         duplicates,
     }
 }
 
+/// Warns about packages that are declared more than once, either directly or through a group.
+pub fn warn_about_duplicates(declared: &FlattenedSet<&str>, merged: &MergedPackages<'_>) {
+    let duplicates: Vec<_> = declared.duplicates.iter().collect();
+    util::warn_about_all(
+        &duplicates,
+        "package",
+        "packages",
+        "declared multiple times",
+    );
+    let mut group_duplicates: Vec<_> = merged.duplicates.iter().collect();
+    group_duplicates.sort_unstable();
+    let group_duplicates: Vec<_> = group_duplicates
+        .into_iter()
+        .map(|(duplicate, group)| format!("{} (group {})", duplicate, group))
+        .collect();
+    util::warn_about_all(
+        &group_duplicates,
+        "package",
+        "packages",
+        "declared both explicitly and as a member of a group",
+    );
+}
+
 /// Organizes packages based on what we should do with them.
+///
+/// Packages in `ignored` are never acted upon, they are put in a separate category instead. The
+/// same goes for foreign packages, which we do not manage.
 pub fn organize_packages<'a>(
     declared: &HashSet<&'a str>,
     installed: &'a InstalledPackages,
+    ignored: &'a IgnoredPackages,
 ) -> OrganizedPackages<'a> {
     let mut to_install = Vec::new();
     let mut to_mark_as_explicit = Vec::new();
+    let mut ignored_packages = Vec::new();
+    let mut protected = Vec::new();
     for &package in declared {
-        if !installed.explicit.contains(package) {
-            if installed.dependencies.contains(package) {
+        if !installed.explicit.contains(package) && !installed.foreign.contains(package) {
+            if ignored.contains(package) {
+                ignored_packages.push(package);
+            } else if installed.dependencies.contains(package) {
                 to_mark_as_explicit.push(package);
             } else {
                 to_install.push(package);
@@ -94,25 +365,285 @@ pub fn organize_packages<'a>(
         }
     }
 
-    let remove_declared = |pkgs: &'a HashSet<String>| {
-        pkgs.iter()
-            .filter(|pkg| !declared.contains(pkg.as_str()))
-            .map(String::as_str)
-            .collect::<Vec<_>>()
+    let mut remove_declared = |pkgs: &'a HashSet<String>| {
+        let mut undeclared = Vec::new();
+        for pkg in pkgs.iter().filter(|pkg| !declared.contains(pkg.as_str())) {
+            if ignored.contains(pkg) {
+                ignored_packages.push(pkg.as_str());
+            } else if let Some(pattern) = ignored.matching_pattern(pkg) {
+                ignored_packages.push(pkg.as_str());
+                protected.push((pkg.as_str(), pattern));
+            } else {
+                undeclared.push(pkg.as_str());
+            }
+        }
+        undeclared
     };
     let mut to_remove = remove_declared(&installed.explicit);
     let mut unneeded = remove_declared(&installed.unneeded);
+    let mut foreign: Vec<_> = installed
+        .foreign
+        .iter()
+        .map(String::as_str)
+        .filter(|pkg| !declared.contains(pkg))
+        .collect();
 
     // sort them so that they look nicer if we print them
     to_remove.sort_unstable();
     to_install.sort_unstable();
     to_mark_as_explicit.sort_unstable();
     unneeded.sort_unstable();
+    ignored_packages.sort_unstable();
+    protected.sort_unstable();
+    foreign.sort_unstable();
 
     OrganizedPackages {
         to_install,
         to_mark_as_explicit,
         to_remove,
         unneeded,
+        ignored: ignored_packages,
+        protected,
+        foreign,
+    }
+}
+
+/// Returns the declared packages that can be reinstalled from the package databases.
+///
+/// These are the declared packages that are installed, not foreign, not ignored and not
+/// `excluded`.
+pub fn reinstall_candidates<'a>(
+    declared: &HashSet<&'a str>,
+    installed: &InstalledPackages,
+    ignored: &IgnoredPackages,
+    excluded: &GlobSet,
+) -> Vec<&'a str> {
+    let mut candidates: Vec<_> = declared
+        .iter()
+        .copied()
+        .filter(|&package| {
+            (installed.explicit.contains(package) || installed.dependencies.contains(package))
+                && !ignored.matches(package)
+                && !excluded.is_match(package)
+        })
+        .collect();
+    candidates.sort_unstable();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set<'a, T: From<&'a str> + Eq + std::hash::Hash>(items: &[&'a str]) -> HashSet<T> {
+        items.iter().map(|&item| item.into()).collect()
+    }
+
+    fn installed(explicit: &[&str], dependencies: &[&str], unneeded: &[&str]) -> InstalledPackages {
+        InstalledPackages {
+            explicit: set(explicit),
+            dependencies: set(dependencies),
+            unneeded: set(unneeded),
+            foreign: HashSet::new(),
+        }
+    }
+
+    fn no_ignored() -> IgnoredPackages {
+        IgnoredPackages::new(HashSet::new()).unwrap()
+    }
+
+    #[test]
+    fn organize_with_nothing_declared() {
+        let installed = installed(&["vim", "base"], &["glibc", "gpm"], &["gpm"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&HashSet::new(), &installed, &ignored);
+
+        assert!(organized.to_install.is_empty());
+        assert!(organized.to_mark_as_explicit.is_empty());
+        assert_eq!(organized.to_remove, ["base", "vim"]);
+        assert_eq!(organized.unneeded, ["gpm"]);
+        assert!(organized.ignored.is_empty());
+        assert!(!organized.is_in_sync());
+    }
+
+    #[test]
+    fn organize_fully_synced() {
+        let installed = installed(&["vim", "base"], &["glibc"], &[]);
+        let declared = set(&["base", "vim"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert!(organized.is_in_sync());
+        assert!(organized.ignored.is_empty());
+    }
+
+    #[test]
+    fn organize_install_mark_and_remove() {
+        let installed = installed(&["vim", "nano"], &["glibc", "gpm"], &["gpm"]);
+        let declared = set(&["vim", "glibc", "emacs", "zsh"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert_eq!(organized.to_install, ["emacs", "zsh"]);
+        assert_eq!(organized.to_mark_as_explicit, ["glibc"]);
+        assert_eq!(organized.to_remove, ["nano"]);
+        assert_eq!(organized.unneeded, ["gpm"]);
+        assert!(organized.ignored.is_empty());
+    }
+
+    #[test]
+    fn organize_ignored_packages() {
+        let installed = installed(&["vim", "nano"], &["glibc", "gpm"], &["gpm"]);
+        let declared = set(&["glibc", "emacs", "zsh"]);
+        let ignored = IgnoredPackages::new(set(&["vim", "gpm", "glibc", "emacs"])).unwrap();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert_eq!(organized.to_install, ["zsh"]);
+        assert!(organized.to_mark_as_explicit.is_empty());
+        assert_eq!(organized.to_remove, ["nano"]);
+        assert!(organized.unneeded.is_empty());
+        assert_eq!(organized.ignored, ["emacs", "glibc", "gpm", "vim"]);
+    }
+
+    #[test]
+    fn organize_overlapping_install_reasons() {
+        // pacman should never report a package as both explicit and a dependency, but the explicit
+        // install reason takes precedence if it does
+        let installed = installed(&["vim", "nano"], &["vim", "nano"], &["nano"]);
+        let declared = set(&["vim"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert!(organized.to_install.is_empty());
+        assert!(organized.to_mark_as_explicit.is_empty());
+        assert_eq!(organized.to_remove, ["nano"]);
+        assert_eq!(organized.unneeded, ["nano"]);
+    }
+
+    #[test]
+    fn organize_declared_unneeded_dependencies() {
+        // a declared package installed as a dependency is marked, even if nothing requires it
+        let installed = installed(&["vim"], &["gpm", "nano"], &["gpm", "nano"]);
+        let declared = set(&["vim", "nano"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert!(organized.to_install.is_empty());
+        assert_eq!(organized.to_mark_as_explicit, ["nano"]);
+        assert!(organized.to_remove.is_empty());
+        assert_eq!(organized.unneeded, ["gpm"]);
+    }
+
+    #[test]
+    fn organize_protected_by_patterns() {
+        let installed = installed(
+            &["linux", "linux-lts", "vim"],
+            &["linux-headers", "gpm"],
+            &["linux-headers", "gpm"],
+        );
+        let declared = set(&["linux-zen"]);
+        let ignored = IgnoredPackages::new(set(&["linux*", "vim"])).unwrap();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert_eq!(organized.to_install, ["linux-zen"]);
+        assert!(organized.to_remove.is_empty());
+        assert_eq!(organized.unneeded, ["gpm"]);
+        assert_eq!(
+            organized.ignored,
+            ["linux", "linux-headers", "linux-lts", "vim"]
+        );
+        assert_eq!(
+            organized.protected,
+            [
+                ("linux", "linux*"),
+                ("linux-headers", "linux*"),
+                ("linux-lts", "linux*"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_group_resolution() {
+        let groups = IndexSet::from(["base-devel", "xorg"]);
+        assert_eq!(groups_to_resolve(&groups, false), groups);
+        assert!(groups_to_resolve(&groups, true).is_empty());
+    }
+
+    #[test]
+    fn organize_declared_patterns() {
+        let installed = installed(
+            &["python-numpy", "vim"],
+            &["python-six", "python"],
+            &["python-six"],
+        );
+        let declared = IndexSet::from(["python-*", "zsh"]);
+        let group_packages = HashMap::new();
+        let mut merged = merge_declared_packages(&declared, &group_packages);
+        assert_eq!(merged.packages, set(&["zsh"]));
+        assert_eq!(merged.patterns, ["python-*"]);
+
+        merged.expand_patterns(installed.names()).unwrap();
+        let ignored = no_ignored();
+        let organized = organize_packages(&merged.packages, &installed, &ignored);
+        assert_eq!(organized.to_install, ["zsh"]);
+        assert_eq!(organized.to_mark_as_explicit, ["python-six"]);
+        assert_eq!(organized.to_remove, ["vim"]);
+        assert!(organized.unneeded.is_empty());
+
+        merged.expand_patterns(["python-scipy", "ruby"]).unwrap();
+        assert!(merged.packages.contains("python-scipy"));
+        assert!(!merged.packages.contains("ruby"));
+    }
+
+    #[test]
+    fn organize_foreign() {
+        let mut installed = installed(&["vim", "base"], &["glibc"], &[]);
+        installed.foreign = set(&["yay", "my-tool"]);
+        let declared = set(&["base", "vim", "yay"]);
+        let ignored = no_ignored();
+        let organized = organize_packages(&declared, &installed, &ignored);
+
+        assert!(organized.is_in_sync());
+        assert_eq!(organized.foreign, ["my-tool"]);
+    }
+
+    #[test]
+    fn organize_with_excluded() {
+        let installed = installed(&["vim", "nano", "linux-lts"], &["gpm"], &["gpm"]);
+        let declared = set(&["vim", "emacs", "emacs-nox"]);
+        let ignored = no_ignored();
+        let mut organized = organize_packages(&declared, &installed, &ignored);
+        let excluded = compile_patterns(&["emacs*".to_owned(), "linux*".to_owned()]).unwrap();
+        organized.exclude(&excluded);
+
+        assert!(organized.to_install.is_empty());
+        assert_eq!(organized.to_remove, ["nano"]);
+        assert_eq!(organized.unneeded, ["gpm"]);
+    }
+
+    #[test]
+    fn organize_restricted() {
+        let installed = installed(&["vim", "nano"], &["gpm", "emacs"], &["gpm"]);
+        let declared = set(&["vim", "emacs", "firefox", "zsh"]);
+        let ignored = no_ignored();
+        let mut organized = organize_packages(&declared, &installed, &ignored);
+        organized.restrict(&set(&["emacs", "firefox", "gpm"]));
+
+        assert_eq!(organized.to_install, ["firefox"]);
+        assert_eq!(organized.to_mark_as_explicit, ["emacs"]);
+        assert!(organized.to_remove.is_empty());
+        assert_eq!(organized.unneeded, ["gpm"]);
+    }
+
+    #[test]
+    fn reinstall_installed_declared_packages() {
+        let mut installed = installed(&["vim", "linux"], &["glibc"], &[]);
+        installed.foreign = set(&["yay"]);
+        let declared = set(&["vim", "glibc", "emacs", "yay", "linux", "nano"]);
+        let ignored = IgnoredPackages::new(set(&["linux*"])).unwrap();
+        let excluded = compile_patterns(&["nano".to_owned(), "vim".to_owned()]).unwrap();
+        let candidates = reinstall_candidates(&declared, &installed, &ignored, &excluded);
+
+        assert_eq!(candidates, ["glibc"]);
     }
 }
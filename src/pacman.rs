@@ -1,17 +1,53 @@
 //! Running `pacman` --- the Arch Linux package manager.
 //!
 //! The functions in this module run the respective `pacman` subcommands. Additional flags are given
-//! based on the function arguments.
+//! based on the function arguments. [`Pacman`] implements [`PackageBackend`] using these functions.
+//!
+//! Commands that modify the system are run as child processes that we wait for. Interrupting the
+//! program with Ctrl-C while such a command is running does not make us exit immediately: the
+//! command receives the interrupt on its own, we wait for it to exit and only then fail. If the
+//! database lock is still held afterwards, we warn about it, but do not remove it, since it might
+//! belong to another instance of `pacman`.
 
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
+    env,
     ffi::OsStr,
-    io,
-    process::Command,
+    fs, io,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
 };
 
+use anyhow::{bail, Context};
 use thiserror::Error;
 
+use crate::{
+    args::RunMode,
+    backend::{
+        self, BackendError, InstallReason, PackageBackend, QueryFilter, SyncOptions, Upgrade,
+    },
+    util::{self, SigintGuard, TimedOut},
+};
+
+pub mod config;
+
+/// The directory containing the sync databases.
+const SYNC_DATABASE_DIR: &str = "/var/lib/pacman/sync";
+
+/// The lock file that `pacman` creates while it modifies the databases.
+const LOCK_FILE: &str = "/var/lib/pacman/db.lck";
+
+/// How long pacman is given to exit after it is sent SIGINT because it ran for longer than the
+/// limit set with `--timeout`, before it is killed.
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// The directory containing cached package files.
+const CACHE_DIR: &str = "/var/cache/pacman/pkg";
+
 /// The return type of all `pacman` calls.
 type Result<T, E = PacmanError> = std::result::Result<T, E>;
 
@@ -24,30 +60,134 @@ pub enum PacmanError {
     /// `pacman` output was not valid UTF-8.
     #[error("pacman output was not valid UTF-8:\n{}", String::from_utf8_lossy(.0))]
     NonUtf8Output(Vec<u8>),
+    /// `pacman` output was not in the expected format.
+    #[error("Unexpected pacman output: {0:?}")]
+    UnexpectedOutput(String),
+    /// The package is not installed.
+    #[error("Package {0:?} is not installed")]
+    NotInstalled(String),
+    /// `pacman` would have to be run as root, but that was disallowed with `--no-sudo`.
+    #[error("Refusing to run pacman with root privileges because of --no-sudo")]
+    Unprivileged,
+    /// `pacman` was interrupted with SIGINT.
+    #[error("pacman was interrupted")]
+    Interrupted,
+    /// `pacman` did not exit within the time limit set with `--timeout` and was stopped.
+    #[error("pacman did not exit within {} s and was stopped", .0.as_secs())]
+    TimedOut(Duration),
+    /// A temporary `pacman` configuration file could not be created.
+    #[error("Failed to create a temporary pacman configuration file: {0}")]
+    TempConfig(io::Error),
     /// A IO error occurred.
     #[error("Failed to run pacman: {0}")]
-    IO(#[from] io::Error),
+    IO(io::Error),
 }
 
-/// Filter for packages returned from a query.
+impl From<io::Error> for PacmanError {
+    fn from(err: io::Error) -> Self {
+        match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<util::TimedOut>())
+        {
+            Some(TimedOut(timeout)) => PacmanError::TimedOut(*timeout),
+            None => PacmanError::IO(err),
+        }
+    }
+}
+
+/// The `pacman` package backend.
 #[derive(Clone, Copy, Debug, Default)]
-pub struct QueryFilter {
-    /// Constrain the install reason.
-    pub install_reason: Option<InstallReason>,
-    /// Only packages not (optionally) required by any other package.
-    // TODO: ignore optional dependencies?
-    pub unrequired: bool,
-    /// Only outdated packages.
-    pub outdated: bool,
+pub struct Pacman;
+
+impl From<PacmanError> for BackendError {
+    fn from(err: PacmanError) -> Self {
+        match err {
+            PacmanError::ExitFailure => BackendError::ExitFailure("pacman"),
+            err => BackendError::Other(err.into()),
+        }
+    }
 }
 
-/// Install reason of a package.
-#[derive(Clone, Copy, Debug)]
-pub enum InstallReason {
-    /// Explicitly installed.
-    Explicit,
-    /// Installed as a dependency of another package.
-    Dependency,
+impl PackageBackend for Pacman {
+    fn query(&self, filter: QueryFilter) -> backend::Result<HashSet<String>> {
+        query(filter).map_err(Into::into)
+    }
+
+    fn available(&self) -> backend::Result<HashSet<String>> {
+        available().map_err(Into::into)
+    }
+
+    fn query_upgrades(&self) -> backend::Result<Vec<Upgrade>> {
+        query_upgrades(QueryFilter::default()).map_err(Into::into)
+    }
+
+    fn query_aur_upgrades(&self, helper: &str) -> backend::Result<Vec<Upgrade>> {
+        query_aur_upgrades(helper).map_err(Into::into)
+    }
+
+    fn installed(&self, packages: &[&str]) -> backend::Result<HashSet<String>> {
+        installed(packages).map_err(Into::into)
+    }
+
+    fn installed_version(&self, package: &str) -> backend::Result<Option<String>> {
+        installed_version(package).map_err(Into::into)
+    }
+
+    fn why(&self, package: &str) -> backend::Result<Vec<String>> {
+        why(package).map_err(Into::into)
+    }
+
+    fn database_age(&self) -> backend::Result<Option<Duration>> {
+        sync_database_age()
+            .context("Failed to determine the age of the package databases")
+            .map_err(Into::into)
+    }
+
+    fn groups<'a>(&self, groups: &[&'a str]) -> backend::Result<HashMap<String, &'a str>> {
+        self::groups(groups.iter().copied()).map_err(Into::into)
+    }
+
+    fn installed_groups(&self) -> backend::Result<HashMap<String, Vec<String>>> {
+        installed_groups().map_err(Into::into)
+    }
+
+    fn set_install_reason(
+        &self,
+        install_reason: InstallReason,
+        packages: &[&str],
+        mode: RunMode,
+    ) -> backend::Result<()> {
+        database(install_reason, packages, mode).map_err(Into::into)
+    }
+
+    fn sync(
+        &self,
+        options: SyncOptions<'_>,
+        packages: &[&str],
+        mode: RunMode,
+    ) -> backend::Result<Vec<String>> {
+        sync(options, packages, mode).map_err(Into::into)
+    }
+
+    fn remove(&self, packages: &[&str], recursive: bool, mode: RunMode) -> backend::Result<()> {
+        remove(packages, recursive, mode).map_err(Into::into)
+    }
+
+    fn prune_cache(&self, mode: RunMode) -> backend::Result<Option<u64>> {
+        prune_cache(mode).map_err(Into::into)
+    }
+
+    fn removal_preview(&self, packages: &[&str], recursive: bool) -> backend::Result<Vec<String>> {
+        remove_print(packages, recursive).map_err(Into::into)
+    }
+
+    fn sync_preview(
+        &self,
+        options: SyncOptions<'_>,
+        packages: &[&str],
+    ) -> backend::Result<Vec<String>> {
+        sync_print(options, packages).map_err(Into::into)
+    }
 }
 
 /// `pacman -D`
@@ -55,7 +195,8 @@ pub enum InstallReason {
 /// # Arguments
 /// - `install_reason`: the install reason to be set for the specified `packages`.
 /// - `packages`: packages that should have their database entries modified.
-pub fn database<P, S>(install_reason: InstallReason, packages: P) -> Result<()>
+/// - `mode`: whether `pacman` should actually be run.
+pub fn database<P, S>(install_reason: InstallReason, packages: P, mode: RunMode) -> Result<()>
 where
     P: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
@@ -68,7 +209,7 @@ where
     };
     cmd.args(packages);
 
-    run_for_status(cmd)
+    run_for_status(cmd, mode)
 }
 
 /// `pacman -S`
@@ -76,63 +217,591 @@ where
 /// The `--refresh` (`-y`) flag is always used.
 ///
 /// # Arguments
-/// - `system_upgrade`: update outdated packages (`-u` flag).
+/// - `options`: a second `-y` flag for `force_refresh`, `-u` for `system_upgrade` and `--ignore`
+///   for every package in `ignore`. If `parallel_downloads` is given, `pacman` is run with a
+///   temporary copy of `config` (or the default configuration file) that sets `ParallelDownloads`.
 /// - `packages`: additional packages to be installed.
-pub fn sync<P, S>(system_upgrade: bool, packages: P) -> Result<()>
+/// - `mode`: whether `pacman` should actually be run.
+///
+/// If `options.capture_transaction` is set, `stdout` is passed through and captured, and the names
+/// of the packages in the transaction summary are returned, otherwise nothing is returned.
+pub fn sync<P, S>(options: SyncOptions<'_>, packages: P, mode: RunMode) -> Result<Vec<String>>
 where
     P: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
     let mut cmd = Command::new("pacman");
     cmd.args(["--color=auto", "-S", "-y"]);
-    if system_upgrade {
+    if options.force_refresh {
+        cmd.arg("-y");
+    }
+    if options.system_upgrade {
         cmd.arg("-u");
     }
+    for package in options.ignore {
+        cmd.args(["--ignore", package]);
+    }
+    let base = options.config.unwrap_or(Path::new(config::DEFAULT_PATH));
+    let temp_config = match options.parallel_downloads {
+        // the temporary copy would be removed before anyone could use it
+        Some(parallel_downloads) if mode.is_dry_run() || mode.is_unprivileged() => {
+            info!(
+                "Would run pacman with a temporary copy of {:?} that sets ParallelDownloads = {}",
+                base, parallel_downloads,
+            );
+            None
+        }
+        Some(parallel_downloads) => {
+            let temp_config = config::write_with_parallel_downloads(base, parallel_downloads)
+                .map_err(PacmanError::TempConfig)?;
+            cmd.arg("--config").arg(&temp_config);
+            Some(temp_config)
+        }
+        None => None,
+    };
     cmd.args(packages);
 
-    run_for_status(cmd)
+    let result = if options.capture_transaction {
+        run_teeing_stdout(cmd, mode).map(|stdout| {
+            parse_transaction_summary(&String::from_utf8_lossy(&stdout)).unwrap_or_default()
+        })
+    } else {
+        run_for_status(cmd, mode).map(|()| Vec::new())
+    };
+    if let Some(temp_config) = temp_config {
+        let _ = fs::remove_file(temp_config);
+    }
+    result
 }
 
 /// `pacman -R`
 ///
-/// The `--recursive` (`-s`) and `--unneeded` (`-u`) flags are always used.
+/// The `--unneeded` (`-u`) flag is always used.
 ///
 /// # Arguments
 /// - `packages`: packages that should be removed.
-pub fn remove<P, S>(packages: P) -> Result<()>
+/// - `recursive`: use the `--recursive` (`-s`) flag, also removing dependencies that are no longer
+///   needed.
+/// - `mode`: whether `pacman` should actually be run.
+pub fn remove<P, S>(packages: P, recursive: bool, mode: RunMode) -> Result<()>
+where
+    P: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new("pacman");
+    cmd.args(["--color=auto", "-R", "-u"]);
+    if recursive {
+        cmd.arg("-s");
+    }
+    cmd.args(packages);
+
+    run_for_status(cmd, mode)
+}
+
+/// `paccache -r`, or `pacman -S -c` if `paccache` is not installed
+///
+/// `paccache` keeps the three most recent versions of every package, `pacman -S -c` only keeps the
+/// installed versions. Returns the number of bytes freed, if the size of the package cache could be
+/// determined.
+///
+/// # Arguments
+/// - `mode`: whether the command should actually be run.
+pub fn prune_cache(mode: RunMode) -> Result<Option<u64>> {
+    let cmd = if is_installed("paccache") {
+        let mut cmd = Command::new("paccache");
+        cmd.arg("-r");
+        cmd
+    } else {
+        warn!("paccache (from pacman-contrib) is not installed, falling back to pacman -Sc");
+        let mut cmd = Command::new("pacman");
+        cmd.args(["--color=auto", "-S", "-c"]);
+        cmd
+    };
+
+    let size_before = cache_size().ok();
+    run_for_status(cmd, mode)?;
+    if mode.is_dry_run() {
+        return Ok(None);
+    }
+    let size_after = cache_size().ok();
+    Ok(size_before
+        .zip(size_after)
+        .map(|(before, after)| before.saturating_sub(after)))
+}
+
+/// Returns the total size of all files in the package cache.
+fn cache_size() -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(CACHE_DIR)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Returns `true` if an executable called `name` can be found in `PATH`.
+fn is_installed(name: &str) -> bool {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+/// `pacman -R -p`
+///
+/// The `--unneeded` (`-u`) flag is always used. Nothing is removed, instead the names of all
+/// packages that would be removed are returned. `stdout` is captured and parsed, `stderr` is
+/// inherited from the current process.
+///
+/// # Arguments
+/// - `packages`: packages that would be removed.
+/// - `recursive`: use the `--recursive` (`-s`) flag, see [`remove`].
+pub fn remove_print<P, S>(packages: P, recursive: bool) -> Result<Vec<String>>
 where
     P: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
     let mut cmd = Command::new("pacman");
-    cmd.args(["--color=auto", "-R", "-s", "-u"]);
+    cmd.args(["-R", "-u", "-p", "--print-format", "%n"]);
+    if recursive {
+        cmd.arg("-s");
+    }
     cmd.args(packages);
 
-    run_for_status(cmd)
+    run_for_output(cmd)
+}
+
+/// `pacman -S -p`
+///
+/// Nothing is installed or upgraded, instead the names and versions of all packages that would be
+/// installed or upgraded are returned, one package per line. The package databases are not
+/// refreshed. `stdout` is captured and parsed, `stderr` is inherited from the current process.
+///
+/// # Arguments
+/// - `options`: `-u` for `system_upgrade` and `--ignore` for every package in `ignore`,
+///   `force_refresh` is ignored.
+/// - `packages`: additional packages that would be installed.
+pub fn sync_print(options: SyncOptions<'_>, packages: &[&str]) -> Result<Vec<String>> {
+    if !options.system_upgrade && packages.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut cmd = Command::new("pacman");
+    cmd.args(["-S", "-p", "--print-format", "%n %v"]);
+    if options.system_upgrade {
+        cmd.arg("-u");
+    }
+    for package in options.ignore {
+        cmd.args(["--ignore", package]);
+    }
+    cmd.args(packages);
+
+    run_for_output(cmd)
+}
+
+/// Runs the given command and returns the lines of its output.
+///
+/// `stdout` is captured, `stderr` is inherited from the current process.
+fn run_for_output(mut cmd: Command) -> Result<Vec<String>> {
+    let output = util::output(&mut cmd)?;
+
+    if output.status.success() {
+        match std::str::from_utf8(&output.stdout) {
+            Ok(s) => Ok(s.lines().map(String::from).collect()),
+            Err(_) => Err(PacmanError::NonUtf8Output(output.stdout)),
+        }
+    } else {
+        Err(PacmanError::ExitFailure)
+    }
 }
 
 /// Runs the given command and maps its return status to a variant of [`Result`].
 ///
 /// The input and output streams of the command are inherited from the current process. Emits output
-/// to mark the start and end of the command output.
-fn run_for_status(mut cmd: Command) -> Result<()> {
+/// to mark the start and end of the command output. In a dry run the command is only printed, in
+/// unprivileged mode an error is returned without running the command. If we are interrupted while
+/// the command is running, we wait for it to exit and return [`PacmanError::Interrupted`].
+///
+/// If enabled with [`set_print_commands`], the command line is printed to `stdout` first, quoted
+/// so that it can be pasted into a shell.
+fn run_for_status(cmd: Command, mode: RunMode) -> Result<()> {
+    run_modifying(cmd, mode, false).map(drop)
+}
+
+/// Like [`run_for_status`], but `stdout` of the command is also captured while it is passed
+/// through, and returned. Returns nothing in a dry run.
+///
+/// `pacman` does not show progress bars if its output is not a terminal.
+fn run_teeing_stdout(cmd: Command, mode: RunMode) -> Result<Vec<u8>> {
+    run_modifying(cmd, mode, true).map(Option::unwrap_or_default)
+}
+
+/// Implements [`run_for_status`] and [`run_teeing_stdout`], capturing `stdout` if `capture` is set.
+fn run_modifying(mut cmd: Command, mode: RunMode, capture: bool) -> Result<Option<Vec<u8>>> {
+    let print_command = PRINT_COMMANDS.load(Ordering::Relaxed);
+    if print_command {
+        println!("{}", render_command(&cmd));
+    }
+    if mode.is_dry_run() {
+        if !print_command {
+            info!("Would run {:?}", cmd);
+        }
+        return Ok(None);
+    }
+    if mode.is_unprivileged() {
+        return Err(PacmanError::Unprivileged);
+    }
     bold!("======== RUNNING PACMAN ========");
-    let status = cmd.status();
+    let status = run_uninterrupted(&mut cmd, capture);
     bold!("===== END OF PACMAN OUTPUT =====");
     match status {
-        Ok(exit_status) if exit_status.success() => Ok(()),
+        Ok((_, true, _)) => {
+            warn_about_stale_lock();
+            Err(PacmanError::Interrupted)
+        }
+        Ok((exit_status, false, stdout)) if exit_status.success() => Ok(stdout),
         Ok(_) => Err(PacmanError::ExitFailure),
-        Err(io_err) => Err(io_err.into()),
+        Err(io_err) => {
+            let err = PacmanError::from(io_err);
+            if let PacmanError::TimedOut(_) = err {
+                warn_about_stale_lock();
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Warns about the pacman database lock, if it still exists after pacman was stopped.
+fn warn_about_stale_lock() {
+    if Path::new(LOCK_FILE).exists() {
+        warn!(
+            "the pacman database lock {:?} still exists, remove it if no other instance of pacman \
+             is running",
+            LOCK_FILE,
+        );
+    }
+}
+
+/// Spawns `cmd` and waits for it to exit, without letting SIGINT terminate the program meanwhile.
+///
+/// The child process is in our process group, so it receives interrupts from the terminal
+/// directly. Returns the exit status of the child, whether we were interrupted while waiting and,
+/// if `capture` is set, everything the child wrote to `stdout`, which is also passed through to
+/// our `stdout` as soon as it arrives.
+///
+/// If the child runs for longer than the limit set with `--timeout`, it is sent SIGINT, which
+/// pacman handles by rolling back the transaction and releasing the database lock. It is only
+/// killed if it does not exit within [`TIMEOUT_GRACE_PERIOD`] after that, see
+/// [`util::wait_interruptible`].
+fn run_uninterrupted(
+    cmd: &mut Command,
+    capture: bool,
+) -> io::Result<(ExitStatus, bool, Option<Vec<u8>>)> {
+    let guard = SigintGuard::install()?;
+    if capture {
+        cmd.stdout(Stdio::piped());
+    }
+    let mut child = cmd.spawn()?;
+    let captured = child
+        .stdout
+        .take()
+        .map(|child_stdout| thread::spawn(|| util::tee(child_stdout, io::stdout())));
+    let status = util::wait_interruptible(&mut child, TIMEOUT_GRACE_PERIOD)?;
+    let captured = captured.map(|tee| tee.join().expect("passing through pacman output panicked"));
+    Ok((status, guard.interrupted(), captured.transpose()?))
+}
+
+/// Returns the names of the packages in the transaction summary printed by `pacman -S`.
+///
+/// The summary looks like `Packages (2) glibc-2.37-2  linux-6.2.8.arch1-1`, possibly wrapped into
+/// indented continuation lines. Returns `None` if there is no summary.
+fn parse_transaction_summary(output: &str) -> Option<Vec<String>> {
+    let mut lines = output
+        .lines()
+        .skip_while(|line| !line.starts_with("Packages ("));
+    let first = lines.next()?;
+    let (_, first) = first.split_once(')')?;
+    let continuation = lines.take_while(|line| line.starts_with(char::is_whitespace));
+    let names = std::iter::once(first)
+        .chain(continuation)
+        .flat_map(str::split_whitespace)
+        .filter_map(|entry| {
+            // `name-pkgver-pkgrel`, where only the name can contain more dashes
+            let mut parts = entry.rsplitn(3, '-');
+            let (_, _, name) = (parts.next()?, parts.next()?, parts.next()?);
+            Some(name.to_owned())
+        })
+        .collect();
+    Some(names)
+}
+
+/// Whether [`run_for_status`] prints the commands it runs.
+static PRINT_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+/// Makes all following commands that modify the system print their command lines, see
+/// [`run_for_status`].
+pub fn set_print_commands(enabled: bool) {
+    PRINT_COMMANDS.store(enabled, Ordering::Relaxed);
+}
+
+/// Renders the program and arguments of `cmd` as a shell command line.
+fn render_command(cmd: &Command) -> String {
+    let program = std::iter::once(cmd.get_program());
+    let words: Vec<_> = program.chain(cmd.get_args()).map(shell_quote).collect();
+    words.join(" ")
+}
+
+/// Quotes `word` for a POSIX shell, if needed.
+///
+/// Words that are not valid UTF-8 are rendered lossily.
+fn shell_quote(word: &OsStr) -> Cow<'_, str> {
+    let word = word.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
     }
 }
 
 /// `pacman -Q`
 ///
-/// The `--native` (`-n`) flag is always used. `stdout` is captured and parsed, `stderr` is
-/// inherited from the current process.
+/// The `--native` (`-n`) flag is used unless querying for foreign packages with `--foreign` (`-m`).
+/// `stdout` is captured and parsed, `stderr` is inherited from the current process.
 pub fn query(filter: QueryFilter) -> Result<HashSet<String>> {
+    run_query(filter, true).map(|lines| lines.into_iter().collect())
+}
+
+/// `pacman -S -l -q`
+///
+/// Retrieves the names of all packages in the sync databases.
+pub fn available() -> Result<HashSet<String>> {
+    let output = util::output(Command::new("pacman").args(["-S", "-l", "-q"]))?;
+    if !output.status.success() {
+        return Err(PacmanError::ExitFailure);
+    }
+    match String::from_utf8(output.stdout) {
+        Ok(stdout) => Ok(stdout.lines().map(String::from).collect()),
+        Err(err) => Err(PacmanError::NonUtf8Output(err.into_bytes())),
+    }
+}
+
+/// `pacman -Q -u`
+///
+/// Retrieves the names of outdated packages along with their installed and new versions. Other
+/// than that, works just like [`query`] with the `outdated` filter.
+pub fn query_upgrades(filter: QueryFilter) -> Result<Vec<Upgrade>> {
+    let filter = QueryFilter {
+        outdated: true,
+        ..filter
+    };
+    let lines = run_query(filter, false)?;
+    lines
+        .iter()
+        .map(|line| parse_upgrade(line).ok_or_else(|| PacmanError::UnexpectedOutput(line.clone())))
+        .collect()
+}
+
+/// `<helper> -Q -u -a`
+///
+/// Retrieves the names of foreign packages that have newer versions in the AUR, along with their
+/// installed and new versions, using an AUR helper that accepts the query flags of `pacman`, like
+/// `paru` or `yay`.
+pub fn query_aur_upgrades(helper: &str) -> anyhow::Result<Vec<Upgrade>> {
+    let output = util::output(Command::new(helper).args(["-Q", "-u", "-a"]))
+        .with_context(|| format!("Failed to run {}", helper))?;
+    // like `pacman`, AUR helpers fail without printing anything if there are no results
+    let no_results = output.stdout.is_empty() && output.stderr.is_empty();
+    if !output.status.success() && !no_results {
+        bail!("{} did not exit successfully", helper);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("{} output was not valid UTF-8", helper))?;
+    stdout
+        .lines()
+        .map(|line| {
+            let upgrade = parse_upgrade(line)
+                .ok_or_else(|| anyhow::anyhow!("Unexpected output of {}: {:?}", helper, line))?;
+            Ok(Upgrade {
+                foreign: true,
+                ..upgrade
+            })
+        })
+        .collect()
+}
+
+/// `pacman -Q <package>`
+///
+/// Returns the installed version of `package`, or `None` if it is not installed.
+pub fn installed_version(package: &str) -> Result<Option<String>> {
+    let output = util::output(Command::new("pacman").args(["-Q", "--", package]))?;
+    if !output.status.success() {
+        // `pacman` only reports that the package was not found on `stderr`
+        return if output.stdout.is_empty() {
+            Ok(None)
+        } else {
+            Err(PacmanError::ExitFailure)
+        };
+    }
+    let stdout = match String::from_utf8(output.stdout) {
+        Ok(stdout) => stdout,
+        Err(err) => return Err(PacmanError::NonUtf8Output(err.into_bytes())),
+    };
+    match stdout.trim_end().split_once(' ') {
+        Some((name, version)) if name == package => Ok(Some(version.to_owned())),
+        _ => Err(PacmanError::UnexpectedOutput(stdout)),
+    }
+}
+
+/// `pacman -Q <packages>`
+///
+/// Returns the names of the installed packages among `packages`, checking all of them with a
+/// single invocation of pacman.
+pub fn installed(packages: &[&str]) -> Result<HashSet<String>> {
+    if packages.is_empty() {
+        return Ok(HashSet::new());
+    }
     let mut cmd = Command::new("pacman");
-    cmd.args(["-Q", "-q", "-n"]);
+    // the error messages are translated
+    cmd.env("LC_ALL", "C").arg("-Q").arg("--").args(packages);
+    let output = util::output(&mut cmd)?;
+    // `pacman` also fails if any of the packages is not installed, which is only reported on
+    // `stderr`, so any other error means that the query itself failed
+    if !output.status.success() && !only_not_found(&String::from_utf8_lossy(&output.stderr)) {
+        return Err(PacmanError::ExitFailure);
+    }
+    let stdout = match String::from_utf8(output.stdout) {
+        Ok(stdout) => stdout,
+        Err(err) => return Err(PacmanError::NonUtf8Output(err.into_bytes())),
+    };
+    parse_installed(&stdout).ok_or(PacmanError::UnexpectedOutput(stdout))
+}
+
+/// Returns `true` if every line of `pacman -Q` error output reports a package that was not found.
+fn only_not_found(stderr: &str) -> bool {
+    let mut lines = stderr.lines().peekable();
+    lines.peek().is_some()
+        && lines.all(|line| {
+            line.strip_prefix("error: package '")
+                .and_then(|rest| rest.strip_suffix("' was not found"))
+                .is_some()
+        })
+}
+
+/// Parses `pacman -Q` output, a line with the name and the version of every installed package.
+fn parse_installed(output: &str) -> Option<HashSet<String>> {
+    output
+        .lines()
+        .map(|line| line.split_once(' ').map(|(name, _)| name.to_owned()))
+        .collect()
+}
+
+/// `pacman -Q -i`
+///
+/// Returns the installed packages that depend on `package`, directly or through other packages, as
+/// the lines of a tree rooted at `package`. Every line is indented by two spaces for each level of
+/// the tree, the packages that require a package are listed below it. Every package is only
+/// expanded once, later occurrences are marked with `(see above)`. Optional dependencies are not
+/// followed.
+///
+/// Fails with [`PacmanError::NotInstalled`] if `package` is not installed.
+pub fn why(package: &str) -> Result<Vec<String>> {
+    reverse_dependency_tree(package, required_by)
+}
+
+/// Builds the tree returned by [`why`], using `required_by` to look up the packages that directly
+/// depend on a package.
+fn reverse_dependency_tree<F>(package: &str, mut required_by: F) -> Result<Vec<String>>
+where
+    F: FnMut(&str) -> Result<Vec<String>>,
+{
+    let mut lines = Vec::new();
+    let mut expanded = HashSet::new();
+    // a stack of packages to print along with their depth, in reverse order
+    let mut stack = vec![(package.to_owned(), 0)];
+    while let Some((package, depth)) = stack.pop() {
+        let indent = "  ".repeat(depth);
+        if expanded.contains(&package) {
+            lines.push(format!("{}{} (see above)", indent, package));
+            continue;
+        }
+        let dependents = required_by(&package)?;
+        lines.push(format!("{}{}", indent, package));
+        stack.extend(dependents.into_iter().rev().map(|name| (name, depth + 1)));
+        expanded.insert(package);
+    }
+    Ok(lines)
+}
+
+/// Returns the installed packages that directly depend on the installed `package`, as listed by
+/// `pacman -Q -i`.
+fn required_by(package: &str) -> Result<Vec<String>> {
+    let mut cmd = Command::new("pacman");
+    // the field names are translated
+    cmd.env("LC_ALL", "C").args(["-Q", "-i", "--", package]);
+    let output = util::output(&mut cmd)?;
+    if !output.status.success() {
+        // `pacman` only reports that the package was not found on `stderr`
+        return if output.stdout.is_empty() {
+            Err(PacmanError::NotInstalled(package.to_owned()))
+        } else {
+            Err(PacmanError::ExitFailure)
+        };
+    }
+    let stdout = match String::from_utf8(output.stdout) {
+        Ok(stdout) => stdout,
+        Err(err) => return Err(PacmanError::NonUtf8Output(err.into_bytes())),
+    };
+    parse_required_by(&stdout).ok_or(PacmanError::UnexpectedOutput(stdout))
+}
+
+/// Parses the `Required By` field of `pacman -Q -i` output, which might be wrapped into indented
+/// continuation lines. Returns `None` if there is no such field.
+fn parse_required_by(info: &str) -> Option<Vec<String>> {
+    let mut lines = info
+        .lines()
+        .skip_while(|line| !line.starts_with("Required By "));
+    let (_, first) = lines.next()?.split_once(':')?;
+    let continuation = lines.take_while(|line| line.starts_with(char::is_whitespace));
+    let names = std::iter::once(first)
+        .chain(continuation)
+        .flat_map(str::split_whitespace)
+        .filter(|name| *name != "None")
+        .map(String::from)
+        .collect();
+    Some(names)
+}
+
+/// Parses a line of `pacman -Q -u` output, e.g. `linux 6.2.7.arch1-1 -> 6.2.8.arch1-1`.
+fn parse_upgrade(line: &str) -> Option<Upgrade> {
+    let mut words = line.split_whitespace();
+    let name = words.next()?;
+    let installed_version = words.next()?;
+    let new_version = match (words.next(), words.next()) {
+        (Some("->"), Some(new_version)) => new_version,
+        _ => return None,
+    };
+    Some(Upgrade {
+        name: name.to_owned(),
+        installed_version: installed_version.to_owned(),
+        new_version: new_version.to_owned(),
+        foreign: false,
+    })
+}
+
+/// Runs `pacman -Q` with the given `filter` and returns the lines of its output.
+///
+/// Passes the `--quiet` (`-q`) flag if `quiet` is set.
+fn run_query(filter: QueryFilter, quiet: bool) -> Result<Vec<String>> {
+    let mut cmd = Command::new("pacman");
+    cmd.arg("-Q");
+    if !filter.foreign {
+        cmd.arg("-n");
+    }
+    if quiet {
+        cmd.arg("-q");
+    }
     if let Some(install_reason) = filter.install_reason {
         match install_reason {
             InstallReason::Explicit => cmd.arg("-e"),
@@ -145,8 +814,11 @@ pub fn query(filter: QueryFilter) -> Result<HashSet<String>> {
     if filter.outdated {
         cmd.arg("-u");
     };
+    if filter.foreign {
+        cmd.arg("-m");
+    }
 
-    let output = cmd.output()?;
+    let output = util::output(&mut cmd)?;
 
     if output.status.success() {
         match std::str::from_utf8(&output.stdout) {
@@ -158,13 +830,29 @@ pub fn query(filter: QueryFilter) -> Result<HashSet<String>> {
         // as an error, so we check if there was any output. No output means that there was no real
         // error.
         if output.stdout.is_empty() && output.stderr.is_empty() {
-            Ok(HashSet::new())
+            Ok(Vec::new())
         } else {
             Err(PacmanError::ExitFailure)
         }
     }
 }
 
+/// Returns the time since the sync databases were last refreshed.
+///
+/// Returns `None` if there are no sync databases.
+pub fn sync_database_age() -> io::Result<Option<Duration>> {
+    let mut newest = None;
+    for entry in fs::read_dir(SYNC_DATABASE_DIR)? {
+        let path = entry?.path();
+        if path.extension() != Some("db".as_ref()) {
+            continue;
+        }
+        let modified = path.metadata()?.modified()?;
+        newest = newest.max(Some(modified));
+    }
+    Ok(newest.map(|newest| newest.elapsed().unwrap_or_default()))
+}
+
 /// `pacman -Sg`
 ///
 /// Retrieves the list of packages that belong to the given `groups`.
@@ -176,7 +864,7 @@ where
     for group in groups {
         let mut cmd = Command::new("pacman");
         cmd.args(["-S", "-g", "-q", group]);
-        let output = cmd.output()?;
+        let output = util::output(&mut cmd)?;
         if output.status.success() {
             match std::str::from_utf8(&output.stdout) {
                 Ok(s) => packages.extend(s.lines().map(|s| (s.to_owned(), group))),
@@ -188,3 +876,182 @@ where
     }
     Ok(packages)
 }
+
+/// `pacman -Q -g`
+///
+/// Retrieves the groups of all installed packages with a single query, mapping every package that
+/// belongs to any group to the groups it belongs to.
+pub fn installed_groups() -> Result<HashMap<String, Vec<String>>> {
+    let output = util::output(Command::new("pacman").args(["-Q", "-g"]))?;
+    // like other queries, `pacman` fails without printing anything if there are no results
+    let no_results = output.stdout.is_empty() && output.stderr.is_empty();
+    if !output.status.success() && !no_results {
+        return Err(PacmanError::ExitFailure);
+    }
+    let stdout = match String::from_utf8(output.stdout) {
+        Ok(stdout) => stdout,
+        Err(err) => return Err(PacmanError::NonUtf8Output(err.into_bytes())),
+    };
+    parse_installed_groups(&stdout).ok_or(PacmanError::UnexpectedOutput(stdout))
+}
+
+/// Parses `pacman -Q -g` output, a line with a group and a package for every group of every
+/// installed package.
+fn parse_installed_groups(output: &str) -> Option<HashMap<String, Vec<String>>> {
+    let mut groups: HashMap<_, Vec<_>> = HashMap::new();
+    for line in output.lines() {
+        let (group, package) = line.split_once(' ')?;
+        groups
+            .entry(package.to_owned())
+            .or_default()
+            .push(group.to_owned());
+    }
+    Some(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_upgrade_line() {
+        let upgrade = parse_upgrade("linux 6.2.7.arch1-1 -> 6.2.8.arch1-1").unwrap();
+        assert_eq!(upgrade.name, "linux");
+        assert_eq!(upgrade.installed_version, "6.2.7.arch1-1");
+        assert_eq!(upgrade.new_version, "6.2.8.arch1-1");
+
+        assert!(parse_upgrade("linux 6.2.7.arch1-1").is_none());
+        assert!(parse_upgrade("linux 6.2.7.arch1-1 6.2.8.arch1-1").is_none());
+    }
+
+    #[test]
+    fn survive_interrupt_while_waiting() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "kill -INT $PPID && sleep 0.1"]);
+        let (status, interrupted, captured) = run_uninterrupted(&mut cmd, false).unwrap();
+        assert!(status.success());
+        assert!(interrupted);
+        assert!(captured.is_none());
+    }
+
+    #[test]
+    fn parse_transaction_packages() {
+        let output = concat!(
+            ":: Starting full system upgrade...\n",
+            "resolving dependencies...\n",
+            "\n",
+            "Packages (3) glibc-2.37-2  lib32-glibc-2.37-2\n",
+            "             linux-6.2.8.arch1-1\n",
+            "\n",
+            "Total Download Size:   140.23 MiB\n",
+        );
+        assert_eq!(
+            parse_transaction_summary(output).unwrap(),
+            ["glibc", "lib32-glibc", "linux"]
+        );
+        assert_eq!(parse_transaction_summary(" there is nothing to do\n"), None);
+    }
+
+    #[test]
+    fn parse_required_by_field() {
+        let info = concat!(
+            "Name            : glib2\n",
+            "Depends On      : libffi  pcre2\n",
+            "Required By     : at-spi2-core  gtk3\n",
+            "                  pango\n",
+            "Optional For    : None\n",
+        );
+        assert_eq!(
+            parse_required_by(info).unwrap(),
+            ["at-spi2-core", "gtk3", "pango"]
+        );
+        let info = "Name            : firefox\nRequired By     : None\nOptional For    : None\n";
+        assert_eq!(parse_required_by(info).unwrap(), Vec::<String>::new());
+        assert_eq!(parse_required_by("Name            : firefox\n"), None);
+    }
+
+    #[test]
+    fn parse_installed_packages() {
+        let output = "alacritty 0.12.0-1\nlinux 6.2.8.arch1-1\n";
+        let installed = parse_installed(output).unwrap();
+        assert_eq!(installed.len(), 2);
+        assert!(installed.contains("alacritty") && installed.contains("linux"));
+        assert!(parse_installed("").unwrap().is_empty());
+        assert_eq!(parse_installed("alacritty\n"), None);
+    }
+
+    #[test]
+    fn tell_missing_packages_from_failures() {
+        let stderr = "error: package 'foo' was not found\nerror: package 'bar' was not found\n";
+        assert!(only_not_found(stderr));
+        assert!(!only_not_found(""));
+        assert!(!only_not_found(
+            "error: failed to initialize alpm library:\n(root: /, dbpath: /var/lib/pacman/)\n"
+        ));
+    }
+
+    #[test]
+    fn parse_installed_groups_output() {
+        let output = concat!(
+            "base-devel gcc\nbase-devel make\n",
+            "xorg xorg-server\nxorg-apps xorg-xrandr\nxorg xorg-xrandr\n",
+        );
+        let groups = parse_installed_groups(output).unwrap();
+        assert_eq!(groups.len(), 4);
+        assert_eq!(groups["gcc"], ["base-devel"]);
+        assert_eq!(groups["xorg-xrandr"], ["xorg-apps", "xorg"]);
+        assert_eq!(parse_installed_groups("xorg\n"), None);
+    }
+
+    #[test]
+    fn build_reverse_dependency_tree() {
+        let dependents: HashMap<&str, &[&str]> = [
+            ("glib2", &["gtk3", "pango"][..]),
+            ("pango", &["gtk3"][..]),
+            ("gtk3", &["firefox"][..]),
+            ("firefox", &[][..]),
+        ]
+        .into_iter()
+        .collect();
+        let required_by = |package: &str| match dependents.get(package) {
+            Some(names) => Ok(names.iter().map(|name| name.to_string()).collect()),
+            None => Err(PacmanError::NotInstalled(package.to_owned())),
+        };
+        assert_eq!(
+            reverse_dependency_tree("glib2", required_by).unwrap(),
+            [
+                "glib2",
+                "  gtk3",
+                "    firefox",
+                "  pango",
+                "    gtk3 (see above)",
+            ]
+        );
+        assert_eq!(
+            reverse_dependency_tree("firefox", required_by).unwrap(),
+            ["firefox"]
+        );
+        assert!(matches!(
+            reverse_dependency_tree("missing", required_by),
+            Err(PacmanError::NotInstalled(_))
+        ));
+    }
+
+    #[test]
+    fn render_quoted_command() {
+        let mut cmd = Command::new("pacman");
+        cmd.args([
+            "-S",
+            "--ignore",
+            "linux*",
+            "--config",
+            "/tmp/my pacman.conf",
+            "it's",
+            "",
+        ]);
+        assert_eq!(
+            render_command(&cmd),
+            r"pacman -S --ignore 'linux*' --config '/tmp/my pacman.conf' 'it'\''s' ''",
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Reading the `pacman` configuration file.
+
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    num::NonZeroU32,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// The default location of the `pacman` configuration file.
+pub const DEFAULT_PATH: &str = "/etc/pacman.conf";
+
+/// The number of temporary configuration files created so far, keeps their names unique.
+static TEMP_FILE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Packages and package groups that `pacman` is configured to ignore.
+#[derive(Debug, Default)]
+pub struct Ignored {
+    /// Values of all `IgnorePkg` directives.
+    pub packages: HashSet<String>,
+    /// Values of all `IgnoreGroup` directives.
+    pub groups: HashSet<String>,
+}
+
+/// Reads the `IgnorePkg` and `IgnoreGroup` directives from the `pacman` configuration file.
+///
+/// A missing file is treated as a file without any directives.
+pub fn ignored(path: &Path) -> anyhow::Result<Ignored> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(parse_ignored(&contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Ignored::default()),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to read the contents of file {:?}", path))
+        }
+    }
+}
+
+/// Writes a copy of the configuration file at `base` that sets `ParallelDownloads` to the given
+/// value to a temporary file and returns its path.
+///
+/// The file is created anew, readable only by its owner, so that other users can neither replace
+/// it nor redirect the write with a link planted at its path.
+pub fn write_with_parallel_downloads(
+    base: &Path,
+    parallel_downloads: NonZeroU32,
+) -> io::Result<PathBuf> {
+    let contents = fs::read_to_string(base)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = format!("archman-pacman-{}-{}-{}.conf", process::id(), count, nanos);
+    let path = env::temp_dir().join(name);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    if let Err(err) =
+        file.write_all(set_parallel_downloads(&contents, parallel_downloads).as_bytes())
+    {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+/// Sets the `ParallelDownloads` directive in the `[options]` section, replacing any existing one.
+fn set_parallel_downloads(contents: &str, parallel_downloads: NonZeroU32) -> String {
+    let directive = format!("ParallelDownloads = {}\n", parallel_downloads);
+    let mut result = String::with_capacity(contents.len() + directive.len());
+    let mut in_options = false;
+    let mut inserted = false;
+    for line in contents.lines() {
+        let trimmed = match line.find('#') {
+            Some(comment_start) => &line[..comment_start],
+            None => line,
+        }
+        .trim();
+
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            in_options = trimmed == "[options]";
+        } else if in_options && trimmed.split('=').next().unwrap().trim() == "ParallelDownloads" {
+            continue;
+        }
+        result.push_str(line);
+        result.push('\n');
+        if in_options && !inserted {
+            result.push_str(&directive);
+            inserted = true;
+        }
+    }
+    if !inserted {
+        result.insert_str(0, &format!("[options]\n{}", directive));
+    }
+    result
+}
+
+/// Parses the `IgnorePkg` and `IgnoreGroup` directives from the `[options]` section.
+fn parse_ignored(contents: &str) -> Ignored {
+    let mut ignored = Ignored::default();
+    let mut in_options = false;
+    for line in contents.lines() {
+        let line = match line.find('#') {
+            Some(comment_start) => &line[..comment_start],
+            None => line,
+        }
+        .trim();
+
+        if line.starts_with('[') && line.ends_with(']') {
+            in_options = line == "[options]";
+            continue;
+        }
+        if !in_options {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value),
+            None => continue,
+        };
+        let values = value.split_whitespace().map(String::from);
+        match key {
+            "IgnorePkg" => ignored.packages.extend(values),
+            "IgnoreGroup" => ignored.groups.extend(values),
+            _ => {}
+        }
+    }
+    ignored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ignore_directives() {
+        let contents = concat!(
+            "# IgnorePkg = commented\n",
+            "[options]\n",
+            "HoldPkg = pacman glibc\n",
+            "IgnorePkg = linux linux-headers # kernel\n",
+            "IgnorePkg=firefox\n",
+            "  IgnoreGroup = gnome\n",
+            "\n",
+            "[core]\n",
+            "IgnorePkg = not-options\n",
+            "Include = /etc/pacman.d/mirrorlist\n",
+        );
+
+        let ignored = parse_ignored(contents);
+        let packages: HashSet<_> = ["linux", "linux-headers", "firefox"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let groups: HashSet<_> = ["gnome"].into_iter().map(String::from).collect();
+        assert_eq!(ignored.packages, packages);
+        assert_eq!(ignored.groups, groups);
+    }
+
+    #[test]
+    fn write_private_copy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = env::temp_dir().join(format!("archman-pacman-base-{}.conf", process::id()));
+        fs::write(&base, "[options]\n").unwrap();
+        let parallel_downloads = NonZeroU32::new(4).unwrap();
+        let first = write_with_parallel_downloads(&base, parallel_downloads).unwrap();
+        let second = write_with_parallel_downloads(&base, parallel_downloads).unwrap();
+        assert_ne!(first, second);
+        let mode = fs::metadata(&first).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            fs::read_to_string(&first).unwrap(),
+            "[options]\nParallelDownloads = 4\n",
+        );
+
+        for path in [base, first, second] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn override_parallel_downloads() {
+        let parallel_downloads = NonZeroU32::new(8).unwrap();
+        let contents = concat!(
+            "# ParallelDownloads = 2\n",
+            "[options]\n",
+            "HoldPkg = pacman glibc\n",
+            "ParallelDownloads = 5\n",
+            "\n",
+            "[core]\n",
+            "Include = /etc/pacman.d/mirrorlist\n",
+        );
+        assert_eq!(
+            set_parallel_downloads(contents, parallel_downloads),
+            concat!(
+                "# ParallelDownloads = 2\n",
+                "[options]\n",
+                "ParallelDownloads = 8\n",
+                "HoldPkg = pacman glibc\n",
+                "\n",
+                "[core]\n",
+                "Include = /etc/pacman.d/mirrorlist\n",
+            ),
+        );
+
+        assert_eq!(
+            set_parallel_downloads("[core]\n", parallel_downloads),
+            "[options]\nParallelDownloads = 8\n[core]\n",
+        );
+    }
+}
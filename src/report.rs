@@ -0,0 +1,191 @@
+//! Summarizing the changes made to the system.
+
+use std::{fmt, path::PathBuf};
+
+/// The version of the format of all JSON output, included in it as `schema_version`.
+///
+/// Bumped on every incompatible change to any JSON output.
+#[cfg(feature = "json")]
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// The changes made to the system by a single run of the program.
+///
+/// In a dry run, the changes that would have been made. Actions that failed, but that we continued
+/// after, are not included.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// Whether this was a dry run and nothing was actually changed.
+    pub dry_run: bool,
+    /// Whether structured output, like JSON, was printed to `stdout`, so the summary must not be.
+    pub structured_output: bool,
+    /// Packages that were installed.
+    pub installed_packages: Vec<String>,
+    /// Already installed packages that were reinstalled.
+    pub reinstalled_packages: Vec<String>,
+    /// Installed packages that were upgraded, only known if pacman's output was captured.
+    pub upgraded_packages: Vec<String>,
+    /// Packages that were marked as explicitly installed.
+    pub marked_as_explicit: Vec<String>,
+    /// Packages that were marked as installed as dependencies.
+    pub marked_as_dependencies: Vec<String>,
+    /// Packages that were removed, not including their dependencies removed along with them.
+    pub removed_packages: Vec<String>,
+    /// Links that were created or replaced.
+    pub links: Vec<PathBuf>,
+    /// Undeclared links that were removed.
+    pub removed_links: Vec<PathBuf>,
+    /// Copies that were created or overwritten.
+    pub copies: Vec<PathBuf>,
+    /// Copies that were removed because they were no longer declared.
+    pub removed_copies: Vec<PathBuf>,
+    /// Services that were unmasked.
+    pub unmasked_services: Vec<String>,
+    /// Services that were enabled.
+    pub enabled_services: Vec<String>,
+    /// Services that were masked.
+    pub masked_services: Vec<String>,
+    /// Services that were restarted.
+    pub restarted_services: Vec<String>,
+    /// The reasons why a reboot is recommended, if it is.
+    pub reboot_reasons: Vec<String>,
+}
+
+impl RunReport {
+    /// Adds the changes from `other` to this report.
+    pub fn merge(&mut self, other: RunReport) {
+        self.structured_output |= other.structured_output;
+        self.installed_packages.extend(other.installed_packages);
+        self.reinstalled_packages.extend(other.reinstalled_packages);
+        self.upgraded_packages.extend(other.upgraded_packages);
+        self.marked_as_explicit.extend(other.marked_as_explicit);
+        self.marked_as_dependencies
+            .extend(other.marked_as_dependencies);
+        self.removed_packages.extend(other.removed_packages);
+        self.links.extend(other.links);
+        self.removed_links.extend(other.removed_links);
+        self.copies.extend(other.copies);
+        self.removed_copies.extend(other.removed_copies);
+        self.unmasked_services.extend(other.unmasked_services);
+        self.enabled_services.extend(other.enabled_services);
+        self.masked_services.extend(other.masked_services);
+        self.restarted_services.extend(other.restarted_services);
+        self.reboot_reasons.extend(other.reboot_reasons);
+    }
+
+    /// Returns `true` if nothing was changed.
+    pub fn is_empty(&self) -> bool {
+        self.counts().all(|(count, _, _)| count == 0)
+    }
+
+    /// Returns the number of changed things of each kind, along with their singular and plural
+    /// descriptions.
+    fn counts(&self) -> impl Iterator<Item = (usize, &'static str, &'static str)> {
+        [
+            (
+                self.installed_packages.len(),
+                "package installed",
+                "packages installed",
+            ),
+            (
+                self.reinstalled_packages.len(),
+                "package reinstalled",
+                "packages reinstalled",
+            ),
+            (
+                self.upgraded_packages.len(),
+                "package upgraded",
+                "packages upgraded",
+            ),
+            (
+                self.marked_as_explicit.len(),
+                "package marked as explicit",
+                "packages marked as explicit",
+            ),
+            (
+                self.marked_as_dependencies.len(),
+                "package marked as dependency",
+                "packages marked as dependencies",
+            ),
+            (
+                self.removed_packages.len(),
+                "package removed",
+                "packages removed",
+            ),
+            (self.links.len(), "link created", "links created"),
+            (self.removed_links.len(), "link removed", "links removed"),
+            (self.copies.len(), "copy created", "copies created"),
+            (self.removed_copies.len(), "copy removed", "copies removed"),
+            (
+                self.unmasked_services.len(),
+                "service unmasked",
+                "services unmasked",
+            ),
+            (
+                self.enabled_services.len(),
+                "service enabled",
+                "services enabled",
+            ),
+            (
+                self.masked_services.len(),
+                "service masked",
+                "services masked",
+            ),
+            (
+                self.restarted_services.len(),
+                "service restarted",
+                "services restarted",
+            ),
+        ]
+        .into_iter()
+    }
+}
+
+impl fmt::Display for RunReport {
+    /// Formats a one-line summary, e.g. `2 packages installed, 1 link created`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (count, singular, plural) in self.counts().filter(|(count, _, _)| *count > 0) {
+            if !first {
+                f.write_str(", ")?;
+            }
+            first = false;
+            let description = if count == 1 { singular } else { plural };
+            write!(f, "{} {}", count, description)?;
+        }
+        if first {
+            f.write_str("no changes")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_summary() {
+        let mut report = RunReport::default();
+        assert!(report.is_empty());
+        assert_eq!(report.to_string(), "no changes");
+
+        report.installed_packages = vec!["vim".to_owned(), "zsh".to_owned()];
+        report.links = vec![PathBuf::from("/home/user/.vimrc")];
+        assert!(!report.is_empty());
+        assert_eq!(report.to_string(), "2 packages installed, 1 link created");
+    }
+
+    #[test]
+    fn merge_reports() {
+        let mut report = RunReport {
+            links: vec![PathBuf::from("/home/user/.vimrc")],
+            ..RunReport::default()
+        };
+        report.merge(RunReport {
+            links: vec![PathBuf::from("/home/user/.zshrc")],
+            enabled_services: vec!["sshd".to_owned()],
+            ..RunReport::default()
+        });
+        assert_eq!(report.to_string(), "2 links created, 1 service enabled");
+    }
+}
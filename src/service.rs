@@ -1,85 +1,441 @@
 //! Managing the state of systemd services.
 
-use std::{collections::HashSet, process::Command};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    process::{Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use indexmap::IndexSet;
+use thiserror::Error;
 
-use crate::{args::ServiceArgs, config::Config};
+use crate::{
+    args::{RunMode, ServiceArgs},
+    backend::PackageBackend,
+    config::Config,
+    report::RunReport,
+    util,
+};
+
+/// Declared services that need to be enabled, grouped by whether they should be started.
+struct ServicesToEnable<'a> {
+    /// Services that are currently masked and have to be unmasked before they are enabled.
+    unmask: Vec<&'a str>,
+    /// Services that should only be enabled.
+    enable: Vec<&'a str>,
+    /// Services that should be enabled and started.
+    enable_and_start: Vec<&'a str>,
+    /// Services that are already running.
+    active: Vec<&'a str>,
+}
+
+/// A failure of `systemctl` that might not happen again if it is retried.
+#[derive(Debug, Error)]
+#[error("systemctl failed to communicate with the system manager: {0}")]
+struct TransientError(String);
+
+/// Parts of `systemctl` error messages that indicate a transient failure, e.g. right after boot.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "Failed to connect to bus",
+    "Transport endpoint is not connected",
+    "Connection timed out",
+    "Activation of org.freedesktop.systemd1 timed out",
+];
+
+/// The delay before the first retry of a transient failure, doubled for every following retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What should be done to a declared service, based on its status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Decision {
+    /// The service should be left alone.
+    Skip,
+    /// The service should be enabled.
+    Enable,
+    /// The service should be enabled and started.
+    EnableAndStart,
+}
 
 struct ServiceStatus {
     /// The service is set to run at every boot.
     enabled: bool,
     /// The service is currently running.
     active: bool,
+    /// The service is masked, i.e. it cannot be started or enabled.
+    masked: bool,
 }
 
 /// Synchronizes enabled systemd services with the service list.
-pub fn synchronize_services(args: ServiceArgs, config: Config) -> anyhow::Result<()> {
-    let services = config.services();
-    warn_about_duplicate_services(&services.duplicates);
+pub fn synchronize_services(
+    args: ServiceArgs,
+    config: &Config,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<RunReport> {
+    let mut report = RunReport::default();
+    let mut services = config.services();
+    warn_about_duplicate_services("service", &services.duplicates);
+
+    if args.list_undeclared {
+        list_undeclared_services(&services.elements)
+            .context("Failed to list undeclared enabled services")?;
+        return Ok(report);
+    }
+    skip_unmet_requirements(
+        backend,
+        &mut services.elements,
+        &config.service_requirements(),
+    )?;
+
+    let retries = args.retries;
+    let start = args.start(config.default_start());
+    if args.explain {
+        let to_start = config.services_to_start();
+        explain_services(&services.elements, &to_start, start, retries)
+            .context("Failed to explain what would be done to declared services")?;
+        return Ok(report);
+    }
+    if args.daemon_reload || args.reset || start {
+        systemctl_daemon_reload(mode, retries).context("Failed to reload systemd unit files")?;
+    }
 
     if args.reset {
-        systemctl_preset_all()
+        systemctl_preset_all(mode, retries)
             .context("Failed to reset the enabled/disabled status of all services")?;
     }
 
-    let to_enable = find_services_to_enable(&services.elements, args.start)
+    let masked_services = config.masked_services();
+    warn_about_duplicate_services("masked service", &masked_services.duplicates);
+
+    let to_start = config.services_to_start();
+
+    let to_enable = find_services_to_enable(&services.elements, &to_start, start, retries)
         .context("Failed to determine the set of services to enable")?;
+    let to_mask = find_services_to_mask(&masked_services.elements, &services.elements, retries)
+        .context("Failed to determine the set of services to mask")?;
 
-    enable_services(&to_enable, args.start).context("Failed to enable declared services")?;
+    unmask_services(&to_enable.unmask, mode, retries)
+        .context("Failed to unmask declared services")?;
+    report.unmasked_services = to_owned(&to_enable.unmask);
+    enable_services(&to_enable.enable, false, mode, retries)
+        .context("Failed to enable declared services")?;
+    report.enabled_services = to_owned(&to_enable.enable);
+    enable_services(&to_enable.enable_and_start, true, mode, retries)
+        .context("Failed to enable and start declared services")?;
+    report
+        .enabled_services
+        .extend(to_owned(&to_enable.enable_and_start));
+    mask_services(&to_mask, mode, retries).context("Failed to mask declared services")?;
+    report.masked_services = to_owned(&to_mask);
 
-    Ok(())
+    if args.restart_changed {
+        restart_services(&to_enable.active, mode, retries)
+            .context("Failed to restart declared services")?;
+        report.restarted_services = to_owned(&to_enable.active);
+    }
+
+    Ok(report)
 }
 
-fn warn_about_duplicate_services(duplicates: &HashSet<&str>) {
-    for duplicate in duplicates {
-        warn!("service {:?} declared multiple times", duplicate);
+fn to_owned(services: &[&str]) -> Vec<String> {
+    services.iter().map(|&service| service.to_owned()).collect()
+}
+
+/// Warns about all services of the given `kind` that were declared more than once, in one line.
+fn warn_about_duplicate_services(kind: &str, duplicates: &IndexSet<&str>) {
+    let duplicates: Vec<_> = duplicates.iter().collect();
+    util::warn_about_all(
+        &duplicates,
+        kind,
+        &format!("{}s", kind),
+        "declared multiple times",
+    );
+}
+
+/// Removes the `declared` services that require a package that is not installed.
+///
+/// An unmet requirement is not an error, the service is only skipped with a message. All required
+/// packages are checked with a single query.
+fn skip_unmet_requirements(
+    backend: &dyn PackageBackend,
+    declared: &mut IndexSet<&str>,
+    requirements: &HashMap<&str, Vec<&str>>,
+) -> anyhow::Result<()> {
+    let required: IndexSet<&str> = declared
+        .iter()
+        .filter_map(|service| requirements.get(service))
+        .flatten()
+        .copied()
+        .collect();
+    if required.is_empty() {
+        return Ok(());
     }
+    let required: Vec<_> = required.into_iter().collect();
+    let installed = backend
+        .installed(&required)
+        .context("Failed to check which required packages are installed")?;
+
+    declared.retain(|service| {
+        let packages = requirements.get(service).into_iter().flatten();
+        match packages
+            .copied()
+            .find(|package| !installed.contains(*package))
+        {
+            Some(package) => {
+                info!(
+                    "Skipping service {:?}, it requires package {:?}, which is not installed",
+                    service, package,
+                );
+                false
+            }
+            None => true,
+        }
+    });
+    Ok(())
 }
 
-fn systemctl_preset_all() -> anyhow::Result<()> {
+fn systemctl_daemon_reload(mode: RunMode, retries: u32) -> anyhow::Result<()> {
+    colour!("Reloading systemd unit files");
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("daemon-reload");
+    run_for_status(cmd, mode, retries)
+}
+
+fn systemctl_preset_all(mode: RunMode, retries: u32) -> anyhow::Result<()> {
     colour!("Resetting the enabled/disabled status of all services to their defaults");
     let mut cmd = Command::new("systemctl");
     cmd.arg("preset-all");
-    run_for_status(cmd)
+    run_for_status(cmd, mode, retries)
 }
 
+/// Determines which of the `declared` services need to be enabled.
+///
+/// A service is started if it is in `to_start` or if `start_all` is set.
 fn find_services_to_enable<'a>(
-    declared: &HashSet<&'a str>,
-    start: bool,
-) -> anyhow::Result<Vec<&'a str>> {
-    let mut to_enable = vec![];
+    declared: &IndexSet<&'a str>,
+    to_start: &HashSet<&str>,
+    start_all: bool,
+    retries: u32,
+) -> anyhow::Result<ServicesToEnable<'a>> {
+    let mut unmask = vec![];
+    let mut enable = vec![];
+    let mut enable_and_start = vec![];
+    let mut active = vec![];
     for service in declared.iter().copied() {
-        let status = check_service_status(service)
+        let start = start_all || to_start.contains(service);
+        let status = with_retries(retries, || check_service_status(service))
             .with_context(|| format!("Failed to query for status of service {:?}", service))?;
-        let should_enable = match (status.enabled, status.active) {
-            (false, _) => true,
-            (true, true) => false,
-            (true, false) => start,
-        };
-        if should_enable {
-            to_enable.push(service);
+        if status.masked {
+            unmask.push(service);
+        }
+        if status.active {
+            active.push(service);
+        }
+        match decide(&status, start).0 {
+            Decision::Skip => {}
+            Decision::Enable => enable.push(service),
+            Decision::EnableAndStart => enable_and_start.push(service),
         }
     }
-    to_enable.sort_unstable();
-    Ok(to_enable)
+    unmask.sort_unstable();
+    enable.sort_unstable();
+    enable_and_start.sort_unstable();
+    active.sort_unstable();
+    Ok(ServicesToEnable {
+        unmask,
+        enable,
+        enable_and_start,
+        active,
+    })
+}
+
+/// Decides what should be done to a declared service with the given `status`, and why.
+///
+/// `start` is set if the service should be started when it is enabled. Disabled services are
+/// always enabled, enabled services are only enabled again to start them if they are inactive.
+fn decide(status: &ServiceStatus, start: bool) -> (Decision, &'static str) {
+    match (status.enabled, status.active, start) {
+        (false, _, false) => (Decision::Enable, "disabled"),
+        (false, _, true) => (Decision::EnableAndStart, "disabled, start set"),
+        (true, true, _) => (Decision::Skip, "already enabled and active"),
+        (true, false, true) => (Decision::EnableAndStart, "enabled but inactive, start set"),
+        (true, false, false) => (Decision::Skip, "enabled but inactive, start not set"),
+    }
+}
+
+/// Prints the status of every `declared` service and what would be done to it, without changing
+/// anything.
+///
+/// A service would be started if it is in `to_start` or if `start_all` is set, see
+/// [`find_services_to_enable`].
+fn explain_services(
+    declared: &IndexSet<&str>,
+    to_start: &HashSet<&str>,
+    start_all: bool,
+    retries: u32,
+) -> anyhow::Result<()> {
+    if declared.is_empty() {
+        colour!("No declared services");
+        return Ok(());
+    }
+    let mut rows = Vec::with_capacity(declared.len());
+    for service in declared.iter().copied() {
+        let start = start_all || to_start.contains(service);
+        let status = with_retries(retries, || check_service_status(service))
+            .with_context(|| format!("Failed to query for status of service {:?}", service))?;
+        let (decision, reason) = decide(&status, start);
+        let decision = decision_str(decision, status.masked);
+        rows.push((service, status_str(&status), reason, decision));
+    }
+
+    let service_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let status_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+    colour!("Declared services:");
+    for (service, status, reason, decision) in rows {
+        println!(
+            "  {:<w0$}  {:<w1$}  {} -> {}",
+            service,
+            status,
+            reason,
+            decision,
+            w0 = service_width,
+            w1 = status_width,
+        );
+    }
+    Ok(())
+}
+
+/// Returns a short description of `status`, e.g. `enabled, active`.
+fn status_str(status: &ServiceStatus) -> String {
+    let enabled = if status.enabled {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    let active = if status.active { "active" } else { "inactive" };
+    if status.masked {
+        format!("{}, {}, masked", enabled, active)
+    } else {
+        format!("{}, {}", enabled, active)
+    }
+}
+
+/// Returns a description of what would be done to a service, e.g. `enable and start`.
+fn decision_str(decision: Decision, masked: bool) -> &'static str {
+    match (decision, masked) {
+        (Decision::Skip, false) => "skip",
+        (Decision::Skip, true) => "unmask",
+        (Decision::Enable, false) => "enable",
+        (Decision::Enable, true) => "unmask and enable",
+        (Decision::EnableAndStart, false) => "enable and start",
+        (Decision::EnableAndStart, true) => "unmask, enable and start",
+    }
+}
+
+/// Determines which of the services declared as masked need to be masked.
+///
+/// Services that are also declared as services to enable are never masked.
+fn find_services_to_mask<'a>(
+    declared_masked: &IndexSet<&'a str>,
+    declared: &IndexSet<&str>,
+    retries: u32,
+) -> anyhow::Result<Vec<&'a str>> {
+    let mut to_mask = vec![];
+    for service in declared_masked.iter().copied() {
+        if declared.contains(service) {
+            warn!(
+                "service {:?} declared both as enabled and as masked, not masking it",
+                service,
+            );
+            continue;
+        }
+        let status = with_retries(retries, || check_service_status(service))
+            .with_context(|| format!("Failed to query for status of service {:?}", service))?;
+        if !status.masked {
+            to_mask.push(service);
+        }
+    }
+    to_mask.sort_unstable();
+    Ok(to_mask)
+}
+
+/// Prints the enabled unit files that are not declared.
+fn list_undeclared_services(declared: &IndexSet<&str>) -> anyhow::Result<()> {
+    let mut cmd = Command::new("systemctl");
+    cmd.args([
+        "list-unit-files",
+        "--state=enabled",
+        "--no-legend",
+        "--plain",
+    ]);
+    let output = util::output(&mut cmd).context("Failed to run systemctl")?;
+    if !output.status.success() {
+        bail!("systemctl did not exit successfully");
+    }
+    let output =
+        String::from_utf8(output.stdout).context("systemctl output was not valid UTF-8")?;
+
+    let declared_units: HashSet<_> = declared.iter().flat_map(|s| unit_names(s)).collect();
+    let mut undeclared: Vec<_> = output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|unit| !declared_units.contains(*unit))
+        .collect();
+    undeclared.sort_unstable();
+
+    if undeclared.is_empty() {
+        colour!("No undeclared enabled services");
+    } else {
+        colour!("Undeclared enabled services:");
+        for unit in undeclared {
+            println!("  {}", unit);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the names of unit files that can correspond to a declared service.
+///
+/// The `.service` suffix can be omitted in the declaration, and instances of template units are
+/// listed by `systemctl list-unit-files` under the name of the template.
+fn unit_names(service: &str) -> Vec<String> {
+    let unit = if service.contains('.') {
+        service.to_owned()
+    } else {
+        format!("{}.service", service)
+    };
+    let template = match (unit.find('@'), unit.rfind('.')) {
+        (Some(at), Some(dot)) if at < dot => Some(format!("{}{}", &unit[..=at], &unit[dot..])),
+        _ => None,
+    };
+    [Some(unit), template].into_iter().flatten().collect()
 }
 
 fn check_service_status(service: &str) -> anyhow::Result<ServiceStatus> {
-    let enabled = Command::new("systemctl")
-        .args(["is-enabled", "-q", service])
-        .status()
-        .context("Failed to run systemctl")?
-        .success();
-    let active = Command::new("systemctl")
-        .args(["is-active", "-q", service])
-        .status()
-        .context("Failed to run systemctl")?
-        .success();
-    Ok(ServiceStatus { enabled, active })
-}
-
-fn enable_services(services: &[&str], start: bool) -> anyhow::Result<()> {
+    let enabled = run_query(&["is-enabled", "-q", service])?.status.success();
+    let active = run_query(&["is-active", "-q", service])?.status.success();
+    let unit_file_state = run_query(&["show", "--property=UnitFileState", "--value", service])?;
+    let masked = unit_file_state.status.success()
+        && matches!(
+            String::from_utf8_lossy(&unit_file_state.stdout).trim(),
+            "masked" | "masked-runtime"
+        );
+    Ok(ServiceStatus {
+        enabled,
+        active,
+        masked,
+    })
+}
+
+fn enable_services(
+    services: &[&str],
+    start: bool,
+    mode: RunMode,
+    retries: u32,
+) -> anyhow::Result<()> {
     if services.is_empty() {
         return Ok(());
     }
@@ -103,22 +459,135 @@ fn enable_services(services: &[&str], start: bool) -> anyhow::Result<()> {
         cmd.arg("--now");
     }
     cmd.args(services);
-    run_for_status(cmd)
+    run_for_status(cmd, mode, retries)
+}
+
+fn unmask_services(services: &[&str], mode: RunMode, retries: u32) -> anyhow::Result<()> {
+    if services.is_empty() {
+        return Ok(());
+    }
+
+    colour!(
+        "Unmasking {} {}",
+        services.len(),
+        services_str(services.len()),
+    );
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("unmask");
+    cmd.args(services);
+    run_for_status(cmd, mode, retries)
+}
+
+fn mask_services(services: &[&str], mode: RunMode, retries: u32) -> anyhow::Result<()> {
+    if services.is_empty() {
+        return Ok(());
+    }
+
+    colour!(
+        "Masking {} {}",
+        services.len(),
+        services_str(services.len())
+    );
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("mask");
+    cmd.args(services);
+    run_for_status(cmd, mode, retries)
+}
+
+fn restart_services(services: &[&str], mode: RunMode, retries: u32) -> anyhow::Result<()> {
+    if services.is_empty() {
+        return Ok(());
+    }
+
+    colour!(
+        "Restarting {} {}",
+        services.len(),
+        services_str(services.len()),
+    );
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("restart");
+    cmd.args(services);
+    run_for_status(cmd, mode, retries)
 }
 
 /// Runs the command, returning `Ok(())` if the command exits successfully.
 ///
-/// The input and output streams of the command are inherited from the current process. Emits output
-/// to mark the start and end of the command output.
-fn run_for_status(mut cmd: Command) -> anyhow::Result<()> {
-    bold!("======== RUNNING SYSTEMCTL ========");
-    let status = cmd.status();
-    bold!("===== END OF SYSTEMCTL OUTPUT =====");
-    match status {
-        Ok(exit_status) if exit_status.success() => Ok(()),
-        Ok(_) => Err(anyhow!("systemctl did not exit successfully")),
-        Err(err) => Err(err).context("Failed to run systemctl"),
+/// The input and output streams of the command are inherited from the current process, `stderr` is
+/// also captured to recognize transient failures. Emits output
+/// to mark the start and end of the command output. In a dry run the command is only printed, in
+/// unprivileged mode an error is returned without running the command. The command is killed if it
+/// runs for longer than the limit set with `--timeout`, see [`util::wait`].
+fn run_for_status(mut cmd: Command, mode: RunMode, retries: u32) -> anyhow::Result<()> {
+    if mode.is_dry_run() {
+        info!("Would run {:?}", cmd);
+        return Ok(());
+    }
+    if mode.is_unprivileged() {
+        bail!("Refusing to run systemctl with root privileges because of --no-sudo");
+    }
+    cmd.stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped());
+    with_retries(retries, || {
+        bold!("======== RUNNING SYSTEMCTL ========");
+        let output = util::collect_output_passing_stderr(&mut cmd);
+        bold!("===== END OF SYSTEMCTL OUTPUT =====");
+        let output = output.context("Failed to run systemctl")?;
+        check_transient(&output)?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("systemctl did not exit successfully"))
+        }
+    })
+}
+
+/// Runs `systemctl` with `args` to query the state of the system, capturing its `stdout`.
+///
+/// `stderr` is passed through, unless the command failed with a [`TransientError`].
+fn run_query(args: &[&str]) -> anyhow::Result<Output> {
+    let output =
+        util::output(Command::new("systemctl").args(args)).context("Failed to run systemctl")?;
+    check_transient(&output)?;
+    let _ = io::stderr().write_all(&output.stderr);
+    Ok(output)
+}
+
+/// Returns a [`TransientError`] if `output` is from a failed command that might succeed if retried.
+fn check_transient(output: &Output) -> Result<(), TransientError> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() || !is_transient(&stderr) {
+        return Ok(());
+    }
+    Err(TransientError(stderr.trim().replace('\n', "; ")))
+}
+
+/// Returns `true` if the error message of `systemctl` indicates a transient failure.
+///
+/// Failing to connect to the system manager is transient, unless systemd is not running at all.
+fn is_transient(stderr: &str) -> bool {
+    TRANSIENT_ERRORS.iter().any(|error| stderr.contains(error))
+        && !stderr.contains("System has not been booted with systemd")
+}
+
+/// Calls `f` until it succeeds, fails with an error other than [`TransientError`], or it was
+/// retried `retries` times.
+fn with_retries<T, F>(retries: u32, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    let mut delay = RETRY_DELAY;
+    for _ in 0..retries {
+        match f() {
+            Err(err) if err.is::<TransientError>() => {
+                info!("{}, retrying in {} s", err, delay.as_secs());
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
     }
+    f()
 }
 
 fn services_str(count: usize) -> &'static str {
@@ -128,3 +597,58 @@ fn services_str(count: usize) -> &'static str {
         "services"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_names_of_declared_services() {
+        assert_eq!(unit_names("sshd"), ["sshd.service"]);
+        assert_eq!(unit_names("fstrim.timer"), ["fstrim.timer"]);
+        assert_eq!(
+            unit_names("getty@tty1"),
+            ["getty@tty1.service", "getty@.service"]
+        );
+        assert_eq!(
+            unit_names("getty@tty1.service"),
+            ["getty@tty1.service", "getty@.service"]
+        );
+    }
+
+    #[test]
+    fn decide_what_to_do_with_services() {
+        let decide = |enabled, active, start| {
+            let status = ServiceStatus {
+                enabled,
+                active,
+                masked: false,
+            };
+            decide(&status, start).0
+        };
+        assert_eq!(decide(false, false, false), Decision::Enable);
+        assert_eq!(decide(false, true, false), Decision::Enable);
+        assert_eq!(decide(false, false, true), Decision::EnableAndStart);
+        assert_eq!(decide(true, true, false), Decision::Skip);
+        assert_eq!(decide(true, true, true), Decision::Skip);
+        assert_eq!(decide(true, false, false), Decision::Skip);
+        assert_eq!(decide(true, false, true), Decision::EnableAndStart);
+    }
+
+    #[test]
+    fn classify_systemctl_errors() {
+        assert!(is_transient(
+            "Failed to connect to bus: Connection refused\n"
+        ));
+        assert!(is_transient(
+            "Failed to enable unit: Transport endpoint is not connected\n"
+        ));
+        assert!(!is_transient(concat!(
+            "System has not been booted with systemd as init system (PID 1). Can't operate.\n",
+            "Failed to connect to bus: Host is down\n",
+        )));
+        assert!(!is_transient(
+            "Failed to enable unit: Unit file foo.service does not exist.\n"
+        ));
+    }
+}
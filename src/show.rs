@@ -1,54 +1,370 @@
 //! Displaying information about declared and currently installed packages.
 
-use std::{collections::HashSet, fmt::Display};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anstyle::{AnsiColor, Style};
+use anyhow::{bail, Context};
+use indexmap::IndexSet;
+use thiserror::Error;
 
 use crate::{
     args::ShowArgs,
-    config::Config,
-    packages::{self, InstalledPackages, OrganizedPackages},
+    backend::{PackageBackend, Upgrade},
+    config::{Config, Declaration},
+    packages::{self, IgnoredPackages, InstalledPackages, OrganizedPackages},
+    pacman,
+    state::{self, State},
+    util,
 };
 
-/// Prints out information about declared and installed packages.
-pub fn show_packages(args: ShowArgs, cfg: Config) -> anyhow::Result<()> {
-    let declared_packages = cfg.packages();
+/// Package databases older than this may not contain the latest versions of packages.
+const STALE_DATABASE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The destination of the output, along with whether headings written to it are styled.
+struct Output<'a> {
+    writer: &'a mut dyn Write,
+    style: bool,
+}
+
+impl Output<'_> {
+    /// Writes a line in the style of the `colour!` macro, if styling is enabled.
+    fn heading(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        let style = if self.style {
+            AnsiColor::Blue.on_default().bold()
+        } else {
+            Style::new()
+        };
+        writeln!(
+            self.writer,
+            "{}{}{}",
+            style.render(),
+            args,
+            style.render_reset()
+        )
+    }
+}
+
+impl Write for Output<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Prints out information about declared and installed packages, to stdout or to the file given
+/// with `--output`.
+pub fn show_packages(
+    args: ShowArgs,
+    cfg: &Config,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    match args.output.clone() {
+        Some(path) => {
+            let file = File::create(&path)
+                .with_context(|| format!("Failed to create the output file {:?}", path))?;
+            let mut writer = BufWriter::new(file);
+            let mut out = Output {
+                writer: &mut writer,
+                style: false,
+            };
+            let result = show_packages_to(&mut out, args, cfg, backend);
+            let flushed = writer.flush();
+            result?;
+            flushed.with_context(|| format!("Failed to write to the output file {:?}", path))
+        }
+        None => {
+            let mut out = Output {
+                writer: &mut io::stdout().lock(),
+                style: util::use_style(&io::stdout()),
+            };
+            show_packages_to(&mut out, args, cfg, backend)
+        }
+    }
+}
+
+fn show_packages_to(
+    out: &mut Output<'_>,
+    args: ShowArgs,
+    cfg: &Config,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    if args.explain_config {
+        print_declarations(out, &cfg.declarations()?)?;
+        return Ok(());
+    }
+    if args.list_hosts {
+        print_hosts(out, &cfg.hosts(), cfg.hostname())?;
+        return Ok(());
+    }
+    if args.effective {
+        return print_effective(out, cfg, args.no_groups, backend);
+    }
+    let exit_code = args.exit_code;
+    let cleanup = args.cleanup(cfg.default_cleanup());
+
+    let declared_packages = cfg.packages()?;
     let declared_groups = cfg.package_groups();
 
-    let installed = packages::query_packages().context("Failed to query for installed packages")?;
-    let group_packages = packages::query_groups(&declared_groups.elements)
-        .context("Failed to query for packages that belong to the declared package groups")?;
+    let resolved_groups = packages::groups_to_resolve(&declared_groups.elements, args.no_groups);
 
-    let declared = packages::merge_declared_packages(&declared_packages.elements, &group_packages);
-    let organized = packages::organize_packages(&declared.packages, &installed);
+    let (installed, group_packages) =
+        packages::query_packages_and_groups(backend, &resolved_groups)?;
+
+    let pacman_conf = args
+        .pacman_conf
+        .or_else(|| cfg.pacman_conf())
+        .unwrap_or_else(|| PathBuf::from(pacman::config::DEFAULT_PATH));
+    let ignored = packages::query_ignored(backend, &pacman_conf)
+        .context("Failed to determine the set of packages ignored by pacman")?;
 
-    // TODO warn about duplicate packages
+    let mut declared =
+        packages::merge_declared_packages(&declared_packages.elements, &group_packages);
+    declared.expand_patterns(installed.names())?;
+    let ignored = IgnoredPackages::new(ignored)?;
+    let mut organized = packages::organize_packages(&declared.packages, &installed, &ignored);
+    organized.exclude(&packages::compile_patterns(&args.exclude)?);
 
-    print_summary(&declared.packages, &installed, &organized);
+    packages::warn_about_duplicates(&declared_packages, &declared);
+
+    if let Some(package) = &args.why {
+        return print_why(out, package, &declared.packages, &installed, backend);
+    }
+    if args.summary_only {
+        print_compact_summary(out, &declared.packages, &installed, &organized)?;
+        return check_drift(exit_code, &organized, cleanup);
+    }
+
+    print_summary(out, &declared.packages, &installed, &organized)?;
     if args.all || args.to_install {
-        print_packages("Packages to install", &organized.to_install);
+        print_packages(out, "Packages to install", &organized.to_install)?;
     }
     if args.all || args.to_explicit {
         print_packages(
+            out,
             "Packages to mark as explicitly installed",
             &organized.to_mark_as_explicit,
-        );
+        )?;
     }
     if args.all || args.to_remove {
-        print_packages("Packages to remove", &organized.to_remove);
+        print_packages(out, "Packages to remove", &organized.to_remove)?;
     }
     if args.all || args.unneeded {
-        print_packages("Unneeded packages", &organized.unneeded);
+        print_packages(out, "Unneeded packages", &organized.unneeded)?;
+    }
+    if args.all || args.ignored {
+        print_packages(out, "Packages ignored by pacman", &organized.ignored)?;
+        print_protected(out, &organized.protected)?;
+        print_packages(out, "Undeclared foreign packages", &organized.foreign)?;
     }
+    if args.drift {
+        print_drift(out, &organized)?;
+    }
+    if args.only_explicit {
+        print_installed(
+            out,
+            "Explicitly installed packages",
+            &installed.explicit,
+            &declared.packages,
+        )?;
+    }
+    if args.only_deps {
+        print_installed(
+            out,
+            "Packages installed as dependencies",
+            &installed.dependencies,
+            &declared.packages,
+        )?;
+    }
+    if args.duplicates {
+        print_duplicates(out, &declared_packages.duplicates, &declared.duplicates)?;
+    }
+    if args.cascade {
+        let mut to_remove = organized.to_remove.clone();
+        to_remove.extend(&organized.unneeded);
+        let mut cascade = if to_remove.is_empty() {
+            vec![]
+        } else {
+            backend
+                .removal_preview(&to_remove, true)
+                .context("Failed to determine the packages that would be removed")?
+        };
+        cascade.sort_unstable();
+        print_packages(out, "Packages removed during cleanup", &cascade)?;
+    }
+    if args.updates {
+        warn_if_databases_are_stale(backend)?;
+        let mut upgrades = backend
+            .query_upgrades()
+            .context("Failed to query for outdated packages")?;
+        let aur_helper = cfg.aur_helper();
+        if let Some(helper) = aur_helper {
+            match backend.query_aur_upgrades(helper) {
+                Ok(aur_upgrades) => upgrades.extend(aur_upgrades),
+                Err(err) => warn!(
+                    "failed to query for outdated foreign packages, only showing updates from \
+                     the package databases: {:#}",
+                    anyhow::Error::from(err),
+                ),
+            }
+        }
+        upgrades.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        print_upgrades(out, &upgrades, aur_helper.is_some())?;
+    }
+    if args.changed {
+        let state = state::read(cfg.state_file()).context("Failed to read the state file")?;
+        let current = State::new(
+            declared_packages.elements.iter().copied(),
+            declared_groups.elements.iter().copied(),
+            cfg.services().elements,
+        );
+        print_changes(out, state.as_ref(), &current)?;
+    }
+
+    check_drift(exit_code, &organized, cleanup)
+}
+
+/// The installed packages differ from the declared ones, reported with `--exit-code`.
+///
+/// Unlike other errors, it makes the program exit with [`Drift::EXIT_CODE`].
+#[derive(Debug, Error)]
+#[error("Installed packages differ from the configuration: {0}")]
+pub struct Drift(String);
 
+impl Drift {
+    /// The exit code of the program when the installed packages differ from the declared ones.
+    pub const EXIT_CODE: i32 = 2;
+}
+
+/// With `--exit-code`, fails with [`Drift`] if the installed packages differ from the declared
+/// ones, see [`drift_counts`].
+fn check_drift(
+    exit_code: bool,
+    organized: &OrganizedPackages<'_>,
+    cleanup: bool,
+) -> anyhow::Result<()> {
+    let counts = drift_counts(organized, cleanup);
+    if !exit_code || counts.is_empty() {
+        return Ok(());
+    }
+    let counts: Vec<_> = counts
+        .iter()
+        .map(|(what, n)| format!("{} {}", n, what))
+        .collect();
+    Err(Drift(counts.join(", ")).into())
+}
+
+/// Returns the number of packages that the sync subcommand would act on, for every kind of action
+/// that has any. Unneeded packages are only included if `cleanup` is set.
+fn drift_counts(organized: &OrganizedPackages<'_>, cleanup: bool) -> Vec<(&'static str, usize)> {
+    let unneeded = if cleanup { organized.unneeded.len() } else { 0 };
+    [
+        ("to install", organized.to_install.len()),
+        (
+            "to mark as explicitly installed",
+            organized.to_mark_as_explicit.len(),
+        ),
+        ("to remove", organized.to_remove.len()),
+        ("unneeded", unneeded),
+    ]
+    .into_iter()
+    .filter(|&(_, n)| n > 0)
+    .collect()
+}
+
+/// Prints the names of all declared packages, with package groups resolved unless `no_groups` is
+/// set, one per line.
+fn print_effective(
+    out: &mut Output<'_>,
+    cfg: &Config,
+    no_groups: bool,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    let declared_packages = cfg.packages()?;
+    let resolved_groups = packages::groups_to_resolve(&cfg.package_groups().elements, no_groups);
+    let group_packages = packages::query_groups(backend, &resolved_groups)
+        .context("Failed to query for packages that belong to the declared package groups")?;
+    let declared = packages::merge_declared_packages(&declared_packages.elements, &group_packages);
+    util::warn_about_all(
+        &declared.patterns,
+        "glob pattern",
+        "glob patterns",
+        "left out of the declared packages",
+    );
+
+    let mut packages: Vec<_> = declared.packages.into_iter().collect();
+    packages.sort_unstable();
+    for package in packages {
+        writeln!(out, "{}", package)?;
+    }
+    Ok(())
+}
+
+/// Prints whether `package` is declared and how it was installed, along with the tree of packages
+/// that depend on it.
+fn print_why(
+    out: &mut Output<'_>,
+    package: &str,
+    declared: &HashSet<&str>,
+    installed: &InstalledPackages,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<()> {
+    let install_reason = if installed.explicit.contains(package) {
+        "explicitly installed"
+    } else if installed.foreign.contains(package) {
+        "explicitly installed, foreign"
+    } else if installed.dependencies.contains(package) {
+        "installed as a dependency"
+    } else {
+        bail!("Package {:?} is not installed", package);
+    };
+    let declared = if declared.contains(package) {
+        "declared"
+    } else {
+        "not declared"
+    };
+    out.heading(format_args!(
+        "{}: {}, {}",
+        package, install_reason, declared
+    ))?;
+
+    let tree = backend
+        .why(package)
+        .with_context(|| format!("Failed to find the packages that depend on {:?}", package))?;
+    match tree.split_first() {
+        Some((_, [])) | None if installed.dependencies.contains(package) => {
+            writeln!(out, "  not required by any package")?;
+        }
+        Some((_, [])) | None => writeln!(
+            out,
+            "  only explicitly installed, not required by any package"
+        )?,
+        Some(_) => {
+            writeln!(out, "  required by:")?;
+            for line in &tree[1..] {
+                writeln!(out, "  {}", line)?;
+            }
+        }
+    }
     Ok(())
 }
 
 fn print_summary(
+    out: &mut Output<'_>,
     declared: &HashSet<&str>,
     installed: &InstalledPackages,
     organized: &OrganizedPackages<'_>,
-) {
+) -> io::Result<()> {
     let summary = [
         ("declared", declared.len()),
         (
@@ -64,6 +380,8 @@ fn print_summary(
         ),
         ("to remove", organized.to_remove.len()),
         ("unneeded", organized.unneeded.len()),
+        ("ignored by pacman", organized.ignored.len()),
+        ("foreign, undeclared", organized.foreign.len()),
     ];
 
     let what_width = summary.iter().map(|&(what, _)| what.len()).max().unwrap();
@@ -73,30 +391,516 @@ fn print_summary(
         .max()
         .unwrap();
 
-    colour!("Packages:");
+    out.heading(format_args!("Packages:"))?;
     for &(what, n) in summary.iter() {
-        println!(
+        writeln!(
+            out,
             "  {what:what_width$} : {n:n_width$}",
             what = what,
             what_width = what_width,
             n = n,
             n_width = n_width
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the summary as a single line of `key=count` pairs, meant to be read by scripts.
+fn print_compact_summary(
+    out: &mut Output<'_>,
+    declared: &HashSet<&str>,
+    installed: &InstalledPackages,
+    organized: &OrganizedPackages<'_>,
+) -> io::Result<()> {
+    let summary = [
+        ("declared", declared.len()),
+        (
+            "installed",
+            installed.explicit.len() + installed.dependencies.len(),
+        ),
+        ("explicit", installed.explicit.len()),
+        ("dependencies", installed.dependencies.len()),
+        ("install", organized.to_install.len()),
+        ("mark_explicit", organized.to_mark_as_explicit.len()),
+        ("remove", organized.to_remove.len()),
+        ("unneeded", organized.unneeded.len()),
+        ("ignored", organized.ignored.len()),
+        ("foreign", organized.foreign.len()),
+    ];
+
+    let line: Vec<_> = summary
+        .iter()
+        .map(|(key, n)| format!("{}={}", key, n))
+        .collect();
+    writeln!(out, "{}", line.join(" "))?;
+    Ok(())
+}
+
+fn warn_if_databases_are_stale(backend: &dyn PackageBackend) -> anyhow::Result<()> {
+    match backend.database_age()? {
+        Some(age) if age > STALE_DATABASE_AGE => warn!(
+            "package databases were last refreshed {} hours ago, run the sync subcommand to \
+             refresh them",
+            age.as_secs() / 3600,
+        ),
+        Some(_) => {}
+        None => warn!("package databases were never refreshed, run the sync subcommand first"),
+    }
+    Ok(())
+}
+
+/// Prints the pending `upgrades`, each labelled with its origin, `repo` or `aur`, if `labelled` is
+/// set.
+fn print_upgrades(out: &mut Output<'_>, upgrades: &[Upgrade], labelled: bool) -> io::Result<()> {
+    if upgrades.is_empty() {
+        out.heading(format_args!("No packages to upgrade"))?;
+        return Ok(());
+    }
+
+    let name_width = upgrades.iter().map(|u| u.name.len()).max().unwrap();
+    let version_width = upgrades
+        .iter()
+        .map(|u| u.installed_version.len())
+        .max()
+        .unwrap();
+    let new_version_width = upgrades.iter().map(|u| u.new_version.len()).max().unwrap();
+    out.heading(format_args!("Packages to upgrade:"))?;
+    for upgrade in upgrades {
+        if !labelled {
+            writeln!(
+                out,
+                "  {:name_width$} : {:version_width$} -> {}",
+                upgrade.name,
+                upgrade.installed_version,
+                upgrade.new_version,
+                name_width = name_width,
+                version_width = version_width,
+            )?;
+            continue;
+        }
+        let origin = if upgrade.foreign { "aur" } else { "repo" };
+        writeln!(
+            out,
+            "  {:name_width$} : {:version_width$} -> {:new_version_width$}  {}",
+            upgrade.name,
+            upgrade.installed_version,
+            upgrade.new_version,
+            origin,
+            name_width = name_width,
+            version_width = version_width,
+            new_version_width = new_version_width,
+        )?;
+    }
+    Ok(())
+}
+
+fn print_drift(out: &mut Output<'_>, organized: &OrganizedPackages<'_>) -> io::Result<()> {
+    let explicit = &organized.to_mark_as_explicit;
+    let dependencies = &organized.to_remove;
+    if explicit.is_empty() && dependencies.is_empty() {
+        out.heading(format_args!("No install reason drift"))?;
+        return Ok(());
+    }
+
+    let width = explicit
+        .iter()
+        .chain(dependencies)
+        .map(|p| p.len())
+        .max()
+        .unwrap();
+    out.heading(format_args!("Install reason drift:"))?;
+    for package in explicit {
+        writeln!(
+            out,
+            "  {:width$} : dependency -> explicit",
+            package,
+            width = width
+        )?;
+    }
+    for package in dependencies {
+        writeln!(
+            out,
+            "  {:width$} : explicit -> dependency",
+            package,
+            width = width
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the declarations that differ between the `last` applied state and the `current` one.
+fn print_changes(out: &mut Output<'_>, last: Option<&State>, current: &State) -> io::Result<()> {
+    let last = match last {
+        Some(last) => last,
+        None => {
+            out.heading(format_args!("No previous sync recorded"))?;
+            return Ok(());
+        }
+    };
+
+    let kinds = [
+        ("package", &last.packages, &current.packages),
+        ("group", &last.package_groups, &current.package_groups),
+        ("service", &last.services, &current.services),
+    ];
+    let changes: Vec<_> = kinds
+        .iter()
+        .flat_map(|&(kind, last, current)| set_changes(kind, last, current))
+        .collect();
+    let age = last.age().as_secs() / 3600;
+    if changes.is_empty() {
+        out.heading(format_args!(
+            "No declarations changed since the last sync {} hours ago",
+            age
+        ))?;
+        return Ok(());
+    }
+
+    let kind_width = changes.iter().map(|(_, kind, _)| kind.len()).max().unwrap();
+    out.heading(format_args!(
+        "Declarations changed since the last sync {} hours ago:",
+        age
+    ))?;
+    for (sign, kind, name) in changes {
+        writeln!(
+            out,
+            "  {} {:kind_width$} {}",
+            sign,
+            kind,
+            name,
+            kind_width = kind_width
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the elements added to and removed from a set, marked with `+` and `-` respectively.
+fn set_changes<'a>(
+    kind: &'a str,
+    last: &'a BTreeSet<String>,
+    current: &'a BTreeSet<String>,
+) -> impl Iterator<Item = (char, &'a str, &'a str)> {
+    let added = current
+        .difference(last)
+        .map(move |name| ('+', kind, name.as_str()));
+    let removed = last
+        .difference(current)
+        .map(move |name| ('-', kind, name.as_str()));
+    added.chain(removed)
+}
+
+fn print_declarations(out: &mut Output<'_>, declarations: &[Declaration<'_>]) -> io::Result<()> {
+    let rows: Vec<_> = declarations
+        .iter()
+        .map(|declaration| {
+            let groups = if declaration.groups.is_empty() {
+                String::from("-")
+            } else {
+                declaration.groups.join(".")
+            };
+            [
+                declaration.kind.to_owned(),
+                declaration.section.to_string(),
+                groups,
+                declaration.value.clone(),
+            ]
+        })
+        .collect();
+
+    let header = ["KIND", "SECTION", "GROUP", "DECLARATION"].map(String::from);
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        writeln!(
+            out,
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints every configured hostname and the values it has sections in, marking `this_host`.
+fn print_hosts(
+    out: &mut Output<'_>,
+    hosts: &[(&OsStr, Vec<&str>)],
+    this_host: &OsStr,
+) -> io::Result<()> {
+    if hosts.is_empty() {
+        out.heading(format_args!("No host-specific sections"))?;
+        return Ok(());
+    }
+    let width = hosts
+        .iter()
+        .map(|(host, _)| host.to_string_lossy().chars().count())
+        .max()
+        .unwrap_or(0);
+    for (host, values) in hosts {
+        let marker = if *host == this_host { '*' } else { ' ' };
+        writeln!(
+            out,
+            "{} {:<width$}  {}",
+            marker,
+            host.to_string_lossy(),
+            values.join(", "),
+            width = width,
+        )?;
+    }
+    if is_hostname_unmatched(hosts, this_host) {
+        warn!(
+            "there are no sections for the hostname of this machine, {:?}",
+            this_host
         );
     }
+    Ok(())
+}
+
+/// Returns `true` if there are host-specific sections, but none of them are for `this_host`.
+///
+/// A configuration without any host-specific sections, using only `common` sections, is not a
+/// mismatch.
+fn is_hostname_unmatched(hosts: &[(&OsStr, Vec<&str>)], this_host: &OsStr) -> bool {
+    !hosts.is_empty() && !hosts.iter().any(|(host, _)| *host == this_host)
+}
+
+fn print_installed(
+    out: &mut Output<'_>,
+    what: &str,
+    installed: &HashSet<String>,
+    declared: &HashSet<&str>,
+) -> io::Result<()> {
+    if installed.is_empty() {
+        out.heading(format_args!("No {}", what.to_lowercase()))?;
+        return Ok(());
+    }
+
+    let mut installed: Vec<_> = installed.iter().collect();
+    installed.sort_unstable();
+    let width = installed.iter().map(|p| p.len()).max().unwrap();
+    out.heading(format_args!("{}:", what))?;
+    for package in installed {
+        let status = if declared.contains(package.as_str()) {
+            "declared"
+        } else {
+            "undeclared"
+        };
+        writeln!(out, "  {:width$} : {}", package, status, width = width)?;
+    }
+    Ok(())
+}
+
+fn print_duplicates(
+    out: &mut Output<'_>,
+    duplicates: &IndexSet<&str>,
+    group_duplicates: &HashMap<&str, &str>,
+) -> io::Result<()> {
+    let mut duplicates: Vec<_> = duplicates.iter().collect();
+    duplicates.sort_unstable();
+    print_packages(out, "Packages declared more than once", duplicates)?;
+
+    if group_duplicates.is_empty() {
+        out.heading(format_args!(
+            "No packages declared both directly and as a member of a group"
+        ))?;
+        return Ok(());
+    }
+    let mut group_duplicates: Vec<_> = group_duplicates.iter().collect();
+    group_duplicates.sort_unstable();
+    let width = group_duplicates.iter().map(|(p, _)| p.len()).max().unwrap();
+    out.heading(format_args!(
+        "Packages declared both directly and as a member of a group:"
+    ))?;
+    for (package, group) in group_duplicates {
+        writeln!(out, "  {:width$} : {}", package, group, width = width)?;
+    }
+    Ok(())
+}
+
+fn print_protected(out: &mut Output<'_>, protected: &[(&str, &str)]) -> io::Result<()> {
+    if protected.is_empty() {
+        return Ok(());
+    }
+
+    let width = protected.iter().map(|(p, _)| p.len()).max().unwrap();
+    out.heading(format_args!("Packages protected from removal by patterns:"))?;
+    for (package, pattern) in protected {
+        writeln!(out, "  {:width$} : {}", package, pattern, width = width)?;
+    }
+    Ok(())
 }
 
-fn print_packages<I, P>(what: &str, packages: I)
+fn print_packages<I, P>(out: &mut Output<'_>, what: &str, packages: I) -> io::Result<()>
 where
     I: IntoIterator<Item = P>,
     P: Display,
 {
     let mut packages = packages.into_iter().peekable();
     if packages.peek().is_none() {
-        colour!("No {}", what.to_lowercase())
+        out.heading(format_args!("No {}", what.to_lowercase()))?;
     } else {
-        colour!("{}:", what);
+        out.heading(format_args!("{}:", what))?;
         for package in packages {
-            println!("  {}", package);
+            writeln!(out, "  {}", package)?;
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_drift() {
+        let mut organized = OrganizedPackages {
+            to_install: vec![],
+            to_mark_as_explicit: vec![],
+            to_remove: vec![],
+            unneeded: vec!["libfoo"],
+            ignored: vec!["linux"],
+            protected: vec![],
+            foreign: vec!["yay"],
+        };
+        assert_eq!(drift_counts(&organized, false), []);
+        assert_eq!(drift_counts(&organized, true), [("unneeded", 1)]);
+        assert!(check_drift(true, &organized, false).is_ok());
+        assert!(check_drift(false, &organized, true).is_ok());
+
+        organized.to_install = vec!["vim", "zsh"];
+        organized.to_remove = vec!["nano"];
+        assert_eq!(
+            drift_counts(&organized, false),
+            [("to install", 2), ("to remove", 1)]
+        );
+        let err = check_drift(true, &organized, true).unwrap_err();
+        assert!(err.is::<Drift>());
+        assert_eq!(
+            err.to_string(),
+            "Installed packages differ from the configuration: 2 to install, 1 to remove, \
+             1 unneeded"
+        );
+    }
+
+    #[test]
+    fn print_to_buffer() {
+        let mut buffer = Vec::new();
+        let mut out = Output {
+            writer: &mut buffer,
+            style: false,
+        };
+        print_packages(&mut out, "Packages to install", ["vim", "zsh"]).unwrap();
+        print_packages(&mut out, "Packages to remove", Vec::<&str>::new()).unwrap();
+        print_protected(&mut out, &[("linux", "linux*")]).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "Packages to install:\n  vim\n  zsh\nNo packages to remove\n\
+             Packages protected from removal by patterns:\n  linux : linux*\n"
+        );
+    }
+
+    #[test]
+    fn print_summary_and_drift() {
+        let set = |packages: &[&str]| packages.iter().map(|&p| p.to_owned()).collect();
+        let installed = InstalledPackages {
+            explicit: set(&["base", "nano", "vim"]),
+            dependencies: set(&["glibc", "libfoo", "zsh"]),
+            unneeded: set(&["libfoo"]),
+            foreign: set(&["yay"]),
+        };
+        let declared = HashSet::from(["base", "git", "vim", "zsh"]);
+        let organized = OrganizedPackages {
+            to_install: vec!["git"],
+            to_mark_as_explicit: vec!["zsh"],
+            to_remove: vec!["nano"],
+            unneeded: vec!["libfoo"],
+            ignored: vec![],
+            protected: vec![],
+            foreign: vec!["yay"],
+        };
+
+        let mut buffer = Vec::new();
+        let mut out = Output {
+            writer: &mut buffer,
+            style: false,
+        };
+        print_summary(&mut out, &declared, &installed, &organized).unwrap();
+        print_drift(&mut out, &organized).unwrap();
+        print_compact_summary(&mut out, &declared, &installed, &organized).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "\
+Packages:
+  declared                        : 4
+  installed                       : 6
+    explicitly                    : 3
+    as dependencies               : 3
+  to install                      : 1
+  to mark as explicitly installed : 1
+  to remove                       : 1
+  unneeded                        : 1
+  ignored by pacman               : 0
+  foreign, undeclared             : 1
+Install reason drift:
+  zsh  : dependency -> explicit
+  nano : explicit -> dependency
+declared=4 installed=6 explicit=3 dependencies=3 install=1 mark_explicit=1 remove=1 unneeded=1 \
+ignored=0 foreign=1
+"
+        );
+    }
+
+    #[test]
+    fn print_labelled_upgrades() {
+        let upgrade = |name: &str, installed: &str, new: &str, foreign| Upgrade {
+            name: name.to_owned(),
+            installed_version: installed.to_owned(),
+            new_version: new.to_owned(),
+            foreign,
+        };
+        let upgrades = [
+            upgrade("linux", "6.2.7-1", "6.2.8-1", false),
+            upgrade("yay", "12.0-1", "12.1-1", true),
+        ];
+
+        let mut buffer = Vec::new();
+        let mut out = Output {
+            writer: &mut buffer,
+            style: false,
+        };
+        print_upgrades(&mut out, &upgrades, false).unwrap();
+        print_upgrades(&mut out, &upgrades, true).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "\
+Packages to upgrade:
+  linux : 6.2.7-1 -> 6.2.8-1
+  yay   : 12.0-1  -> 12.1-1
+Packages to upgrade:
+  linux : 6.2.7-1 -> 6.2.8-1  repo
+  yay   : 12.0-1  -> 12.1-1   aur
+"
+        );
+    }
+
+    #[test]
+    fn hostname_mismatch() {
+        let this_host = OsStr::new("archbox");
+        assert!(!is_hostname_unmatched(&[], this_host));
+
+        let other = || (OsStr::new("laptop"), vec!["packages"]);
+        assert!(is_hostname_unmatched(&[other()], this_host));
+
+        let matching = (this_host, vec!["links", "packages"]);
+        assert!(!is_hostname_unmatched(&[matching, other()], this_host));
+    }
 }
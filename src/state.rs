@@ -0,0 +1,122 @@
+//! Remembering the declarations applied by the last successful sync.
+//!
+//! The state file lets us tell apart changes made to the configuration file from changes made to
+//! the system since the last sync.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::ErrorKind,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The version of the state file format, bumped on every incompatible change.
+const STATE_VERSION: u32 = 1;
+
+/// Declarations applied by a successful sync.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct State {
+    /// The version of the state file format.
+    version: u32,
+    /// The time of the sync, in seconds since the Unix epoch.
+    timestamp: u64,
+    /// The declared packages.
+    pub packages: BTreeSet<String>,
+    /// The declared package groups.
+    pub package_groups: BTreeSet<String>,
+    /// The declared services.
+    pub services: BTreeSet<String>,
+}
+
+impl State {
+    /// Creates a state recorded at the current time.
+    pub fn new<'a, P, G, S>(packages: P, package_groups: G, services: S) -> Self
+    where
+        P: IntoIterator<Item = &'a str>,
+        G: IntoIterator<Item = &'a str>,
+        S: IntoIterator<Item = &'a str>,
+    {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            version: STATE_VERSION,
+            timestamp,
+            packages: packages.into_iter().map(String::from).collect(),
+            package_groups: package_groups.into_iter().map(String::from).collect(),
+            services: services.into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Returns the time that passed since the state was recorded.
+    pub fn age(&self) -> Duration {
+        let recorded = UNIX_EPOCH + Duration::from_secs(self.timestamp);
+        recorded.elapsed().unwrap_or_default()
+    }
+}
+
+/// Reads the state file at `path`.
+///
+/// Returns `None` if the file does not exist. A file that cannot be parsed or that uses an older
+/// format is treated as missing, with a warning.
+pub fn read(path: &Path) -> anyhow::Result<Option<State>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read the contents of file {:?}", path))
+        }
+    };
+    Ok(parse(&contents, path))
+}
+
+fn parse(contents: &str, path: &Path) -> Option<State> {
+    match toml::from_str::<State>(contents) {
+        Ok(state) if state.version == STATE_VERSION => Some(state),
+        Ok(_) | Err(_) => {
+            warn!("ignoring the state file {:?} in an unknown format", path);
+            None
+        }
+    }
+}
+
+/// Writes `state` to the state file at `path`, creating its parent directories if needed.
+pub fn write(path: &Path, state: &State) -> anyhow::Result<()> {
+    let serialized = toml::to_string(state).context("Failed to serialize the state")?;
+    let contents = format!("# Generated by archman, do not edit.\n{}", serialized);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create the parent directory of {:?}", path))?;
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write to {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_round_trip() {
+        let state = State::new(["vim", "base"], ["gnome"], ["sshd"]);
+        let serialized = toml::to_string(&state).unwrap();
+        let parsed = parse(&serialized, Path::new("last-run.toml")).unwrap();
+        assert_eq!(parsed.timestamp, state.timestamp);
+        assert_eq!(parsed.packages, state.packages);
+        assert_eq!(parsed.package_groups, state.package_groups);
+        assert_eq!(parsed.services, state.services);
+    }
+
+    #[test]
+    fn unknown_state_format() {
+        let path = Path::new("last-run.toml");
+        assert!(parse("version = 0\ntimestamp = 0\npackages = []\n", path).is_none());
+        assert!(parse("not a state file", path).is_none());
+    }
+}
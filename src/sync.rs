@@ -6,75 +6,370 @@
 //! e.g. for packages that are build dependencies of some AUR packages. One day this might be
 //! addressed.
 //!
-//! Right now we do not concern ourselves with AUR packages.
+//! Right now we do not concern ourselves with AUR packages. Foreign packages, i.e. ones that are
+//! not in any package database, are never installed or removed, declared ones count as installed.
 //!
 //! For now this is what we do:
 //! - mark declared packages that are installed as dependencies as explicitly installed
 //! - mark explicitly installed packages that are not declared as installed as dependencies
 //! - update packages and install declared packages that are not installed
-//! - remove explicitly installed packages that are not declared
+//! - remove explicitly installed packages that are not declared, along with their dependencies
+//!   that are no longer needed, unless `--no-recursive` is given
 //! - if doing cleanup, also remove packages installed as dependencies that are not declared and
 //!   not required by other packages
+//! - if pruning the cache, remove old versions of packages from the package cache
+//! - record the declared packages, package groups and services in the state file, unless only part
+//!   of the configuration was applied, e.g. with `--exclude` or `--no-groups`, or because some
+//!   packages were skipped with `--interactive` or `--explain-removal`
+//! - recommend a reboot if the running kernel is no longer installed, or if any of the packages
+//!   configured with `reboot_packages` was upgraded
+//!
+//! If `pacman` does not exit successfully in any of these steps, we warn about it and continue with
+//! the next step, unless `--strict` was given, in which case we stop. The state file is only
+//! written if all of the steps succeeded.
+//!
+//! Packages ignored by `pacman` through the `IgnorePkg` and `IgnoreGroup` directives are left
+//! alone. Entries of `IgnorePkg` and `--ignore` that are glob patterns, like `linux*`, only protect
+//! the packages they match from removal.
+//!
+//! With `--interactive`, the user chooses which of the packages to act upon before the first step.
+//! With `--reasons-only`, only the first two steps are done. With `--no-groups`, packages that
+//! belong to declared package groups are not queried, so nothing is marked as a dependency or
+//! removed.
 //!
 //! Bonus step:
-//! - check if the xkb_types file needs to be patched
+//! - check if the xkb_types file needs to be patched, unless `xkb_patch` is turned off
 
-use std::{fs, path::Path};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
+use indexmap::IndexSet;
+use is_terminal::IsTerminal;
 use regex::Regex;
 
 use crate::{
-    args::SyncArgs,
+    args::{RunMode, SyncArgs},
+    backend::{self, BackendError, InstallReason, PackageBackend, SyncOptions},
     config::Config,
-    packages::{self, OrganizedPackages},
-    pacman::{self, InstallReason, PacmanError},
+    packages::{self, IgnoredPackages, InstalledPackages, OrganizedPackages},
+    pacman,
+    report::RunReport,
+    state::{self, State},
+    util,
 };
 
+/// The file containing the release of the running kernel, as printed by `uname -r`.
+const OSRELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+/// The directory with a subdirectory of modules for every installed kernel, named after its
+/// release.
+const MODULES_DIR: &str = "/usr/lib/modules";
+
 /// Synchronizes installed packages with the package list.
 ///
 /// See module documentation for the details.
-pub fn synchronize_packages(args: SyncArgs, cfg: Config) -> anyhow::Result<()> {
-    let declared_packages = cfg.packages();
+pub fn synchronize_packages(
+    args: SyncArgs,
+    cfg: &Config,
+    mode: RunMode,
+    backend: &dyn PackageBackend,
+) -> anyhow::Result<RunReport> {
+    let mut report = RunReport::default();
+    let cleanup = args.cleanup(cfg.default_cleanup());
+    let recursive = !args.no_recursive;
+    let system_upgrade = args.system_upgrade();
+    let mut partial = args.is_partial();
+    if args.prune_cache && !cleanup {
+        bail!("--prune-cache requires cleanup, enable it with --cleanup or defaults.sync.cleanup");
+    }
+    if args.interactive && !io::stdin().is_terminal() {
+        bail!("--interactive requires stdin to be a terminal");
+    }
+    let declared_packages = cfg.packages()?;
     let declared_groups = cfg.package_groups();
 
-    let installed = packages::query_packages().context("Failed to query for installed packages")?;
-    let group_packages = packages::query_groups(&declared_groups.elements)
-        .context("Failed to query for packages that belong to the declared package groups")?;
+    let resolved_groups = packages::groups_to_resolve(&declared_groups.elements, args.no_groups);
+
+    let (installed, group_packages) =
+        packages::query_packages_and_groups(backend, &resolved_groups)?;
+
+    let pacman_conf = args
+        .pacman_conf
+        .or_else(|| cfg.pacman_conf())
+        .unwrap_or_else(|| PathBuf::from(pacman::config::DEFAULT_PATH));
+    let mut ignored = packages::query_ignored(backend, &pacman_conf)
+        .context("Failed to determine the set of packages ignored by pacman")?;
+    ignored.extend(args.ignore.iter().cloned());
 
-    let declared = packages::merge_declared_packages(&declared_packages.elements, &group_packages);
-    let organized = packages::organize_packages(&declared.packages, &installed);
+    let available = if args.expand_globs {
+        backend
+            .available()
+            .context("Failed to query for packages in the package databases")?
+    } else {
+        HashSet::new()
+    };
+
+    let mut declared =
+        packages::merge_declared_packages(&declared_packages.elements, &group_packages);
+    declared.expand_patterns(installed.names())?;
+    declared.expand_patterns(available.iter().map(String::as_str))?;
+    let ignored = IgnoredPackages::new(ignored)?;
+    let mut organized = packages::organize_packages(&declared.packages, &installed, &ignored);
+    let excluded = packages::compile_patterns(&args.exclude)?;
+    organized.exclude(&excluded);
+    if args.no_groups && !declared_groups.elements.is_empty() {
+        // Packages that belong to the skipped groups would look undeclared
+        organized.to_remove.clear();
+        organized.unneeded.clear();
+    }
+    let scoped = !args.packages.is_empty();
+    let mut to_reinstall = if scoped {
+        let candidates =
+            packages::reinstall_candidates(&declared.packages, &installed, &ignored, &excluded);
+        scope_to_packages(
+            &args.packages,
+            args.allow_undeclared,
+            &declared.packages,
+            &installed,
+            candidates,
+            &mut organized,
+        )?
+    } else {
+        match &args.reinstall {
+            Some(only) => {
+                let candidates = packages::reinstall_candidates(
+                    &declared.packages,
+                    &installed,
+                    &ignored,
+                    &excluded,
+                );
+                select_reinstalled(candidates, only)
+            }
+            None => Vec::new(),
+        }
+    };
 
-    // TODO warn about duplicate packages
+    packages::warn_about_duplicates(&declared_packages, &declared);
+    warn_about_ignored_packages(&organized.ignored);
+    if !organized.foreign.is_empty() {
+        info!(
+            "Skipping {} undeclared foreign {}",
+            organized.foreign.len(),
+            packages_str(organized.foreign.len()),
+        );
+    }
 
-    update_database(&organized).context("Failed to update package database")?;
-    update_and_install_packages(args.no_upgrade, &organized.to_install)
-        .context("Failed to update and install new packages")?;
+    if args.interactive {
+        let accepted = select_actions(&mut organized, &mut to_reinstall, cleanup, &mut util::ask)
+            .context("Failed to ask which packages to act upon")?;
+        partial |= !accepted;
+    }
+
+    if args.reasons_only {
+        if organized.to_mark_as_explicit.is_empty() && organized.to_remove.is_empty() {
+            info!("Install reasons are already in sync");
+        }
+        update_database(backend, &organized, args.strict, mode, &mut report)
+            .context("Failed to update package database")?;
+        return Ok(report);
+    }
+
+    if organized.is_in_sync() && to_reinstall.is_empty() && !scoped {
+        if args.no_upgrade {
+            info!("Installed packages are already in sync, only updating package databases");
+        } else {
+            info!("Installed packages are already in sync, only upgrading packages");
+        }
+    }
+
+    let sync_options = SyncOptions {
+        force_refresh: args.force_refresh,
+        system_upgrade,
+        ignore: &args.ignore,
+        parallel_downloads: args.parallel_downloads,
+        config: Some(&pacman_conf),
+        capture_transaction: args.count_upgrades,
+    };
+    let unneeded: &[&str] = if cleanup { &organized.unneeded } else { &[] };
+    let mut to_remove = organized.to_remove.clone();
+    to_remove.extend(unneeded);
+
+    let mut to_install = organized.to_install.clone();
+    to_install.extend(&to_reinstall);
+
+    if args.preview {
+        preview_transaction(backend, sync_options, &to_install, &to_remove, recursive)
+            .context("Failed to preview the transaction")?;
+        return Ok(report);
+    }
+
+    check_partial_upgrade(&to_install, system_upgrade, args.allow_partial)?;
+
+    let reboot_packages = cfg.reboot_packages();
+    let versions_before = if mode.is_dry_run() {
+        Vec::new()
+    } else {
+        installed_versions(backend, &reboot_packages)?
+    };
+
+    let strict = args.strict;
+    let mut succeeded = update_database(backend, &organized, strict, mode, &mut report)
+        .context("Failed to update package database")?;
+    let transaction = update_and_install_packages(
+        backend,
+        sync_options,
+        &to_install,
+        to_reinstall.len(),
+        strict,
+        mode,
+    )
+    .context("Failed to update and install new packages")?;
+    succeeded &= transaction.is_some();
+    if let Some(transaction) = transaction {
+        report.installed_packages = to_owned(&organized.to_install);
+        report.reinstalled_packages = to_owned(&to_reinstall);
+        report.upgraded_packages = transaction
+            .into_iter()
+            .filter(|package| {
+                !to_install.contains(&package.as_str())
+                    && (installed.explicit.contains(package)
+                        || installed.dependencies.contains(package)
+                        || installed.foreign.contains(package))
+            })
+            .collect();
+    }
+    if !mode.is_dry_run() {
+        let versions_after = installed_versions(backend, &reboot_packages)?;
+        report.reboot_reasons = reboot_reasons(&versions_before, &versions_after);
+        if let Some(reason) = check_running_kernel() {
+            report.reboot_reasons.push(reason);
+        }
+    }
 
-    if args.cleanup {
-        let mut unneeded = organized.to_remove.clone();
-        unneeded.extend(&organized.unneeded);
-        remove_packages(&unneeded).context("Failed to remove packages")?;
+    let confirmed = !args.explain_removal
+        || explain_removal(
+            &organized.to_remove,
+            unneeded,
+            args.yes || mode.is_dry_run(),
+        )
+        .context("Failed to confirm the removal of packages")?;
+    if confirmed {
+        let removed = remove_packages(backend, &to_remove, recursive, strict, mode)
+            .context("Failed to remove packages")?;
+        if removed {
+            report.removed_packages = to_owned(&to_remove);
+        }
+        succeeded &= removed;
     } else {
-        remove_packages(&organized.to_remove).context("Failed to remove packages")?;
+        info!("Not removing any packages");
+        partial = true;
+    }
+
+    if args.prune_cache {
+        succeeded &=
+            prune_cache(backend, strict, mode).context("Failed to prune the package cache")?;
     }
 
     if let Some(xkb_types) = args.xkb_types.or_else(|| cfg.xkb_types()) {
-        patch_xkb_types(&xkb_types).context("Failed to patch the xkb types file")?;
+        patch_xkb_types(&xkb_types, mode).context("Failed to patch the xkb types file")?;
+    }
+
+    if !succeeded {
+        warn!("not recording the applied state, some of the steps failed");
+    } else if !mode.is_dry_run() && !partial {
+        let state = State::new(
+            declared_packages.elements.iter().copied(),
+            declared_groups.elements.iter().copied(),
+            cfg.services().elements,
+        );
+        if let Err(err) = state::write(cfg.state_file(), &state) {
+            warn!("failed to record the applied state: {:#}", err);
+        }
+    }
+
+    Ok(report)
+}
+
+fn warn_about_ignored_packages(ignored: &[&str]) {
+    if !ignored.is_empty() {
+        warn!(
+            "skipping {} {} ignored by pacman: {}",
+            ignored.len(),
+            packages_str(ignored.len()),
+            ignored.join(" "),
+        );
+    }
+}
+
+/// Prints the packages that would be installed, upgraded and removed, as resolved by the backend.
+fn preview_transaction(
+    backend: &dyn PackageBackend,
+    options: SyncOptions<'_>,
+    to_install: &[&str],
+    to_remove: &[&str],
+    recursive: bool,
+) -> anyhow::Result<()> {
+    let mut installed = backend.sync_preview(options, to_install)?;
+    installed.sort_unstable();
+    if installed.is_empty() {
+        colour!("No packages to install or upgrade");
+    } else {
+        colour!("Packages to install or upgrade:");
+        for package in &installed {
+            println!("  {}", package);
+        }
+    }
+
+    let mut removed = if to_remove.is_empty() {
+        Vec::new()
+    } else {
+        backend.removal_preview(to_remove, recursive)?
+    };
+    removed.sort_unstable();
+    if removed.is_empty() {
+        colour!("No packages to remove");
+    } else {
+        colour!("Packages to remove:");
+        for package in &removed {
+            println!("  {}", package);
+        }
     }
 
     Ok(())
 }
 
-/// Updates the install reason of already installed packages.
-fn update_database(organized: &OrganizedPackages<'_>) -> anyhow::Result<()> {
+/// Updates the install reason of already installed packages, recording the changes in `report`.
+///
+/// Returns `false` if the package manager failed, but we continued anyway.
+fn update_database(
+    backend: &dyn PackageBackend,
+    organized: &OrganizedPackages<'_>,
+    strict: bool,
+    mode: RunMode,
+    report: &mut RunReport,
+) -> anyhow::Result<bool> {
+    let mut succeeded = true;
     if !organized.to_mark_as_explicit.is_empty() {
         colour!(
             "Marking {} {} as explicitly installed",
             organized.to_mark_as_explicit.len(),
             packages_str(organized.to_mark_as_explicit.len()),
         );
-        pacman::database(InstallReason::Explicit, &organized.to_mark_as_explicit)?;
+        let marked = run_pacman_step(strict, || {
+            backend.set_install_reason(
+                InstallReason::Explicit,
+                &organized.to_mark_as_explicit,
+                mode,
+            )
+        })?;
+        if marked.is_some() {
+            report.marked_as_explicit = to_owned(&organized.to_mark_as_explicit);
+        }
+        succeeded &= marked.is_some();
     }
 
     if !organized.to_remove.is_empty() {
@@ -88,45 +383,322 @@ fn update_database(organized: &OrganizedPackages<'_>) -> anyhow::Result<()> {
                 "dependencies"
             },
         );
-        pacman::database(InstallReason::Dependency, &organized.to_remove)?;
+        let marked = run_pacman_step(strict, || {
+            backend.set_install_reason(InstallReason::Dependency, &organized.to_remove, mode)
+        })?;
+        if marked.is_some() {
+            report.marked_as_dependencies = to_owned(&organized.to_remove);
+        }
+        succeeded &= marked.is_some();
     }
 
-    Ok(())
+    Ok(succeeded)
+}
+
+/// Fails if packages would be installed without upgrading the installed ones, i.e. without a
+/// `system_upgrade`, unless the resulting partial upgrade is allowed with `--allow-partial`.
+fn check_partial_upgrade(
+    to_install: &[&str],
+    system_upgrade: bool,
+    allow_partial: bool,
+) -> anyhow::Result<()> {
+    if to_install.is_empty() || system_upgrade {
+        return Ok(());
+    }
+    if allow_partial {
+        warn!(
+            "installing {} {} without upgrading installed packages, this is a partial upgrade",
+            to_install.len(),
+            packages_str(to_install.len()),
+        );
+        return Ok(());
+    }
+    bail!(
+        "Refusing to install {} {} without upgrading installed packages: partial upgrades are not \
+         supported on Arch Linux and can leave packages that need newer libraries broken. Run \
+         without --no-upgrade and without naming packages, or accept the risk with \
+         --allow-partial",
+        to_install.len(),
+        packages_str(to_install.len()),
+    );
 }
 
 /// Updates installed packages and installs new ones.
-fn update_and_install_packages(no_upgrade: bool, to_install: &[&str]) -> anyhow::Result<()> {
-    let update_str = if no_upgrade {
-        "Updating package databases"
-    } else {
+///
+/// The last `reinstalled` packages of `to_install` are already installed and are reinstalled.
+/// Returns the packages in the transaction, if they were captured, or `None` if the package manager
+/// failed, but we continued anyway.
+fn update_and_install_packages(
+    backend: &dyn PackageBackend,
+    options: SyncOptions<'_>,
+    to_install: &[&str],
+    reinstalled: usize,
+    strict: bool,
+    mode: RunMode,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let update_str = if options.system_upgrade {
         "Upgrading installed packages"
+    } else {
+        "Updating package databases"
     };
+    let new = to_install.len() - reinstalled;
 
-    if !to_install.is_empty() {
-        colour!(
+    match (new, reinstalled) {
+        (0, 0) => colour!("{}", update_str),
+        (new, 0) => colour!(
             "{} and installing {} new {}",
             update_str,
-            to_install.len(),
-            packages_str(to_install.len()),
+            new,
+            packages_str(new),
+        ),
+        (0, reinstalled) => colour!(
+            "{} and reinstalling {} {}",
+            update_str,
+            reinstalled,
+            packages_str(reinstalled),
+        ),
+        (new, reinstalled) => colour!(
+            "{}, installing {} new {} and reinstalling {} {}",
+            update_str,
+            new,
+            packages_str(new),
+            reinstalled,
+            packages_str(reinstalled),
+        ),
+    }
+
+    run_pacman_step(strict, || backend.sync(options, to_install, mode))
+}
+
+/// Returns the installed versions of `packages`, `None` for packages that are not installed.
+fn installed_versions<'a>(
+    backend: &dyn PackageBackend,
+    packages: &[&'a str],
+) -> anyhow::Result<Vec<(&'a str, Option<String>)>> {
+    packages
+        .iter()
+        .map(|&package| {
+            let version = backend
+                .installed_version(package)
+                .with_context(|| format!("Failed to query the version of {:?}", package))?;
+            Ok((package, version))
+        })
+        .collect()
+}
+
+/// Returns a reason to reboot for every package that was upgraded between `before` and `after`.
+///
+/// Packages that were not installed before are not counted, since nothing running uses them yet.
+fn reboot_reasons(
+    before: &[(&str, Option<String>)],
+    after: &[(&str, Option<String>)],
+) -> Vec<String> {
+    before
+        .iter()
+        .zip(after)
+        .filter_map(|((package, before), (_, after))| match (before, after) {
+            (Some(before), Some(after)) if before != after => Some(format!(
+                "{} was upgraded from {} to {}",
+                package, before, after
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns a reason to reboot if the modules of the running kernel are no longer installed, which
+/// happens when the kernel package is upgraded or removed.
+fn check_running_kernel() -> Option<String> {
+    let release = fs::read_to_string(OSRELEASE_PATH).ok()?;
+    let release = release.trim();
+    if release.is_empty() || Path::new(MODULES_DIR).join(release).exists() {
+        return None;
+    }
+    Some(format!(
+        "the running kernel {} is no longer installed",
+        release
+    ))
+}
+
+/// Restricts `organized` to the packages given on the command line and returns the ones that should
+/// be reinstalled.
+///
+/// Declared packages that are installed are reinstalled. Undeclared packages are an error, unless
+/// `allow_undeclared` is set, in which case they are installed or reinstalled, but never removed.
+fn scope_to_packages<'a>(
+    named: &'a [String],
+    allow_undeclared: bool,
+    declared: &HashSet<&'a str>,
+    installed: &InstalledPackages,
+    reinstall_candidates: Vec<&'a str>,
+    organized: &mut OrganizedPackages<'a>,
+) -> anyhow::Result<Vec<&'a str>> {
+    let named: IndexSet<&str> = named.iter().map(String::as_str).collect();
+    let undeclared: Vec<&str> = named
+        .iter()
+        .copied()
+        .filter(|package| !declared.contains(package))
+        .collect();
+    if !undeclared.is_empty() && !allow_undeclared {
+        bail!(
+            "Not declared in the configuration file: {}, use --allow-undeclared to sync them \
+             anyway",
+            undeclared.join(" "),
         );
-    } else {
-        colour!("{}", update_str);
     }
 
-    match pacman::sync(!no_upgrade, to_install) {
-        Ok(()) => Ok(()),
-        Err(PacmanError::ExitFailure) => {
-            warn!("pacman did not exit successfully, continuing...");
-            Ok(())
+    organized.restrict(&named.iter().copied().collect());
+    organized.to_remove.clear();
+    organized.unneeded.clear();
+    let mut to_reinstall: Vec<_> = reinstall_candidates
+        .into_iter()
+        .filter(|package| named.contains(package))
+        .collect();
+    for package in undeclared {
+        if installed.explicit.contains(package) || installed.dependencies.contains(package) {
+            to_reinstall.push(package);
+        } else if installed.foreign.contains(package) {
+            warn!("not syncing {:?}, it is a foreign package", package);
+        } else {
+            organized.to_install.push(package);
+        }
+    }
+    Ok(to_reinstall)
+}
+
+/// Selects the packages given with `--reinstall` from the `candidates`, or all of them if no
+/// packages were given.
+fn select_reinstalled<'a>(candidates: Vec<&'a str>, only: &[String]) -> Vec<&'a str> {
+    if only.is_empty() {
+        return candidates;
+    }
+    for package in only {
+        if !candidates.contains(&package.as_str()) {
+            warn!(
+                "not reinstalling {:?}, it is not an installed declared package",
+                package
+            );
         }
-        Err(err) => Err(err.into()),
     }
+    candidates
+        .into_iter()
+        .filter(|candidate| only.iter().any(|package| package == candidate))
+        .collect()
 }
 
-/// Recursively removes given packages, if they are not needed by other packages.
-fn remove_packages(to_remove: &[&str]) -> anyhow::Result<()> {
+/// Lists the packages that are about to be removed along with the reason for their removal.
+///
+/// Unless `skip_prompt` is set, asks the user for confirmation and returns their answer.
+fn explain_removal(
+    undeclared: &[&str],
+    unneeded: &[&str],
+    skip_prompt: bool,
+) -> anyhow::Result<bool> {
+    let width = match undeclared.iter().chain(unneeded).map(|p| p.len()).max() {
+        Some(width) => width,
+        None => return Ok(true),
+    };
+
+    colour!("Packages to remove:");
+    for package in undeclared {
+        println!("  {:width$} : undeclared", package, width = width);
+    }
+    for package in unneeded {
+        println!("  {:width$} : orphaned dependency", package, width = width);
+    }
+
+    if skip_prompt {
+        return Ok(true);
+    }
+    Ok(util::is_yes(&util::ask("Proceed with the removal? [y/N]")?))
+}
+
+/// Asks the user which of the packages in every group of `organized` and `to_reinstall` to act
+/// upon, see [`select_packages`]. Unneeded packages are only included if `cleanup` is set.
+///
+/// Questions are asked with `ask`. Returns `true` if all packages in `organized` were accepted, so
+/// that the whole configuration is still applied. Packages to reinstall do not count, since they
+/// are not part of the configuration.
+fn select_actions<'a>(
+    organized: &mut OrganizedPackages<'a>,
+    to_reinstall: &mut Vec<&'a str>,
+    cleanup: bool,
+    ask: &mut dyn FnMut(&str) -> io::Result<String>,
+) -> io::Result<bool> {
+    let mut accepted = select_packages("Install", &mut organized.to_install, ask)?;
+    select_packages("Reinstall", to_reinstall, ask)?;
+    accepted &= select_packages(
+        "Mark as explicitly installed",
+        &mut organized.to_mark_as_explicit,
+        ask,
+    )?;
+    accepted &= select_packages(
+        "Mark as dependencies and remove",
+        &mut organized.to_remove,
+        ask,
+    )?;
+    if cleanup {
+        accepted &= select_packages("Remove as unneeded", &mut organized.unneeded, ask)?;
+    }
+    Ok(accepted)
+}
+
+/// Lists `packages` and asks the user with `ask` whether to `action` all of them, none of them, or
+/// to choose package by package. Only the accepted packages are kept.
+///
+/// Returns `true` if all of them were accepted.
+fn select_packages(
+    action: &str,
+    packages: &mut Vec<&str>,
+    ask: &mut dyn FnMut(&str) -> io::Result<String>,
+) -> io::Result<bool> {
+    if packages.is_empty() {
+        return Ok(true);
+    }
+    colour!(
+        "{} {} {}:",
+        action,
+        packages.len(),
+        packages_str(packages.len())
+    );
+    for package in packages.iter() {
+        println!("  {}", package);
+    }
+    loop {
+        match ask("Proceed? [y/N/p(er package)]")?.as_str() {
+            answer if util::is_yes(answer) => return Ok(true),
+            "" | "n" | "N" | "no" | "No" => {
+                packages.clear();
+                return Ok(false);
+            }
+            "p" | "P" => break,
+            _ => continue,
+        }
+    }
+    let mut accepted = Vec::with_capacity(packages.len());
+    for &package in packages.iter() {
+        if util::is_yes(&ask(&format!("{} {}? [y/N]", action, package))?) {
+            accepted.push(package);
+        }
+    }
+    let all_accepted = accepted.len() == packages.len();
+    *packages = accepted;
+    Ok(all_accepted)
+}
+
+/// Removes given packages, if they are not needed by other packages, along with their dependencies
+/// that are no longer needed if `recursive` is set.
+///
+/// Returns `false` if the package manager failed, but we continued anyway.
+fn remove_packages(
+    backend: &dyn PackageBackend,
+    to_remove: &[&str],
+    recursive: bool,
+    strict: bool,
+    mode: RunMode,
+) -> anyhow::Result<bool> {
     if to_remove.is_empty() {
-        return Ok(());
+        return Ok(true);
     }
 
     colour!(
@@ -134,18 +706,42 @@ fn remove_packages(to_remove: &[&str]) -> anyhow::Result<()> {
         to_remove.len(),
         packages_str(to_remove.len())
     );
-    match pacman::remove(to_remove) {
-        Ok(()) => Ok(()),
-        Err(PacmanError::ExitFailure) => {
-            warn!("pacman did not exit successfully, continuing...");
-            Ok(())
+    let removed = run_pacman_step(strict, || backend.remove(to_remove, recursive, mode))?;
+    Ok(removed.is_some())
+}
+
+/// Removes old versions of packages from the package cache and reports the reclaimed space.
+///
+/// Returns `false` if the package manager failed, but we continued anyway.
+fn prune_cache(backend: &dyn PackageBackend, strict: bool, mode: RunMode) -> anyhow::Result<bool> {
+    colour!("Pruning the package cache");
+    let pruned = run_pacman_step(strict, || backend.prune_cache(mode))?;
+    if let Some(Some(freed)) = pruned {
+        info!("Reclaimed {:.1} MiB", freed as f64 / (1024.0 * 1024.0));
+    }
+    Ok(pruned.is_some())
+}
+
+/// Runs a single step that modifies the system with the package manager.
+///
+/// If the package manager does not exit successfully, fails if `strict` is set, otherwise warns
+/// about it and returns `None`. Other errors always fail.
+fn run_pacman_step<T, F>(strict: bool, step: F) -> anyhow::Result<Option<T>>
+where
+    F: FnOnce() -> backend::Result<T>,
+{
+    match step() {
+        Ok(value) => Ok(Some(value)),
+        Err(err @ BackendError::ExitFailure(_)) if !strict => {
+            warn!("{}, continuing...", err);
+            Ok(None)
         }
         Err(err) => Err(err.into()),
     }
 }
 
 /// Includes my own xkb types in the types file, in case it was overwritten during the update.
-fn patch_xkb_types(path: &Path) -> anyhow::Result<()> {
+fn patch_xkb_types(path: &Path, mode: RunMode) -> anyhow::Result<()> {
     let mut contents = fs::read_to_string(path).context("Failed to read from file")?;
 
     const XKB_TYPES_REGEX_STR: &str =
@@ -158,16 +754,28 @@ fn patch_xkb_types(path: &Path) -> anyhow::Result<()> {
     );
 
     if !contents.contains("include \"ed\"") {
+        if mode.is_dry_run() {
+            info!("Would patch up {:?}", path);
+            return Ok(());
+        }
+        if mode.is_unprivileged() {
+            bail!("Refusing to patch {:?} because of --no-sudo", path);
+        }
         println!("Patching up {:?}", path);
         // regex match ensures the string contains '}'
         let last_line_start = contents.find('}').unwrap();
         contents.insert_str(last_line_start, "    include \"ed\"\n");
-        fs::write(path, &contents).with_context(|| format!("Failed to modify {:?}", path))?;
+        util::write_atomically(path, contents.as_bytes())
+            .with_context(|| format!("Failed to modify {:?}", path))?;
     }
 
     Ok(())
 }
 
+fn to_owned(packages: &[&str]) -> Vec<String> {
+    packages.iter().map(|&package| package.to_owned()).collect()
+}
+
 fn packages_str(count: usize) -> &'static str {
     if count == 1 {
         "package"
@@ -175,3 +783,64 @@ fn packages_str(count: usize) -> &'static str {
         "packages"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_partial_upgrades() {
+        assert!(check_partial_upgrade(&[], false, false).is_ok());
+        assert!(check_partial_upgrade(&["vim"], true, false).is_ok());
+        assert!(check_partial_upgrade(&["vim"], false, false).is_err());
+        assert!(check_partial_upgrade(&["vim"], false, true).is_ok());
+    }
+
+    #[test]
+    fn select_packages_to_act_upon() {
+        let organized = || OrganizedPackages {
+            to_install: vec!["vim", "zsh"],
+            to_mark_as_explicit: vec!["git"],
+            to_remove: vec!["nano"],
+            unneeded: vec!["libfoo"],
+            ignored: vec![],
+            protected: vec![],
+            foreign: vec![],
+        };
+        fn select<'a>(
+            organized: &mut OrganizedPackages<'a>,
+            cleanup: bool,
+            answers: &[&str],
+        ) -> (bool, Vec<&'a str>) {
+            let mut answers = answers.iter();
+            let mut ask = |_: &str| Ok(answers.next().unwrap().to_string());
+            let mut to_reinstall = vec!["linux"];
+            let accepted = select_actions(organized, &mut to_reinstall, cleanup, &mut ask).unwrap();
+            assert_eq!(answers.next(), None);
+            (accepted, to_reinstall)
+        }
+
+        let mut all = organized();
+        assert_eq!(
+            select(&mut all, true, &["y", "y", "y", "y", "y"]),
+            (true, vec!["linux"])
+        );
+        assert_eq!(all.unneeded, ["libfoo"]);
+
+        // skipping a reinstall or unneeded packages without cleanup still applies everything
+        let mut most = organized();
+        assert_eq!(
+            select(&mut most, false, &["y", "n", "y", "y"]),
+            (true, vec![])
+        );
+        assert_eq!(most.to_install, ["vim", "zsh"]);
+
+        let mut some = organized();
+        let answers = ["p", "y", "n", "y", "", "y", "y"];
+        assert_eq!(select(&mut some, true, &answers), (false, vec!["linux"]));
+        assert_eq!(some.to_install, ["vim"]);
+        assert_eq!(some.to_mark_as_explicit, Vec::<&str>::new());
+        assert_eq!(some.to_remove, ["nano"]);
+        assert_eq!(some.unneeded, ["libfoo"]);
+    }
+}
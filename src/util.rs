@@ -1,10 +1,50 @@
 //! Utilities.
 
-/// Prints an error to `stderr` using colours if `stderr` is connected to a terminal.
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem,
+    path::Path,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use is_terminal::IsTerminal;
+use thiserror::Error;
+
+use crate::args::{ColorChoice, ColorTestArgs};
+
+/// The [`ColorChoice`] selected with `--color`, as set by [`set_color_choice`].
+static COLOR_CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// Sets when output should be styled, for all following output.
+pub fn set_color_choice(choice: ColorChoice) {
+    COLOR_CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+/// Returns `true` if output written to `stream` should be styled.
+///
+/// With `--color=auto`, output is styled if `stream` is a terminal and the `NO_COLOR` environment
+/// variable is not set to a non-empty value.
+pub fn use_style<S: IsTerminal>(stream: &S) -> bool {
+    match COLOR_CHOICE.load(Ordering::Relaxed) {
+        choice if choice == ColorChoice::Always as u8 => true,
+        choice if choice == ColorChoice::Never as u8 => false,
+        _ => env::var_os("NO_COLOR").map_or(true, |value| value.is_empty()) && stream.is_terminal(),
+    }
+}
+
+/// Prints an error to `stderr` using colours if [`use_style`] allows it.
 macro_rules! error {
     ($($fmt:expr),+ $(,)?) => {
         {
-            let use_style = ::is_terminal::IsTerminal::is_terminal(&::std::io::stderr());
+            let use_style = $crate::util::use_style(&::std::io::stderr());
             let style = if use_style {
                 ::anstyle::AnsiColor::Red.on_default().bold()
             } else {
@@ -21,11 +61,11 @@ macro_rules! error {
     }
 }
 
-/// Prints a warning to `stderr` using colours if `stderr` is connected to a terminal.
+/// Prints a warning to `stderr` using colours if [`use_style`] allows it.
 macro_rules! warn {
     ($($fmt:expr),+ $(,)?) => {
         {
-            let use_style = ::is_terminal::IsTerminal::is_terminal(&::std::io::stderr());
+            let use_style = $crate::util::use_style(&::std::io::stderr());
             let style = if use_style {
                 ::anstyle::AnsiColor::Yellow.on_default().bold()
             } else {
@@ -42,11 +82,11 @@ macro_rules! warn {
     }
 }
 
-/// Prints an info string to `stdout` using a bold style if `stdout` is connected to a terminal.
+/// Prints an info string to `stdout` using a bold style if [`use_style`] allows it.
 macro_rules! info {
     ($($fmt:expr),+ $(,)?) => {
         {
-            let use_style = ::is_terminal::IsTerminal::is_terminal(&::std::io::stdout());
+            let use_style = $crate::util::use_style(&::std::io::stdout());
             let style = if use_style {
                 ::anstyle::Style::new().bold()
             } else {
@@ -63,11 +103,11 @@ macro_rules! info {
     }
 }
 
-/// Prints a coloured string to `stdout` using colours if `stdout` is connected to a terminal.
+/// Prints a coloured string to `stdout` using colours if [`use_style`] allows it.
 macro_rules! colour {
     ($($fmt:expr),+ $(,)?) => {
         {
-            let use_style = ::is_terminal::IsTerminal::is_terminal(&::std::io::stdout());
+            let use_style = $crate::util::use_style(&::std::io::stdout());
             let style = if use_style {
                 ::anstyle::AnsiColor::Blue.on_default().bold()
             } else {
@@ -83,11 +123,11 @@ macro_rules! colour {
     }
 }
 
-/// Prints a string `stdout` using a bold style if `stdout` is connected to a terminal.
+/// Prints a string `stdout` using a bold style if [`use_style`] allows it.
 macro_rules! bold {
     ($($fmt:expr),+ $(,)?) => {
         {
-            let use_style = ::is_terminal::IsTerminal::is_terminal(&::std::io::stdout());
+            let use_style = $crate::util::use_style(&::std::io::stdout());
             let style = if use_style {
                 ::anstyle::Style::new().bold()
             } else {
@@ -102,3 +142,398 @@ macro_rules! bold {
         }
     }
 }
+
+/// Prints a single warning about all `items` that have the same `problem`, e.g.
+/// `3 services declared multiple times: a, b, c`.
+///
+/// `singular` and `plural` describe a single item and multiple items. Nothing is printed if there
+/// are no items.
+pub fn warn_about_all<I: Display>(items: &[I], singular: &str, plural: &str, problem: &str) {
+    if items.is_empty() {
+        return;
+    }
+    warn!("{}", describe_all(items, singular, plural, problem));
+}
+
+/// Formats the message printed by [`warn_about_all`].
+fn describe_all<I: Display>(items: &[I], singular: &str, plural: &str, problem: &str) -> String {
+    let what = if items.len() == 1 { singular } else { plural };
+    let names: Vec<_> = items.iter().map(ToString::to_string).collect();
+    format!("{} {} {}: {}", items.len(), what, problem, names.join(", "))
+}
+
+/// Prints a line in every style used by the macros in this module.
+pub fn print_color_test(args: ColorTestArgs) {
+    let ColorTestArgs {} = args;
+    let yes_no = |styled| if styled { "yes" } else { "no" };
+    println!(
+        "Styling stdout: {}, stderr: {}",
+        yes_no(use_style(&std::io::stdout())),
+        yes_no(use_style(&std::io::stderr())),
+    );
+    error!("an error, in bold red");
+    warn!("a warning, in bold yellow");
+    info!("an info message, in bold");
+    colour!("a heading, in bold blue");
+    bold!("bold text");
+    println!("plain text, after all styles were reset");
+}
+
+/// Set by [`handle_sigint`] when the program is interrupted.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Keeps [`handle_sigint`] installed as the SIGINT handler, restoring the previous one on drop.
+///
+/// While the guard exists, SIGINT does not terminate the program, it is up to the owner of the
+/// guard to check [`SigintGuard::interrupted`]. A handled signal, unlike an ignored one, is reset
+/// to its default action in child processes.
+pub struct SigintGuard {
+    previous: libc::sigaction,
+}
+
+impl SigintGuard {
+    pub fn install() -> io::Result<Self> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        // SAFETY: `action` is fully initialized before use and the handler only stores to an
+        // atomic, which is async-signal-safe.
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous = mem::zeroed();
+            if libc::sigaction(libc::SIGINT, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { previous })
+        }
+    }
+
+    /// Returns `true` if SIGINT was received since the guard was installed.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        // SAFETY: `previous` was filled in by `sigaction`.
+        unsafe {
+            libc::sigaction(libc::SIGINT, &self.previous, ptr::null_mut());
+        }
+    }
+}
+
+/// The limit on the run time of external commands selected with `--timeout`, in milliseconds, as
+/// set by [`set_command_timeout`]. Zero means no limit.
+static COMMAND_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// How often a child process is checked for having exited, while waiting for it with a timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// An external command was stopped because it did not exit within the time limit.
+#[derive(Debug, Error)]
+#[error("the command did not exit within {} s and was stopped", .0.as_secs())]
+pub struct TimedOut(pub Duration);
+
+/// Sets the limit on the run time of all following external commands, see [`wait`].
+pub fn set_command_timeout(timeout: Option<Duration>) {
+    let millis = timeout.map_or(0, |timeout| timeout.as_millis().max(1));
+    COMMAND_TIMEOUT.store(millis.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// Returns the limit on the run time of external commands, if there is one.
+fn command_timeout() -> Option<Duration> {
+    match COMMAND_TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Waits for `child` to exit, like [`Child::wait`], but kills it if it runs for longer than the
+/// limit set with [`set_command_timeout`].
+///
+/// A killed child results in an error of kind [`io::ErrorKind::TimedOut`] that wraps [`TimedOut`].
+pub fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    match command_timeout() {
+        Some(timeout) => wait_with_timeout(child, timeout),
+        None => child.wait(),
+    }
+}
+
+/// Waits for `child` to exit, like [`wait`], but first sends it SIGINT if it runs for longer than
+/// the limit set with [`set_command_timeout`]. It is only killed if it does not exit within `grace`
+/// after that.
+///
+/// This gives commands that handle SIGINT, like pacman, a chance to clean up after themselves.
+pub fn wait_interruptible(child: &mut Child, grace: Duration) -> io::Result<ExitStatus> {
+    match command_timeout() {
+        Some(timeout) => wait_with_grace(child, timeout, grace),
+        None => child.wait(),
+    }
+}
+
+/// Waits for `child` to exit, killing it if it does not exit within `timeout`, see [`wait`].
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<ExitStatus> {
+    if let Some(status) = wait_until(child, Instant::now() + timeout)? {
+        return Ok(status);
+    }
+    // the child might have exited in the meantime, in which case killing it fails
+    let _ = child.kill();
+    child.wait()?;
+    Err(io::Error::new(io::ErrorKind::TimedOut, TimedOut(timeout)))
+}
+
+/// Waits for `child` to exit, interrupting it if it does not exit within `timeout` and killing it
+/// if it does not exit within `grace` after that, see [`wait_interruptible`].
+fn wait_with_grace(
+    child: &mut Child,
+    timeout: Duration,
+    grace: Duration,
+) -> io::Result<ExitStatus> {
+    if let Some(status) = wait_until(child, Instant::now() + timeout)? {
+        return Ok(status);
+    }
+    // SAFETY: the child was not waited for yet, so its pid was not reused. It might have exited in
+    // the meantime, in which case sending the signal has no effect.
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    if wait_until(child, Instant::now() + grace)?.is_none() {
+        let _ = child.kill();
+        child.wait()?;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, TimedOut(timeout)))
+}
+
+/// Waits for `child` to exit, until `deadline`. Returns `None` if it is still running.
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Runs `cmd` and collects its output, like [`Command::output`], but subject to the limit set with
+/// [`set_command_timeout`], see [`wait`].
+///
+/// As with [`Command::output`], `stdin` is closed and both `stdout` and `stderr` are captured.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    collect_output(cmd)
+}
+
+/// Runs `cmd` with the standard streams it was configured with, and collects the output written to
+/// `stdout` and `stderr` if they are piped. Subject to the limit set with [`set_command_timeout`],
+/// see [`wait`].
+pub fn collect_output(cmd: &mut Command) -> io::Result<Output> {
+    collect_output_with(cmd, false)
+}
+
+/// Runs `cmd` like [`collect_output`], but also passes everything it writes to a piped `stderr`
+/// through to our `stderr` as soon as it arrives.
+pub fn collect_output_passing_stderr(cmd: &mut Command) -> io::Result<Output> {
+    collect_output_with(cmd, true)
+}
+
+fn collect_output_with(cmd: &mut Command, pass_stderr: bool) -> io::Result<Output> {
+    let mut child = cmd.spawn()?;
+    // the pipes are read on separate threads, so that a child that does not exit in time cannot
+    // block us, and so that a child writing to both pipes never waits for us to read the other one
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| thread::spawn(|| read_all(stdout)));
+    let stderr = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            if pass_stderr {
+                tee(stderr, io::stderr())
+            } else {
+                read_all(stderr)
+            }
+        })
+    });
+    let status = wait(&mut child)?;
+    let join = |reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>| match reader {
+        Some(reader) => reader.join().expect("reading from a pipe panicked"),
+        None => Ok(Vec::new()),
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Reads everything from `input` until its end.
+fn read_all(mut input: impl Read) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    input.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Copies everything from `input` to `output` until the end of `input`, returning a copy of it.
+///
+/// Output is flushed after every read, so that prompts without a trailing newline are shown.
+/// Failures to write are ignored, so that the child process is never blocked by a full pipe.
+pub fn tee(mut input: impl Read, mut output: impl Write) -> io::Result<Vec<u8>> {
+    let mut captured = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => return Ok(captured),
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let _ = output.write_all(&buf[..len]).and_then(|()| output.flush());
+        captured.extend_from_slice(&buf[..len]);
+    }
+}
+
+/// Prints `question` and returns the answer read from stdin, without surrounding whitespace.
+///
+/// Returns an empty answer if stdin is closed.
+pub fn ask(question: &str) -> io::Result<String> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+/// Returns `true` if `answer` to a question asked with [`ask`] is affirmative.
+pub fn is_yes(answer: &str) -> bool {
+    matches!(answer, "y" | "Y" | "yes" | "Yes")
+}
+
+/// Replaces the contents of the file at `path` with `contents`, so that it either has the old or
+/// the new contents at any point, even if we are interrupted.
+///
+/// The contents are written to a temporary file in the same directory, which is then renamed over
+/// the original file. The permissions of the original file are preserved.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "the path does not name a file")
+    })?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".archman-tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.set_permissions(fs::metadata(path)?.permissions())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn describe_items_with_the_same_problem() {
+        let describe =
+            |items: &[&str]| describe_all(items, "service", "services", "declared multiple times");
+        assert_eq!(
+            describe(&["sshd"]),
+            "1 service declared multiple times: sshd"
+        );
+        assert_eq!(
+            describe(&["sshd", "docker"]),
+            "2 services declared multiple times: sshd, docker"
+        );
+    }
+
+    #[test]
+    fn tee_output() {
+        let mut output = Vec::new();
+        let captured = tee(&b"Proceed with installation? [Y/n] "[..], &mut output).unwrap();
+        assert_eq!(captured, b"Proceed with installation? [Y/n] ");
+        assert_eq!(output, captured);
+    }
+
+    #[test]
+    fn kill_commands_that_time_out() {
+        let timeout = Duration::from_millis(100);
+        let mut child = Command::new("true").spawn().unwrap();
+        assert!(wait_with_timeout(&mut child, timeout).unwrap().success());
+
+        let started = Instant::now();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let err = wait_with_timeout(&mut child, timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.get_ref().unwrap().is::<TimedOut>());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn interrupt_commands_that_time_out() {
+        let timeout = Duration::from_millis(100);
+        let grace = Duration::from_secs(10);
+
+        // exits on SIGINT, so it is never killed
+        let started = Instant::now();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let err = wait_with_grace(&mut child, timeout, grace).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<TimedOut>());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // ignores SIGINT, so it is killed after the grace period
+        let started = Instant::now();
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' INT; sleep 10"])
+            .spawn()
+            .unwrap();
+        let err = wait_with_grace(&mut child, timeout, timeout).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<TimedOut>());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn replace_file_atomically() {
+        let dir = env::temp_dir().join("archman-test-write-atomically");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let missing = dir.join("missing");
+        assert!(write_atomically(&missing, b"new").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}